@@ -1,26 +1,14 @@
 #!/bin/bash
 
-# Build for aarch64 and x86, in centralized mode.
-cargo build --release --no-default-features --features=experiment_1_centralized
-cargo build --release --no-default-features --target aarch64-unknown-linux-gnu --features=experiment_1_centralized
+# Build for aarch64 and x86, the coordination mode
+# (centralized or distributed) is selected at startup.
+cargo build --release --no-default-features --features=experiment_1
+cargo build --release --no-default-features --target aarch64-unknown-linux-gnu --features=experiment_1
 
 # Remove previous build.
-rm -f out/app_lev_orc_aarch64_c
-rm -f out/app_lev_orc_x86_c
+rm -f out/app_lev_orc_aarch64_1
+rm -f out/app_lev_orc_x86_1
 
 # Copy the new binaries in out.
-cp target/aarch64-unknown-linux-gnu/release/app_lev_orc out/app_lev_orc_aarch64_c
-cp target/release/app_lev_orc out/app_lev_orc_x86_c
-
-# Build for aarch64 and x86, in distributed mode.
-cargo build --release --no-default-features --features=experiment_1_distributed
-cargo build --release --no-default-features --target aarch64-unknown-linux-gnu --features=experiment_1_distributed
-
-# Remove previous build.
-rm -f out/app_lev_orc_aarch64_d
-rm -f out/app_lev_orc_x86_d
-
-# Copy the new binaries in out.
-cp target/aarch64-unknown-linux-gnu/release/app_lev_orc out/app_lev_orc_aarch64_d
-cp target/release/app_lev_orc out/app_lev_orc_x86_d
-
+cp target/aarch64-unknown-linux-gnu/release/app_lev_orc out/app_lev_orc_aarch64_1
+cp target/release/app_lev_orc out/app_lev_orc_x86_1
@@ -23,8 +23,7 @@ if [ $1 -gt 0 ]; then
 else
   cp -r requests_empty experiment_folder/requests
 fi
-cp out/app_lev_orc_aarch64_c experiment_folder/app_lev_orc_c
-cp out/app_lev_orc_aarch64_d experiment_folder/app_lev_orc_d
+cp out/app_lev_orc_aarch64_1 experiment_folder/app_lev_orc
 
 cp experiment_data/nodes_conf/node_0 experiment_folder/node_0
 cp experiment_data/nodes_conf/node_1 experiment_folder/node_1
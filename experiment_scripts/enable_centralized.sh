@@ -3,6 +3,6 @@
 # This script should be started from the
 #  root directory (".." from here).
 
+# Select the centralized coordination in the node configurations.
 cd experiment_folder
-rm app_lev_orc &> /dev/null
-cp app_lev_orc_c app_lev_orc
+sed -i 's/^coordination .*/coordination      = "centralized"/' node_0 node_1 node_2
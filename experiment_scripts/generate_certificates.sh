@@ -0,0 +1,53 @@
+#!/bin/bash
+
+# This script should be started from the
+#  root directory (".." from here).
+
+# $1   : folder of the certificates.
+# $2   : address of the broker.
+# $3.. : addresses of node_0, node_1, ...
+
+# Generates the CA of the federation, the certificate of the
+# broker, and the certificate of each node, named node_N.
+
+set -e
+
+folder=$1
+shift
+
+mkdir -p $folder
+cd $folder
+
+# The address, as an IP address or a host name.
+subject_alt_name() {
+  if [[ $1 =~ ^[0-9.]+$ ]]; then
+    echo "IP:$1"
+  else
+    echo "DNS:$1"
+  fi
+}
+
+# The CA, valid for 10 years.
+openssl req -x509 -newkey rsa:2048 -nodes -days 3650 \
+  -keyout ca.key -out ca.crt -subj "/CN=federation_ca"
+
+# $1 : name.
+# $2 : address.
+certificate() {
+  openssl req -newkey rsa:2048 -nodes \
+    -keyout $1.key -out $1.csr -subj "/CN=$1"
+  echo "subjectAltName = $(subject_alt_name $2)" > $1.ext
+  echo "extendedKeyUsage = serverAuth, clientAuth" >> $1.ext
+  openssl x509 -req -in $1.csr -CA ca.crt -CAkey ca.key -CAcreateserial \
+    -days 365 -out $1.crt -extfile $1.ext
+  rm $1.csr $1.ext
+}
+
+certificate broker $1
+shift
+
+index=0
+for address in "$@"; do
+  certificate node_$index $address
+  index=$((index + 1))
+done
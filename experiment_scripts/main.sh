@@ -70,7 +70,7 @@ for mode in "${modes[@]}"s; do
     echo "ORCHESTRATION STARTED"
 
     # Start the experiment.
-    mosquitto_pub -h $node_1 -t federation/migration -m "$request"
+    mosquitto_pub -h $node_1 -t federation/app_0/migration -m "$request"
     # mosquitto_pub -h $node_1 -t node_state_2 -m "$new_state_2"
 
     # Pause to allow for convergence.
@@ -39,7 +39,7 @@ while read -u 9 line; do
 
   sleep 10s
 
-  mosquitto_pub -h 192.168.1.210 -t federation/migration -m "$request"
+  mosquitto_pub -h 192.168.1.210 -t federation/app_0/migration -m "$request"
 
   sleep 60s
 
@@ -50,7 +50,7 @@ while read -u 9 line; do
 
   sleep 10s
 
-  mosquitto_pub -h 192.168.1.210 -t federation/migration -m "$request"
+  mosquitto_pub -h 192.168.1.210 -t federation/app_0/migration -m "$request"
 
   sleep 60s
 
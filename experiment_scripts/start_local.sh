@@ -36,7 +36,7 @@ if [ $2 == "centralized" ]; then
   fi
 
 # Alter the node state.
-sed -i "4s/.*/$4/" experiment_folder/$3
+sed -i "s/^state .*/state             = \"$4\"/" experiment_folder/$3
 
 # Prepare the log file.
 # echo -n "$5 " >> experiment_data/send.txt
@@ -32,11 +32,11 @@ if [ $2 == "centralized" ]; then
 # Select the new bin.
 cd experiment_folder
 rm app_lev_orc &> /dev/null
-cp ../out/app_lev_orc_aarch64_c_2 app_lev_orc
+cp ../out/app_lev_orc_aarch64_2 app_lev_orc
 cd ..
 
 # Alter the node state.
-sed -i "4s/.*/$4/" experiment_folder/$3
+sed -i "s/^state .*/state             = \"$4\"/" experiment_folder/$3
 
 # Prepare the log file.
 echo -n "$5 " >> experiment_data/send.txt
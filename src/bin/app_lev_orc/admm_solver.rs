@@ -2,7 +2,8 @@
 /*        ADMM SOLVER        */
 /*****************************/
 
-use crate::state::{Coord, Request};
+use crate::distance_metric::DistanceMetric;
+use crate::state::{Attributes, Request};
 
 /// Tolerance value used to determine when to stop
 /// the ADMM executions.
@@ -71,10 +72,14 @@ pub struct LocalSolver
     /// gamma in the model.
     pub etc_multiplier : f32,
 
-    /// Coordinates of the node, used to determine
-    /// the distance from the desired position of
+    /// Attributes of the node, used to determine
+    /// the distance from the desired attributes of
     /// a request.
-    pub coordinate     : Coord,
+    pub attributes     : Attributes,
+
+    /// The metric of the distance, the same used to
+    /// trigger the migrations.
+    pub metric         : std::sync::Arc<dyn DistanceMetric>,
 
     /// The expected times to completion for the
     /// incoming request in this node.
@@ -83,7 +88,11 @@ pub struct LocalSolver
 
 impl LocalSolver
 {
-    pub fn new (number_of_nodes : usize, penalty : f32, etc_multiplier : f32, coordinate : Coord) -> Self
+    pub fn new (number_of_nodes : usize,
+                penalty         : f32,
+                etc_multiplier  : f32,
+                attributes      : Attributes,
+                metric          : std::sync::Arc<dyn DistanceMetric>) -> Self
     {
         Self
         {
@@ -92,19 +101,20 @@ impl LocalSolver
             global : 1.0 / number_of_nodes as f32,
             penalty,
             etc_multiplier,
-            coordinate,
+            attributes,
+            metric,
             request_etc: 0,
         }
     }
 
-    pub fn clear (&mut self, number_of_nodes : usize, penalty : f32, etc_multiplier: f32, coordinate : Coord, request_etc: u32)
+    pub fn clear (&mut self, number_of_nodes : usize, penalty : f32, etc_multiplier: f32, attributes : Attributes, request_etc: u32)
     {
         self.local      = 0.0;
         self.dual       = 0.0;
         self.global     = 1.0 / number_of_nodes as f32;
         self.penalty    = penalty;
         self.etc_multiplier = etc_multiplier;
-        self.coordinate     = coordinate;
+        self.attributes     = attributes;
         self.request_etc    = request_etc;
     }
 
@@ -113,7 +123,7 @@ impl LocalSolver
     pub fn local_x_update (&mut self, request: &Request)
     {
         // The object function of the minimization problem.
-        let desired_coord = request.get_desired_coord ();
+        let desired_attributes = request.get_desired_attributes ();
         fn to_minimize (local    :   f32,
                         dual     :   f32,
                         global   :   f32,
@@ -123,8 +133,7 @@ impl LocalSolver
             c_term * local + (penalty / 2f32) * (local - global + dual).powf (2f32)
         }
 
-        let distance: f32 =
-            ((desired_coord.get_x () - self.coordinate.get_x ()).powi (2) + (desired_coord.get_y () - self.coordinate.get_y ()).powi (2)).sqrt ();
+        let distance: f32 = self.metric.distance (&desired_attributes, &self.attributes);
 
         let c_term: f32 = distance + self.request_etc as f32 * self.etc_multiplier;
 
@@ -190,6 +199,9 @@ pub struct GlobalSolver
     /// Received local data.
     received_locals : std::collections::HashSet<usize>,
 
+    /// Nodes excluded from the placement, as silent in a round.
+    excluded        : std::collections::HashSet<usize>,
+
     /// Number of nodes. 
     number_of_nodes : usize,
 
@@ -209,32 +221,35 @@ impl GlobalSolver
             globals         : Globals::new (vec![1.0 / number_of_nodes as f32; number_of_nodes]),
             locals          : Variables::new (vec![0.0; number_of_nodes]),
             received_locals : std::collections::HashSet::new (),
+            excluded        : std::collections::HashSet::new (),
             number_of_nodes,
             iteration_limit,
             iteration       : 0
         }
     }
 
-    pub fn clear (&mut self)
-    {
-        self.globals.z = vec![1.0 / self.number_of_nodes as f32; self.number_of_nodes];
-        self.locals.x  = vec![0.0; self.number_of_nodes];
-        self.iteration = 0;
-    }
-
     pub fn clear_locals (&mut self)
     {
         self.locals.x = vec![0.0; self.number_of_nodes];
     }
 
-    pub fn add_local_sum (&mut self, sum: f32, src: usize)
+    /// Add the x + u of `src' for the round `iteration', and return
+    /// whether it is counted: a local of another round (e.g. the late
+    /// reply of a node after a round timeout) is dropped.
+    pub fn add_local_sum (&mut self, sum: f32, src: usize, iteration: usize) -> bool
     {
+        // A late reply of an excluded node is ignored.
+        if self.excluded.contains (&src) || iteration != self.iteration
+        {
+            return false;
+        }
         self.locals.x[src] = sum;
         self.received_locals.insert (src);
 
         #[cfg(feature = "print_log")]
         println! ("requests_coordination_loop - add_local_sum - x[src] = {}", self.locals.x[src]);
 
+        true
     }
 
     pub fn locals_len (&self) -> usize
@@ -242,6 +257,24 @@ impl GlobalSolver
         self.received_locals.len ()
     }
 
+    /// Whether all the nodes taking part in the placement have
+    /// completed their local update.
+    pub fn is_round_complete (&self) -> bool
+    {
+        self.received_locals.len () + self.excluded.len () == self.number_of_nodes
+    }
+
+    /// Exclude from the placement the nodes which have not completed
+    /// their local update in the current round, and return them.
+    pub fn exclude_silent (&mut self) -> Vec<usize>
+    {
+        let silent : Vec<usize> = (0..self.number_of_nodes)
+            .filter (|i| !self.received_locals.contains (i) && !self.excluded.contains (i))
+            .collect ();
+        self.excluded.extend (silent.iter ());
+        silent
+    }
+
     fn clear_received_locals (&mut self)
     {
         self.received_locals.clear ();
@@ -264,11 +297,14 @@ impl GlobalSolver
         #[cfg(feature = "print_log")]
         println! ("requests_coordination_loop - global_z_updater - x = {:?}", self.locals.x);
 
-        // Compute the vector v.
+        // Compute the vector v, of the nodes taking part in the placement.
         let mut v : Vec<f32> = Vec::new ();
         for i in 0..self.locals.x.len ()
         {
-            v.push (self.locals.x[i]);
+            if !self.excluded.contains (&i)
+            {
+                v.push (self.locals.x[i]);
+            }
         }
 
         // Produce the subtrahend in the z-update.
@@ -277,10 +313,19 @@ impl GlobalSolver
         #[cfg(feature = "print_log")]
         println! ("requests_coordination_loop - global_z_updater - subt = {}", subt);
 
-        // Update the global variables.
+        // Update the global variables, an excluded node gets none
+        // of the request.
+        let mut participants = v.iter ();
         for i in 0..self.globals.z.len ()
         {
-            self.globals.z[i] = v[i] - subt;
+            self.globals.z[i] = if self.excluded.contains (&i)
+            {
+                0.0
+            }
+            else
+            {
+                participants.next ().expect ("Missing local") - subt
+            };
         }
 
         #[cfg(feature = "print_log")]
@@ -335,7 +380,7 @@ impl GlobalSolver
         for i in 0..self.globals.z.len ()
         {
             let &z = &self.globals.z[i];
-            if z > max_z
+            if z > max_z && !self.excluded.contains (&i)
             {
                 max_z  = z;
                 result = i;
@@ -343,4 +388,30 @@ impl GlobalSolver
         }
         result
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn locals_of_another_round_are_dropped ()
+    {
+        let mut solver = GlobalSolver::new (2, 20);
+        assert! (solver.add_local_sum (1.0, 0, 0));
+        assert! (solver.add_local_sum (0.0, 1, 0));
+        assert! (solver.is_round_complete ());
+        solver.global_z_updater ();
+
+        // The late local of round 0, and the one of a round to come,
+        // are not counted in round 1.
+        assert! (!solver.add_local_sum (0.0, 1, 0));
+        assert! (!solver.add_local_sum (0.0, 1, 2));
+        assert_eq! (solver.locals_len (), 0);
+
+        assert! (solver.add_local_sum (1.0, 0, 1));
+        assert! (solver.add_local_sum (0.0, 1, 1));
+        assert! (solver.is_round_complete ());
+    }
+}
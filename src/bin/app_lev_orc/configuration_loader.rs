@@ -4,45 +4,1286 @@
 
 // This component is responsible for loading the initial
 // set of requests for a node.
-// To do so, a configuration file is used, located in the
-// 'requests' folder.
+// To do so, each request folder in 'requests' carries a
+// manifest (manifest.toml) describing the request.
+//
+// It also loads the configuration of the node, written as
+// a set of named keys grouped in sections, e.g.:
+//
+//     [node]
+//     index = 0
+//     state = "[(1.0,1.0);1]"
+//
+// The syntax is a subset of TOML: comments start with '#',
+// values are integers, floats, booleans, quoted strings or
+// single-line arrays of them.
 
-use std::io::BufRead;
-use crate::state::{ApplicationState, Request};
+use crate::distance_metric::{DistanceMetric, Euclidean, Weighted, ZoneGraph};
+use crate::request_transfer;
+use crate::state::{ApplicationState, Attributes, Coord, NodeState, Request, RequestFolder, RequestId};
+use crate::coordination_strategy::CoordinationMode;
+use crate::negotiation::Limits;
+use crate::protocol::Encoding;
+use crate::tls::TlsFiles;
 
-pub fn load_requests (application_state: std::sync::Arc<std::sync::Mutex<ApplicationState>>)
+/// Name of the manifest file within a request folder.
+pub const MANIFEST_FILE_NAME : &str = "manifest.toml";
+
+/// Version of the manifest format supported by this node.
+pub const MANIFEST_VERSION : i64 = 1;
+
+/// Load the requests of `application_index' from the folders
+/// in `request_directory': the identified ones, named
+/// {app}_{origin}_{sequence}_req, and the new ones, named
+/// {app}_{label}_req, which are admitted by `node_index' and
+/// renamed after their identifier. The requests already recovered
+/// from the journal, or which it dropped (`dropped'), are skipped.
+pub fn load_requests (application_state : std::sync::Arc<std::sync::Mutex<ApplicationState>>,
+                      application_index : usize,
+                      node_index        : usize,
+                      request_directory : &str,
+                      dropped           : &std::collections::BTreeSet<RequestId>) -> Result<(), ConfigError>
 {
-    let config_file_name = "requests/requests.txt".to_string ();
+    let mut manifests : Vec<(String, RequestFolder, RequestManifest)> = Vec::new ();
+
+    let entries = std::fs::read_dir (request_directory)
+        .map_err (|e| ConfigError
+        {
+            file_name : request_directory.to_string (),
+            line      : None,
+            message   : format! ("unable to read the requests directory ({})", e),
+        })?;
+
+    for entry in entries.flatten ()
+    {
+        let path = entry.path ();
+        let folder_name = entry.file_name ().to_string_lossy ().to_string ();
+        if !path.is_dir () || !folder_name.starts_with (&format! ("{}_", application_index))
+            || !folder_name.ends_with ("_req")
+        {
+            continue;
+        }
+
+        let request_folder = RequestFolder::from_folder_name (&folder_name)
+            .ok_or_else (|| ConfigError
+            {
+                file_name : path.to_string_lossy ().to_string (),
+                line      : None,
+                message   : "invalid request folder name (expected {app}_{label}_req or {app}_{origin}_{sequence}_req)"
+                    .to_string (),
+            })?;
+        let request_id = match request_folder
+        {
+            RequestFolder::Identified (request_id) =>
+                {
+                    if dropped.contains (&request_id) || application_state.lock ().unwrap ().get_request (request_id).is_some ()
+                    {
+                        continue;
+                    }
+                    request_id
+                }
+
+            // The identifier of a new request is allocated once it is
+            // valid, the label stands in for its sequence until then.
+            RequestFolder::New { application, label } => RequestId::new (node_index, application, label),
+        };
+        manifests.push ((folder_name, request_folder, RequestManifest::load (&path.to_string_lossy (), request_id)?));
+    }
+
+    // Add the requests in a deterministic order, the identified
+    // ones first so that no new request takes their sequence.
+    manifests.sort_by_key (|(_, request_folder, manifest)|
+        (matches! (request_folder, RequestFolder::New { .. }), manifest.request.get_id ()));
 
     let mut application_state = application_state.lock ().unwrap ();
+    for (folder_name, request_folder, mut manifest) in manifests
+    {
+        match request_folder
+        {
+            RequestFolder::Identified (request_id) if request_id.get_origin () == node_index =>
+                application_state.reserve_sequence (request_id.get_sequence ()),
+            RequestFolder::Identified (_) => (),
+            RequestFolder::New { application, .. } =>
+                {
+                    let request_id = RequestId::new (node_index, application, application_state.allocate_sequence ());
+                    request_transfer::rename_request_folder (request_directory, &folder_name, request_id)
+                        .map_err (|e| ConfigError
+                        {
+                            file_name : format! ("{}/{}", request_directory, folder_name),
+                            line      : None,
+                            message   : format! ("unable to rename the request folder ({})", e),
+                        })?;
+                    manifest.request.set_id (request_id);
+                }
+        }
+
+        #[cfg(feature = "print_log")]
+        println! ("configuration_loader - request {} LOADED", manifest.request);
+
+        application_state.add_request (manifest.request);
+    }
+
+    Ok (())
+}
+
+/// The keys accepted in a request manifest.
+const MANIFEST_KEYS : [&str; 16] =
+    [
+        "version",
+        "request.index",
+        "request.application_index",
+        "request.execution_time",
+        "request.desired_completion_time",
+        "request.migratable_up_to",
+        "request.required_memory",
+        "request.desired_coord",
+        "request.desired_zone",
+        "request.desired_battery",
+        "request.desired_link_quality",
+        "request.desired_cpu_load",
+        "request.threshold",
+        "request.current_region",
+        "request.module",
+        "request.data_files",
+    ];
+
+/// Description of a request, stored in the manifest.toml file
+/// of its folder. The manifest travels with the request when
+/// it migrates. The index is a label for operators, the request
+/// is identified by its folder (see RequestFolder), e.g.:
+///
+///     version = 1
+///
+///     [request]
+///     index                   = 0
+///     application_index       = 0
+///     execution_time          = 200
+///     desired_completion_time = 250
+///     migratable_up_to        = 22
+///     required_memory         = 128
+///     desired_coord           = [1.0, 2.0]
+///     desired_zone            = 3
+///     threshold               = 1.5
+///     module                  = "module.wasm"
+///     data_files              = ["input_small.pgm"]
+///
+///     [metadata]
+///     description = "Edge detection on a small image"
+pub struct RequestManifest
+{
+    /// Version of the manifest format.
+    #[allow(dead_code)]
+    pub version           : i64,
+
+    /// Label of the request, given by operators.
+    #[allow(dead_code)]
+    pub label             : usize,
+
+    /// Index of the application of the request.
+    pub application_index : usize,
+
+    /// The request described by the manifest.
+    pub request           : Request,
+
+    /// Path of the Wasm module, relative to the request folder.
+    pub module            : String,
+
+    /// Files that must travel with the request, relative
+    /// to the request folder.
+    pub data_files        : Vec<String>,
+
+    /// Optional metadata, not interpreted by the orchestrator.
+    #[allow(dead_code)]
+    pub metadata          : Vec<(String, ConfigValue)>,
+}
+
+impl RequestManifest
+{
+    /// Load and validate the manifest in `request_folder', which
+    /// must describe a request of the application of `request_id'
+    /// (the request takes this identifier).
+    pub fn load (request_folder : &str, request_id : RequestId) -> Result<Self, ConfigError>
+    {
+        let file_name = format! ("{}/{}", request_folder, MANIFEST_FILE_NAME);
+        let text = std::fs::read_to_string (&file_name)
+            .map_err (|e| ConfigError
+            {
+                file_name : file_name.clone (),
+                line      : None,
+                message   : format! ("unable to read the manifest ({})", e),
+            })?;
+
+        let document = ConfigDocument::parse (&file_name, &text)?;
+        let manifest = Self::from_document (&document, request_id)?;
+
+        // The manifest must match the folder it is in.
+        if manifest.application_index != request_id.get_application ()
+        {
+            return Err (document.error_at ("request.application_index",
+                format! ("the request belongs to application {}, this node hosts application {}",
+                         manifest.application_index, request_id.get_application ())));
+        }
+
+        // Then check that the listed files are available.
+        for (key, path) in std::iter::once (("request.module", &manifest.module))
+            .chain (manifest.data_files.iter ().map (|path| ("request.data_files", path)))
+        {
+            if !std::path::Path::new (&format! ("{}/{}", request_folder, path)).is_file ()
+            {
+                return Err (document.error_at (key, format! ("file '{}' not found in {}", path, request_folder)));
+            }
+        }
+
+        Ok (manifest)
+    }
+
+    /// The manifest in `document', of the request `request_id'.
+    pub fn from_document (document : &ConfigDocument, request_id : RequestId) -> Result<Self, ConfigError>
+    {
+        let version = document.get_integer ("version", None)?;
+        if version != MANIFEST_VERSION
+        {
+            return Err (document.error_at ("version",
+                format! ("unsupported manifest version {} (expected {})", version, MANIFEST_VERSION)));
+        }
+
+        // Metadata are free-form, every other key must be known.
+        let metadata = document.get_section ("metadata");
+        let mut known_keys : Vec<String> = MANIFEST_KEYS.iter ().map (|key| key.to_string ()).collect ();
+        known_keys.extend (metadata.iter ().map (|(key, _)| format! ("metadata.{}", key)));
+        document.check_unknown_keys (&known_keys.iter ().map (|key| key.as_str ()).collect::<Vec<&str>> ())?;
+
+        let label : usize = document.get_in_range ("request.index", Some (0u32), 0, u32::MAX)? as usize;
+        let application_index : usize =
+            document.get_in_range ("request.application_index", None, 0u32, 1023)? as usize;
+        let execution_time : u32 = document.get_in_range ("request.execution_time", None, 1, u32::MAX)?;
+        let desired_completion_time : u32 =
+            document.get_in_range ("request.desired_completion_time", None, 1, u32::MAX)?;
+        let migratable_up_to : usize =
+            document.get_in_range ("request.migratable_up_to", None, 0u32, u32::MAX)? as usize;
+        let required_memory : u32 = document.get_in_range ("request.required_memory", None, 0, u32::MAX)?;
+        let current_region : usize =
+            document.get_in_range ("request.current_region", Some (0u32), 0, u32::MAX)? as usize;
+
+        let coord = document.get_float_array ("request.desired_coord")?;
+        if !(2..=3).contains (&coord.len ()) || coord.iter ().any (|c| !c.is_finite ())
+        {
+            return Err (document.error_at ("request.desired_coord",
+                "'request.desired_coord' must be an array of two or three numbers [x, y(, z)]".to_string ()));
+        }
+        let mut desired_attributes = Attributes::new_from (match coord[..]
+        {
+            [x, y, z] => Coord::new_from_3d (x as f32, y as f32, z as f32),
+            _         => Coord::new_from (coord[0] as f32, coord[1] as f32),
+        });
+
+        // The other attributes are optional.
+        if document.get ("request.desired_zone").is_some ()
+        {
+            desired_attributes.zone = Some (document.get_in_range ("request.desired_zone", None, 0, u32::MAX)?);
+        }
+        desired_attributes.battery      = Self::get_ratio (document, "request.desired_battery")?;
+        desired_attributes.link_quality = Self::get_ratio (document, "request.desired_link_quality")?;
+        desired_attributes.cpu_load     = Self::get_ratio (document, "request.desired_cpu_load")?;
+
+        let threshold = document.get_float ("request.threshold", None)?;
+        if !threshold.is_finite () || threshold < 0.0
+        {
+            return Err (document.error_at ("request.threshold",
+                format! ("'request.threshold' = {} must be a non-negative number", threshold)));
+        }
+
+        let module     = document.get_text ("request.module", Some ("module.wasm"))?;
+        let data_files = document.get_text_array ("request.data_files", Some (Vec::new ()))?;
+
+        // Files are relative to the request folder, and must not escape it.
+        for (key, path) in std::iter::once (("request.module", &module))
+            .chain (data_files.iter ().map (|path| ("request.data_files", path)))
+        {
+            let is_plain = std::path::Path::new (path).components ()
+                .all (|component| matches! (component, std::path::Component::Normal (_)));
+            if path.is_empty () || !is_plain
+            {
+                return Err (document.error_at (key,
+                    format! ("'{}' must be a path relative to the request folder", path)));
+            }
+        }
+
+        Ok (Self
+        {
+            version,
+            label,
+            application_index,
+            request : Request::new_from (request_id,
+                                         execution_time,
+                                         desired_completion_time,
+                                         migratable_up_to,
+                                         required_memory,
+                                         desired_attributes,
+                                         threshold as f32,
+                                         current_region),
+            module,
+            data_files,
+            metadata,
+        })
+    }
+
+    /// The optional attribute `key', in [0, 1].
+    fn get_ratio (document : &ConfigDocument, key : &str) -> Result<Option<f32>, ConfigError>
+    {
+        if document.get (key).is_none ()
+        {
+            return Ok (None);
+        }
+        let ratio = document.get_float (key, None)?;
+        if !(0.0..=1.0).contains (&ratio)
+        {
+            return Err (document.error_at (key, format! ("'{}' = {} must be in [0, 1]", key, ratio)));
+        }
+        Ok (Some (ratio as f32))
+    }
+
+    /// Files to transfer when the request migrates, relative
+    /// to the request folder: the manifest, the module and the
+    /// data files.
+    pub fn files_to_transfer (&self) -> Vec<String>
+    {
+        let mut files = vec! [MANIFEST_FILE_NAME.to_string (), self.module.clone ()];
+        files.extend (self.data_files.iter ().cloned ());
+        files
+    }
+}
+
+/// Error produced while reading a configuration file,
+/// it carries the line where the problem was found
+/// (when it refers to a specific line).
+#[derive(Debug)]
+pub struct ConfigError
+{
+    /// Name of the configuration file.
+    pub file_name : String,
+
+    /// Line of the error, starting from 1.
+    pub line      : Option<usize>,
+
+    /// Description of the error.
+    pub message   : String,
+}
+
+impl std::fmt::Display for ConfigError
+{
+    fn fmt (&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self.line
+        {
+            Some (line) => write! (f, "{}:{}: {}", self.file_name, line, self.message),
+            None        => write! (f, "{}: {}", self.file_name, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// A value assigned to a key in a configuration file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValue
+{
+    Integer (i64),
+    Float   (f64),
+    Boolean (bool),
+    Text    (String),
+    Array   (Vec<ConfigValue>),
+}
+
+impl ConfigValue
+{
+    fn type_name (&self) -> &'static str
+    {
+        match self
+        {
+            ConfigValue::Integer (_) => "an integer",
+            ConfigValue::Float (_)   => "a float",
+            ConfigValue::Boolean (_) => "a boolean",
+            ConfigValue::Text (_)    => "a string",
+            ConfigValue::Array (_)   => "an array",
+        }
+    }
+}
+
+impl std::str::FromStr for ConfigValue
+{
+    type Err = String;
+
+    fn from_str (s: &str) -> Result<Self, Self::Err>
+    {
+        if s.starts_with ('[')
+        {
+            if !s.ends_with (']')
+            {
+                return Err (format! ("unterminated array {}", s));
+            }
+
+            // Split on the commas outside of strings.
+            let mut items : Vec<ConfigValue> = Vec::new ();
+            let mut in_string = false;
+            let mut item = String::new ();
+            for c in s[1..s.len () - 1].chars ().chain (std::iter::once (','))
+            {
+                if c == '"'
+                {
+                    in_string = !in_string;
+                }
+                if c == ',' && !in_string
+                {
+                    let trimmed = item.trim ();
+                    if !trimmed.is_empty ()
+                    {
+                        if trimmed.starts_with ('[')
+                        {
+                            return Err (format! ("nested arrays are not supported: {}", s));
+                        }
+                        items.push (trimmed.parse ()?);
+                    }
+                    item.clear ();
+                }
+                else
+                {
+                    item.push (c);
+                }
+            }
+            Ok (ConfigValue::Array (items))
+        }
+        else if s.len () >= 2 && s.starts_with ('"') && s.ends_with ('"')
+        {
+            let text = &s[1..s.len () - 1];
+            if text.contains ('"')
+            {
+                return Err (format! ("unexpected quote in string {}", s));
+            }
+            Ok (ConfigValue::Text (text.to_string ()))
+        }
+        else if s == "true" || s == "false"
+        {
+            Ok (ConfigValue::Boolean (s == "true"))
+        }
+        else if let Ok (integer) = s.replace ('_', "").parse::<i64> ()
+        {
+            Ok (ConfigValue::Integer (integer))
+        }
+        else if let Ok (float) = s.replace ('_', "").parse::<f64> ()
+        {
+            Ok (ConfigValue::Float (float))
+        }
+        else
+        {
+            Err (format! ("unable to parse value '{}' (strings must be quoted)", s))
+        }
+    }
+}
+
+/// A key-value pair found in a configuration file.
+#[derive(Clone, Debug)]
+pub struct ConfigEntry
+{
+    /// The key, qualified by its section: "section.key".
+    pub key   : String,
+
+    pub value : ConfigValue,
+
+    /// Line of the entry, starting from 1.
+    pub line  : usize,
+}
+
+/// A parsed configuration file.
+pub struct ConfigDocument
+{
+    file_name : String,
+    entries   : Vec<ConfigEntry>,
+
+    /// Section headers, with their line.
+    sections  : Vec<(String, usize)>,
+}
+
+impl ConfigDocument
+{
+    pub fn parse (file_name : &str, text : &str) -> Result<Self, ConfigError>
+    {
+        let mut entries  : Vec<ConfigEntry> = Vec::new ();
+        let mut sections : Vec<(String, usize)> = Vec::new ();
+        let mut section = String::new ();
+
+        for (i, raw_line) in text.lines ().enumerate ()
+        {
+            let line_number = i + 1;
+            let error = |message : String| ConfigError
+            {
+                file_name : file_name.to_string (),
+                line      : Some (line_number),
+                message,
+            };
+
+            // Strip comments, unless the '#' is within a string.
+            let mut in_string = false;
+            let mut end = raw_line.len ();
+            for (j, c) in raw_line.char_indices ()
+            {
+                if c == '"'
+                {
+                    in_string = !in_string;
+                }
+                else if c == '#' && !in_string
+                {
+                    end = j;
+                    break;
+                }
+            }
+            let line = raw_line[..end].trim ();
+
+            if line.is_empty ()
+            {
+                continue;
+            }
+
+            // Section header.
+            if line.starts_with ('[')
+            {
+                if !line.ends_with (']') || line.len () < 3
+                {
+                    return Err (error (format! ("malformed section header '{}'", line)));
+                }
+                section = line[1..line.len () - 1].trim ().to_string ();
+                if let Some ((_, previous)) = sections.iter ().find (|(name, _)| *name == section)
+                {
+                    return Err (error (format! ("duplicate section '[{}]' (first defined at line {})", section, previous)));
+                }
+                sections.push ((section.clone (), line_number));
+                continue;
+            }
+
+            // Key-value pair.
+            let (key, value) = match line.split_once ('=')
+            {
+                Some ((key, value)) => (key.trim (), value.trim ()),
+                None => return Err (error (format! ("expected 'key = value', found '{}'", line))),
+            };
+            if key.is_empty ()
+            {
+                return Err (error ("missing key before '='".to_string ()));
+            }
+            let value : ConfigValue = value.parse ().map_err (error)?;
+            let key = if section.is_empty () { key.to_string () } else { format! ("{}.{}", section, key) };
+
+            if let Some (previous) = entries.iter ().find (|entry| entry.key == key)
+            {
+                return Err (error (format! ("duplicate key '{}' (first defined at line {})", key, previous.line)));
+            }
+
+            entries.push (ConfigEntry { key, value, line: line_number });
+        }
+
+        Ok (Self { file_name: file_name.to_string (), entries, sections })
+    }
+
+    pub fn get (&self, key : &str) -> Option<&ConfigEntry>
+    {
+        self.entries.iter ().find (|entry| entry.key == key)
+    }
+
+    /// Names of the sections starting with `prefix.' (without the
+    /// prefix), with their line, including the sections without keys.
+    pub fn get_subsections (&self, prefix : &str) -> Vec<(String, usize)>
+    {
+        let prefix = format! ("{}.", prefix);
+        let mut subsections : Vec<(String, usize)> = self.sections.iter ()
+            .filter_map (|(section, line)| section.strip_prefix (&prefix)
+                .map (|name| (name.to_string (), *line)))
+            .collect ();
+
+        // Keys might also be written in full (e.g. application.1.budget).
+        for entry in &self.entries
+        {
+            let name = entry.key.strip_prefix (&prefix)
+                .and_then (|key| key.split_once ('.'))
+                .map (|(name, _)| name);
+            if let Some (name) = name.filter (|name| !subsections.iter ().any (|(n, _)| n == name))
+            {
+                subsections.push ((name.to_string (), entry.line));
+            }
+        }
+        subsections
+    }
+
+    /// Build an error pointing to the line of `key', if present.
+    pub fn error_at (&self, key : &str, message : String) -> ConfigError
+    {
+        ConfigError
+        {
+            file_name : self.file_name.clone (),
+            line      : self.get (key).map (|entry| entry.line),
+            message,
+        }
+    }
+
+    /// Reject keys that are not in `known_keys', most likely typos.
+    pub fn check_unknown_keys (&self, known_keys : &[&str]) -> Result<(), ConfigError>
+    {
+        match self.entries.iter ().find (|entry| !known_keys.contains (&entry.key.as_str ()))
+        {
+            Some (entry) => Err (self.error_at (&entry.key, format! ("unknown key '{}'", entry.key))),
+            None         => Ok (()),
+        }
+    }
 
-    // Read the file line by line, each line encodes for a request.
-    for line in std::fs::read_to_string (config_file_name).unwrap ().lines ()
+    fn get_value (&self, key : &str, default : Option<ConfigValue>) -> Result<ConfigValue, ConfigError>
     {
-        let request : Request = line.parse ()
-            .expect ("Failed to parse line in requests.txt");
+        match (self.get (key), default)
+        {
+            (Some (entry), _)      => Ok (entry.value.clone ()),
+            (None, Some (default)) => Ok (default),
+            (None, None)           => Err (self.error_at (key, format! ("missing required key '{}'", key))),
+        }
+    }
+
+    pub fn get_integer (&self, key : &str, default : Option<i64>) -> Result<i64, ConfigError>
+    {
+        match self.get_value (key, default.map (ConfigValue::Integer))?
+        {
+            ConfigValue::Integer (integer) => Ok (integer),
+            other => Err (self.error_at (key, format! ("'{}' must be an integer, found {}", key, other.type_name ()))),
+        }
+    }
+
+    /// Read an integer and check that it is within `min..=max'.
+    pub fn get_in_range<T> (&self, key : &str, default : Option<T>, min : T, max : T) -> Result<T, ConfigError>
+    where
+        T : Copy + std::fmt::Display + TryFrom<i64> + Into<i64>
+    {
+        let integer = self.get_integer (key, default.map (|d| d.into ()))?;
+        if integer < min.into () || integer > max.into ()
+        {
+            return Err (self.error_at (key, format! ("'{}' = {} is out of range [{}, {}]", key, integer, min, max)));
+        }
+        T::try_from (integer)
+            .map_err (|_| self.error_at (key, format! ("'{}' = {} is out of range", key, integer)))
+    }
+
+    pub fn get_float (&self, key : &str, default : Option<f64>) -> Result<f64, ConfigError>
+    {
+        match self.get_value (key, default.map (ConfigValue::Float))?
+        {
+            ConfigValue::Float (float)     => Ok (float),
+            ConfigValue::Integer (integer) => Ok (integer as f64),
+            other => Err (self.error_at (key, format! ("'{}' must be a number, found {}", key, other.type_name ()))),
+        }
+    }
+
+    pub fn get_bool (&self, key : &str, default : Option<bool>) -> Result<bool, ConfigError>
+    {
+        match self.get_value (key, default.map (ConfigValue::Boolean))?
+        {
+            ConfigValue::Boolean (flag) => Ok (flag),
+            other => Err (self.error_at (key, format! ("'{}' must be a boolean, found {}", key, other.type_name ()))),
+        }
+    }
+
+    pub fn get_float_array (&self, key : &str) -> Result<Vec<f64>, ConfigError>
+    {
+        let not_numbers = || self.error_at (key, format! ("'{}' must be an array of numbers", key));
+        match self.get_value (key, None)?
+        {
+            ConfigValue::Array (items) => items.iter ()
+                .map (|item| match item
+                {
+                    ConfigValue::Float (float)     => Ok (*float),
+                    ConfigValue::Integer (integer) => Ok (*integer as f64),
+                    _                              => Err (not_numbers ()),
+                })
+                .collect (),
+            _ => Err (not_numbers ()),
+        }
+    }
+
+    pub fn get_text_array (&self, key : &str, default : Option<Vec<String>>) -> Result<Vec<String>, ConfigError>
+    {
+        let not_strings = || self.error_at (key, format! ("'{}' must be an array of strings", key));
+        let default = default.map (|d| ConfigValue::Array (d.into_iter ().map (ConfigValue::Text).collect ()));
+        match self.get_value (key, default)?
+        {
+            ConfigValue::Array (items) => items.iter ()
+                .map (|item| match item
+                {
+                    ConfigValue::Text (text) => Ok (text.clone ()),
+                    _                        => Err (not_strings ()),
+                })
+                .collect (),
+            _ => Err (not_strings ()),
+        }
+    }
+
+    /// Return the entries of `section', with unqualified keys.
+    pub fn get_section (&self, section : &str) -> Vec<(String, ConfigValue)>
+    {
+        let prefix = format! ("{}.", section);
+        self.entries.iter ()
+            .filter_map (|entry| entry.key.strip_prefix (&prefix)
+                .map (|key| (key.to_string (), entry.value.clone ())))
+            .collect ()
+    }
 
-        application_state.add_request (request);
+    pub fn get_text (&self, key : &str, default : Option<&str>) -> Result<String, ConfigError>
+    {
+        match self.get_value (key, default.map (|d| ConfigValue::Text (d.to_string ())))?
+        {
+            ConfigValue::Text (text) => Ok (text),
+            other => Err (self.error_at (key, format! ("'{}' must be a string, found {}", key, other.type_name ()))),
+        }
     }
 }
 
-pub fn load_config (config_file: String) -> Vec<String>
+/// The keys accepted in a node configuration file.
+const NODE_CONFIG_KEYS : [&str; 40] =
+    [
+        "node.index",
+        "node.application_index",
+        "node.address",
+        "node.state",
+        "node.affinity",
+        "federation.coordination",
+        "federation.broker_address",
+        "federation.broker_port",
+        "federation.embedded_broker",
+        "federation.data_port",
+        "federation.encoding",
+        "admm.penalty",
+        "admm.round_timeout",
+        "admm.session_timeout",
+        "admm.quorum",
+        "distance.metric",
+        "distance.position",
+        "distance.zone",
+        "distance.battery",
+        "distance.link_quality",
+        "distance.cpu_load",
+        "distance.zone_links",
+        "sporadic_server.budget",
+        "sporadic_server.period",
+        "memory.assigned_memory",
+        "memory.use_cgroup",
+        "priorities.state_monitoring",
+        "priorities.requests_monitoring",
+        "priorities.requests_coordination",
+        "priorities.sporadic_server",
+        "priorities.requests_admission",
+        "requests_monitoring.period",
+        "requests_monitoring.first_activation_delay",
+        "requests_admission.period",
+        "shutdown.drain_timeout",
+        "membership.heartbeat_period",
+        "membership.missed_heartbeats",
+        "tls.ca_file",
+        "tls.cert_file",
+        "tls.key_file",
+    ];
+
+/// The keys accepted in a [application.N] section.
+const APPLICATION_KEYS : [&str; 3] = ["budget", "period", "assigned_memory"];
+
+/// Configuration of an application hosted by a node.
+#[derive(Clone)]
+pub struct ApplicationConfig
+{
+    /// Index of the application.
+    pub application_index      : usize,
+
+    /// Budget and period of the sporadic server, in ms.
+    pub sporadic_server_budget : u32,
+    pub sporadic_server_period : u32,
+
+    /// Memory assigned to the application in kB.
+    pub assigned_memory        : u32,
+}
+
+/// Configuration of a node, loaded at startup.
+#[derive(Clone)]
+pub struct NodeConfig
 {
-    let file = std::fs::OpenOptions::new ()
-        .read (true)
-        .open (&config_file)
-        .expect ("Unable to open config file");
-    let buf_reader = std::io::BufReader::new (file);
-    let mut lines = Vec::<String>::new ();
+    /// Index of the node in the federation.
+    pub node_index        : usize,
+
+    /// Applications hosted by the node.
+    pub applications      : Vec<ApplicationConfig>,
+
+    /// Address of the node, used for the transfer of requests.
+    pub node_address      : String,
+
+    /// Initial state of the node.
+    pub node_state        : NodeState,
+
+    /// CPU where the tasks of the orchestrator run.
+    pub affinity          : usize,
+
+    /// How the federation decides where to migrate a request.
+    pub coordination      : CoordinationMode,
+
+    /// Address and port of the MQTT broker.
+    pub broker_address    : String,
+    pub broker_port       : u16,
+
+    /// The node runs the MQTT broker of the federation, on broker_port.
+    pub embedded_broker   : bool,
+
+    /// Port used for the transfer of requests.
+    pub data_port         : u16,
+
+    /// Encoding of the messages sent through MQTT.
+    pub encoding          : Encoding,
+
+    /// Memory cgroup of the node, when its limit bounds the
+    /// memory available to the requests.
+    pub memory_cgroup     : Option<std::path::PathBuf>,
+
+    /// Penalty factor of the ADMM algorithm.
+    pub penalty           : f32,
+
+    /// Timeouts and quorum of the negotiations.
+    pub limits            : Limits,
 
-    for line in buf_reader.lines ()
+    /// Distance between the desired attributes of a request and
+    /// the attributes of a node, shared by the migration trigger
+    /// and the ADMM.
+    pub distance_metric   : std::sync::Arc<dyn DistanceMetric>,
+
+    /// Priorities of the tasks (SCHED_FIFO).
+    pub state_monitoring_priority      : i32,
+    pub requests_monitoring_priority   : i32,
+    pub requests_coordination_priority : i32,
+    pub sporadic_server_priority       : i32,
+    pub requests_admission_priority    : i32,
+
+    /// Period of the requests monitoring loop, in us.
+    pub requests_monitoring_period     : i32,
+
+    /// Delay of the first activation of the requests
+    /// monitoring loop, in us.
+    pub first_activation_delay         : i32,
+
+    /// Period of the scan of the requests directory for new
+    /// requests, in ms (0 disables the admission at runtime).
+    pub requests_admission_period      : u32,
+
+    /// Maximum time to hand off the requests on shutdown, in ms.
+    pub drain_timeout                  : u32,
+
+    /// Period of the heartbeats of the node, in ms.
+    pub heartbeat_period               : u32,
+
+    /// Number of heartbeats missed before a node is considered gone.
+    pub missed_heartbeats              : u32,
+
+    /// The CA, certificate and key of the node, when the broker
+    /// connection and the data plane use TLS.
+    pub tls                            : Option<TlsFiles>,
+}
+
+impl NodeConfig
+{
+    /// Load and validate the configuration in `config_file'.
+    pub fn load (config_file : &str) -> Result<Self, ConfigError>
     {
+        let text = std::fs::read_to_string (config_file)
+            .map_err (|e| ConfigError
+            {
+                file_name : config_file.to_string (),
+                line      : None,
+                message   : format! ("unable to read the file ({})", e),
+            })?;
+
         #[cfg(feature = "print_log")]
-        println!("line = {:?}", line);
+        println! ("configuration_loader - config = {:?}", text);
+
+        let document = ConfigDocument::parse (config_file, &text)?;
+        Self::from_document (&document)
+    }
 
-        lines.push (line.unwrap ());
+    pub fn from_document (document : &ConfigDocument) -> Result<Self, ConfigError>
+    {
+        // Each [application.N] section adds its own keys.
+        let application_indices = Self::application_indices (document)?;
+        let mut known_keys : Vec<String> = NODE_CONFIG_KEYS.iter ().map (|key| key.to_string ()).collect ();
+        for application_index in &application_indices
+        {
+            known_keys.extend (APPLICATION_KEYS.iter ()
+                .map (|key| format! ("application.{}.{}", application_index, key)));
+        }
+        document.check_unknown_keys (&known_keys.iter ().map (|key| key.as_str ()).collect::<Vec<&str>> ())?;
+
+        // Available CPUs, to validate the affinity.
+        let cpus = unsafe { libc::sysconf (libc::_SC_NPROCESSORS_CONF) }.max (1) as i64;
+
+        let node_index  : usize = document.get_in_range ("node.index", None, 0u32, 1023)? as usize;
+
+        let node_state : NodeState = document.get_text ("node.state", None)?.parse ()
+            .map_err (|e| document.error_at ("node.state", format! ("unable to parse 'node.state' ({})", e)))?;
+
+        let affinity = document.get_integer ("node.affinity", Some (0))?;
+        if affinity < 0 || affinity >= cpus
+        {
+            return Err (document.error_at ("node.affinity",
+                format! ("'node.affinity' = {} is not a valid CPU (this host has {})", affinity, cpus)));
+        }
+
+        let coordination : CoordinationMode = document.get_text ("federation.coordination", Some ("distributed"))?
+            .parse ()
+            .map_err (|e| document.error_at ("federation.coordination",
+                format! ("{} (expected \"centralized\" or \"distributed\")", e)))?;
+
+        let encoding : Encoding = document.get_text ("federation.encoding", Some ("json"))?
+            .parse ()
+            .map_err (|e| document.error_at ("federation.encoding", e))?;
+
+        let penalty = document.get_float ("admm.penalty", Some (70.0))?;
+        if !penalty.is_finite () || penalty <= 0.0
+        {
+            return Err (document.error_at ("admm.penalty",
+                format! ("'admm.penalty' = {} must be a positive number", penalty)));
+        }
+
+        // By default, a round goes on with a majority of the nodes
+        // taking part in the negotiation.
+        let limits = Limits
+        {
+            round_timeout   : std::time::Duration::from_millis (
+                document.get_in_range ("admm.round_timeout", Some (1_000), 1, 3_600_000u32)? as u64),
+            session_timeout : std::time::Duration::from_millis (
+                document.get_in_range ("admm.session_timeout", Some (10_000), 1, 3_600_000u32)? as u64),
+            quorum          : match document.get ("admm.quorum")
+            {
+                Some (_) => Some (document.get_in_range ("admm.quorum", None, 1, 1024u32)? as usize),
+                None     => None,
+            },
+        };
+
+        let applications = Self::applications_from_document (document, &application_indices)?;
+        let distance_metric = Self::distance_metric_from_document (document)?;
+
+        let memory_cgroup = if document.get_bool ("memory.use_cgroup", Some (false))?
+        {
+            Some (crate::linux_utils::memory_cgroup ()
+                .ok_or_else (|| document.error_at ("memory.use_cgroup",
+                    "'memory.use_cgroup' is set, but this process has no memory cgroup".to_string ()))?)
+        }
+        else
+        {
+            None
+        };
+
+        let node_address   = document.get_text ("node.address", None)?;
+        if node_address.is_empty ()
+        {
+            return Err (document.error_at ("node.address", "'node.address' must not be empty".to_string ()));
+        }
+        let broker_address = document.get_text ("federation.broker_address", None)?;
+        if broker_address.is_empty ()
+        {
+            return Err (document.error_at ("federation.broker_address",
+                "'federation.broker_address' must not be empty".to_string ()));
+        }
+
+        // The [tls] section enables TLS, with all of its files.
+        let tls = if ["tls.ca_file", "tls.cert_file", "tls.key_file"].iter ().any (|key| document.get (key).is_some ())
+        {
+            Some (TlsFiles
+            {
+                ca_file   : document.get_text ("tls.ca_file", None)?,
+                cert_file : document.get_text ("tls.cert_file", None)?,
+                key_file  : document.get_text ("tls.key_file", None)?,
+            })
+        }
+        else
+        {
+            None
+        };
+
+        Ok (Self
+        {
+            node_index,
+            applications,
+            node_address,
+            node_state,
+            affinity          : affinity as usize,
+            coordination,
+            broker_address,
+            broker_port       : document.get_in_range ("federation.broker_port", Some (1883), 1, u16::MAX)?,
+            embedded_broker   : document.get_bool ("federation.embedded_broker", Some (false))?,
+            data_port         : document.get_in_range ("federation.data_port", Some (8888), 1, u16::MAX)?,
+            encoding,
+            memory_cgroup,
+            penalty           : penalty as f32,
+            limits,
+            distance_metric,
+            state_monitoring_priority      :
+                document.get_in_range ("priorities.state_monitoring", Some (50), 1, 99)?,
+            requests_monitoring_priority   :
+                document.get_in_range ("priorities.requests_monitoring", Some (50), 1, 99)?,
+            requests_coordination_priority :
+                document.get_in_range ("priorities.requests_coordination", Some (45), 1, 99)?,
+            sporadic_server_priority       :
+                document.get_in_range ("priorities.sporadic_server", Some (30), 1, 99)?,
+            requests_admission_priority    :
+                document.get_in_range ("priorities.requests_admission", Some (20), 1, 99)?,
+            // The activation step does not support periods >= 1 sec.
+            requests_monitoring_period     :
+                document.get_in_range ("requests_monitoring.period", Some (1_000), 1, 999_999)?,
+            first_activation_delay         :
+                document.get_in_range ("requests_monitoring.first_activation_delay", Some (10_000), 0, 999_999)?,
+            requests_admission_period      :
+                document.get_in_range ("requests_admission.period", Some (500), 0, 60_000)?,
+            drain_timeout                  :
+                document.get_in_range ("shutdown.drain_timeout", Some (10_000), 0, 3_600_000)?,
+            heartbeat_period               :
+                document.get_in_range ("membership.heartbeat_period", Some (1_000), 10, 60_000)?,
+            missed_heartbeats              :
+                document.get_in_range ("membership.missed_heartbeats", Some (3), 1, 100)?,
+            tls,
+        })
+    }
+
+    /// The metric of the [distance] section: "euclidean" (default),
+    /// "weighted" (with a weight per attribute) or "zone_graph" (with
+    /// the links between the zones, e.g. zone_links = ["0-1", "1-2"]).
+    fn distance_metric_from_document (document : &ConfigDocument) -> Result<std::sync::Arc<dyn DistanceMetric>, ConfigError>
+    {
+        let metric = document.get_text ("distance.metric", Some ("euclidean"))?;
+        match metric.as_str ()
+        {
+            "euclidean"  => Ok (std::sync::Arc::new (Euclidean)),
+            "weighted"   =>
+                {
+                    let weight = |key : &str, default : f64| -> Result<f32, ConfigError>
+                    {
+                        let weight = document.get_float (key, Some (default))?;
+                        if !weight.is_finite () || weight < 0.0
+                        {
+                            return Err (document.error_at (key,
+                                format! ("'{}' = {} must be a non-negative number", key, weight)));
+                        }
+                        Ok (weight as f32)
+                    };
+                    Ok (std::sync::Arc::new (Weighted
+                    {
+                        position     : weight ("distance.position", 1.0)?,
+                        zone         : weight ("distance.zone", 1.0)?,
+                        battery      : weight ("distance.battery", 1.0)?,
+                        link_quality : weight ("distance.link_quality", 1.0)?,
+                        cpu_load     : weight ("distance.cpu_load", 1.0)?,
+                    }))
+                }
+            "zone_graph" =>
+                {
+                    let mut links = Vec::new ();
+                    for link in document.get_text_array ("distance.zone_links", Some (Vec::new ()))?
+                    {
+                        let zones = link.split_once ('-')
+                            .and_then (|(a, b)| Some ((a.trim ().parse::<u32> ().ok ()?, b.trim ().parse::<u32> ().ok ()?)))
+                            .ok_or_else (|| document.error_at ("distance.zone_links",
+                                format! ("'{}' is not a link between two zones (expected \"A-B\")", link)))?;
+                        links.push (zones);
+                    }
+                    Ok (std::sync::Arc::new (ZoneGraph::new (&links)))
+                }
+            _ => Err (document.error_at ("distance.metric",
+                format! ("unknown distance metric '{}' (expected \"euclidean\", \"weighted\" or \"zone_graph\")", metric))),
+        }
+    }
+
+    /// Indices of the [application.N] sections, in increasing order.
+    fn application_indices (document : &ConfigDocument) -> Result<Vec<usize>, ConfigError>
+    {
+        let mut indices : Vec<usize> = Vec::new ();
+        for (name, line) in document.get_subsections ("application")
+        {
+            let error = |message : String| ConfigError
+            {
+                file_name : document.file_name.clone (),
+                line      : Some (line),
+                message,
+            };
+            let index : usize = name.parse ()
+                .map_err (|_| error (format! ("'{}' is not a valid application index (expected [application.N])", name)))?;
+            if index > 1023
+            {
+                return Err (error (format! ("application index {} is out of range [0, 1023]", index)));
+            }
+            if !indices.contains (&index)
+            {
+                indices.push (index);
+            }
+        }
+        indices.sort ();
+        Ok (indices)
+    }
+
+    /// The applications hosted by the node: either the [application.N]
+    /// sections, or the single application of node.application_index.
+    /// The sporadic_server and memory sections provide the defaults.
+    fn applications_from_document (document            : &ConfigDocument,
+                                   application_indices : &[usize]) -> Result<Vec<ApplicationConfig>, ConfigError>
+    {
+        let default_budget : u32 = document.get_in_range ("sporadic_server.budget", Some (20), 1, 60_000)?;
+        let default_period : u32 = document.get_in_range ("sporadic_server.period", Some (100), 1, 60_000)?;
+        let default_memory : u32 = document.get_in_range ("memory.assigned_memory", Some (1_000_000), 1, u32::MAX)?;
+
+        if application_indices.is_empty ()
+        {
+            let application_index = document.get_in_range ("node.application_index", Some (0u32), 0, 1023)? as usize;
+            if default_budget > default_period
+            {
+                return Err (document.error_at ("sporadic_server.budget",
+                    format! ("'sporadic_server.budget' = {} exceeds 'sporadic_server.period' = {}",
+                             default_budget, default_period)));
+            }
+            return Ok (vec![ApplicationConfig
+            {
+                application_index,
+                sporadic_server_budget : default_budget,
+                sporadic_server_period : default_period,
+                assigned_memory        : default_memory,
+            }]);
+        }
+
+        if document.get ("node.application_index").is_some ()
+        {
+            return Err (document.error_at ("node.application_index",
+                "'node.application_index' cannot be used together with [application.N] sections".to_string ()));
+        }
+
+        let mut applications = Vec::with_capacity (application_indices.len ());
+        let mut utilization  = 0.0;
+        for application_index in application_indices
+        {
+            let key = |name : &str| format! ("application.{}.{}", application_index, name);
+            let budget : u32 = document.get_in_range (&key ("budget"), Some (default_budget), 1, 60_000)?;
+            let period : u32 = document.get_in_range (&key ("period"), Some (default_period), 1, 60_000)?;
+            if budget > period
+            {
+                return Err (document.error_at (&key ("budget"),
+                    format! ("'{}' = {} exceeds the period {}", key ("budget"), budget, period)));
+            }
+
+            // The sporadic servers share the same CPU.
+            utilization += budget as f64 / period as f64;
+            if utilization > 1.0
+            {
+                return Err (document.error_at (&key ("budget"),
+                    "the sporadic servers of the applications exceed the capacity of the CPU".to_string ()));
+            }
+
+            applications.push (ApplicationConfig
+            {
+                application_index      : *application_index,
+                sporadic_server_budget : budget,
+                sporadic_server_period : period,
+                assigned_memory        : document.get_in_range (&key ("assigned_memory"), Some (default_memory), 1, u32::MAX)?,
+            });
+        }
+        Ok (applications)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn parse (text : &str) -> Result<ConfigDocument, ConfigError>
+    {
+        ConfigDocument::parse ("test.toml", text)
+    }
+
+    /// The line and the message of the error of parsing `text'.
+    fn parse_error (text : &str) -> (Option<usize>, String)
+    {
+        match parse (text)
+        {
+            Ok (_)      => panic! ("parsed: {}", text),
+            Err (error) => (error.line, error.message),
+        }
+    }
+
+    #[test]
+    fn comments_are_stripped_outside_of_strings ()
+    {
+        let document = parse ("# header\n\
+                               [section] # comment\n\
+                               text = \"a # b\" # comment\n\
+                               texts = [\"#\", \"c\"]#comment\n").unwrap ();
+        assert_eq! (document.get_text ("section.text", None).unwrap (), "a # b");
+        assert_eq! (document.get_text_array ("section.texts", None).unwrap (), vec! ["#", "c"]);
+
+        // Quotes cannot be escaped.
+        assert_eq! (parse_error ("text = \"a \\\" b\"\n").0, Some (1));
+    }
+
+    #[test]
+    fn arrays ()
+    {
+        let document = parse ("numbers = [1, 2.5, -3_000]\n\
+                               texts   = [\"a,b\", \"c\",]\n\
+                               empty   = []\n").unwrap ();
+        assert_eq! (document.get_float_array ("numbers").unwrap (), vec! [1.0, 2.5, -3000.0]);
+        assert_eq! (document.get_text_array ("texts", None).unwrap (), vec! ["a,b", "c"]);
+        assert_eq! (document.get_text_array ("empty", None).unwrap (), Vec::<String>::new ());
+        assert! (document.get_text_array ("numbers", None).is_err ());
+
+        assert! (parse_error ("a = [[1], [2]]\n").1.contains ("nested arrays"));
+        assert! (parse_error ("a = [1, 2\n").1.contains ("unterminated array"));
+        assert! (parse_error ("a = [1, b]\n").1.contains ("strings must be quoted"));
+    }
+
+    #[test]
+    fn duplicates_are_rejected ()
+    {
+        assert_eq! (parse_error ("[a]\nb = 1\nb = 2\n"),
+                    (Some (3), "duplicate key 'a.b' (first defined at line 2)".to_string ()));
+        assert_eq! (parse_error ("a.b = 1\n[a]\nb = 2\n"),
+                    (Some (3), "duplicate key 'a.b' (first defined at line 1)".to_string ()));
+        assert_eq! (parse_error ("[a]\nb = 1\n[c]\n[a]\nd = 2\n"),
+                    (Some (4), "duplicate section '[a]' (first defined at line 1)".to_string ()));
+
+        // The same key in different sections.
+        assert! (parse ("[a]\nb = 1\n[c]\nb = 1\n").is_ok ());
     }
 
-    lines
+    #[test]
+    fn errors_carry_their_line ()
+    {
+        assert_eq! (parse_error ("a = 1\n\n[b\n").0, Some (3));
+        assert_eq! (parse_error ("# comment\na = 1\nb\n").0, Some (3));
+        assert_eq! (parse_error ("a = 1\n = 2\n").0, Some (2));
+        assert_eq! (parse_error ("a = 1\nb = unquoted\n").0, Some (2));
+
+        let document = parse ("a = 1\n\n[b]\nc = \"text\"\n").unwrap ();
+        assert_eq! (document.get_integer ("b.c", None).unwrap_err ().line, Some (4));
+        assert_eq! (document.get_integer ("b.d", None).unwrap_err ().line, None);
+        assert_eq! (document.check_unknown_keys (&["a"]).unwrap_err ().line, Some (4));
+        assert_eq! (document.error_at ("a", String::new ()).to_string (), "test.toml:1: ");
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn get_in_range_checks_the_bounds ()
+    {
+        let document = parse ("low = 1\nhigh = 10\nbelow = 0\nabove = 11\nfloat = 1.5\nhuge = 4_294_967_296\n").unwrap ();
+        assert_eq! (document.get_in_range ("low", None, 1u32, 10).unwrap (), 1);
+        assert_eq! (document.get_in_range ("high", None, 1u32, 10).unwrap (), 10);
+        assert_eq! (document.get_in_range ("below", None, 1u32, 10).unwrap_err ().line, Some (3));
+        assert_eq! (document.get_in_range ("above", None, 1u32, 10).unwrap_err ().message,
+                    "'above' = 11 is out of range [1, 10]");
+        assert! (document.get_in_range ("float", None, 1u32, 10).is_err ());
+        assert! (document.get_in_range ("huge", None, 0, u32::MAX).is_err ());
+
+        // The default is used when the key is missing, and checked too.
+        assert_eq! (document.get_in_range ("missing", Some (5u32), 1, 10).unwrap (), 5);
+        assert! (document.get_in_range ("missing", Some (0u32), 1, 10).is_err ());
+        assert! (document.get_in_range::<u32> ("missing", None, 1, 10).is_err ());
+    }
+}
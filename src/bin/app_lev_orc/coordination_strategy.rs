@@ -0,0 +1,59 @@
+/****************************************/
+/*        COORDINATION STRATEGY         */
+/****************************************/
+
+use crate::state::{ApplicationState, RequestId};
+
+/// How the nodes of the federation coordinate to decide
+/// where a migrating request should be hosted.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum CoordinationMode
+{
+    /// A controller node performs the global update of the
+    /// ADMM algorithm on behalf of the federation.
+    Centralized,
+
+    /// Every node performs the global update on its own.
+    Distributed,
+}
+
+impl std::str::FromStr for CoordinationMode
+{
+    type Err = String;
+
+    /// The expected string: centralized or distributed.
+    fn from_str (s: &str) -> Result<Self, Self::Err>
+    {
+        match s
+        {
+            "centralized" => Ok (CoordinationMode::Centralized),
+            "distributed" => Ok (CoordinationMode::Distributed),
+            _             => Err (format! ("unknown coordination mode '{}'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for CoordinationMode
+{
+    fn fmt (&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            CoordinationMode::Centralized => write! (f, "centralized"),
+            CoordinationMode::Distributed => write! (f, "distributed"),
+        }
+    }
+}
+
+/// Common interface of the requests coordination loops,
+/// so that the coordination mode can be selected at startup.
+pub trait CoordinationStrategy : Send
+{
+    /// Start the request coordination loop implementing the
+    /// ADMM consensus algorithm. The sporadic server posts on
+    /// `job_ends' the requests whose job has ended.
+    fn start (&mut self,
+              application_state : std::sync::Arc<std::sync::Mutex<ApplicationState>>,
+              barrier           : std::sync::Arc<(std::sync::Mutex<u8>, std::sync::Condvar)>,
+              job_ends          : futures::channel::mpsc::UnboundedReceiver<RequestId>);
+}
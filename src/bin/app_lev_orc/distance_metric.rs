@@ -0,0 +1,144 @@
+/***************************************/
+/*           DISTANCE METRIC           */
+/***************************************/
+
+// The distance between the attributes desired by a request
+// and the attributes of a node is used both to trigger a
+// migration (state::should_migrate) and as the cost term of
+// the ADMM (LocalSolver::local_x_update), so that the two
+// always agree. The metric is selected in the [distance]
+// section of the node configuration.
+
+use crate::state::Attributes;
+
+/// Distance between the desired attributes of a request
+/// and the attributes of a node.
+pub trait DistanceMetric : Send + Sync
+{
+    /// Distance of `actual' (node) from `desired' (request),
+    /// a finite non-negative number.
+    fn distance (&self, desired : &Attributes, actual : &Attributes) -> f32;
+}
+
+/// Euclidean distance between the positions, in 2D or 3D
+/// (a 2D position has height 0). The other attributes are
+/// ignored, as in the original experimentation.
+pub struct Euclidean;
+
+impl DistanceMetric for Euclidean
+{
+    fn distance (&self, desired : &Attributes, actual : &Attributes) -> f32
+    {
+        let (desired, actual) = (desired.position, actual.position);
+        ((desired.get_x () - actual.get_x ()).powi (2)
+            + (desired.get_y () - actual.get_y ()).powi (2)
+            + (desired.get_z () - actual.get_z ()).powi (2)).sqrt ()
+    }
+}
+
+/// Weighted sum of the distance of each attribute: the
+/// Euclidean distance of the positions, 1 for a different
+/// zone, and the absolute difference of the other attributes.
+/// An attribute the request does not specify is ignored; one
+/// the node does not report counts as the farthest value.
+pub struct Weighted
+{
+    pub position     : f32,
+    pub zone         : f32,
+    pub battery      : f32,
+    pub link_quality : f32,
+    pub cpu_load     : f32,
+}
+
+impl DistanceMetric for Weighted
+{
+    fn distance (&self, desired : &Attributes, actual : &Attributes) -> f32
+    {
+        let mut distance = self.position * Euclidean.distance (desired, actual);
+
+        if desired.zone.is_some () && actual.zone != desired.zone
+        {
+            distance += self.zone;
+        }
+
+        for (weight, desired, actual) in [(self.battery, desired.battery, actual.battery),
+                                          (self.link_quality, desired.link_quality, actual.link_quality),
+                                          (self.cpu_load, desired.cpu_load, actual.cpu_load)]
+        {
+            if let Some (desired) = desired
+            {
+                // The ratios are in [0, 1].
+                distance += weight * actual.map_or (1.0, |actual| (desired - actual).abs ());
+            }
+        }
+        distance
+    }
+}
+
+/// Number of hops between the zones, in the graph of the
+/// zones of the plant. A request without a desired zone is
+/// at distance 0 from any node; a node without a zone, or in
+/// a zone that cannot be reached, is at distance `unreachable'.
+pub struct ZoneGraph
+{
+    /// Zones adjacent to each zone.
+    links       : std::collections::HashMap<u32, Vec<u32>>,
+
+    /// Distance of an unreachable zone, larger than any path.
+    unreachable : f32,
+}
+
+impl ZoneGraph
+{
+    /// Build the graph from its (undirected) links.
+    pub fn new (links : &[(u32, u32)]) -> Self
+    {
+        let mut adjacency : std::collections::HashMap<u32, Vec<u32>> = std::collections::HashMap::new ();
+        for &(a, b) in links
+        {
+            adjacency.entry (a).or_default ().push (b);
+            adjacency.entry (b).or_default ().push (a);
+        }
+        Self
+        {
+            unreachable : adjacency.len () as f32 + 1.0,
+            links       : adjacency,
+        }
+    }
+
+    /// Hop count between two zones (breadth-first search).
+    fn hops (&self, from : u32, to : u32) -> Option<u32>
+    {
+        let mut visited = std::collections::HashSet::from ([from]);
+        let mut queue   = std::collections::VecDeque::from ([(from, 0)]);
+        while let Some ((zone, hops)) = queue.pop_front ()
+        {
+            if zone == to
+            {
+                return Some (hops);
+            }
+            for &next in self.links.get (&zone).into_iter ().flatten ()
+            {
+                if visited.insert (next)
+                {
+                    queue.push_back ((next, hops + 1));
+                }
+            }
+        }
+        None
+    }
+}
+
+impl DistanceMetric for ZoneGraph
+{
+    fn distance (&self, desired : &Attributes, actual : &Attributes) -> f32
+    {
+        match (desired.zone, actual.zone)
+        {
+            (None, _)                       => 0.0,
+            (Some (desired), Some (actual)) =>
+                self.hops (desired, actual).map_or (self.unreachable, |hops| hops as f32),
+            (Some (_), None)                => self.unreachable,
+        }
+    }
+}
@@ -0,0 +1,1099 @@
+/***************************************/
+/*           EMBEDDED BROKER           */
+/***************************************/
+
+// A minimal MQTT broker (3.1.1 and 5), that a node starts within the
+// orchestrator with federation.embedded_broker, so that a small
+// federation or a local test needs no separately installed broker.
+// It covers what the nodes and the mosquitto clients use: QoS 0 and 1
+// (QoS 2 publications are acknowledged, then delivered with QoS 1),
+// wildcards, retained messages, will messages and keep alive. Sessions
+// are not persisted (every connection starts clean), and credentials
+// are not checked. Each connection is read by its own thread and
+// written by another one, so that a slow client holds up no other.
+// With TLS, clients must present a certificate of the federation CA,
+// and their client identifier must start with the name it carries.
+// A message which claims to come from a node (see claimed_node) is
+// only relayed from the client with the certificate of that node. A
+// TLS stream cannot be shared between threads: the thread of such a
+// connection both reads it and writes the packets queued meanwhile.
+
+use std::io::{Read, Write};
+use openssl::ssl::SslStream;
+use crate::protocol::{self, Message};
+use crate::tls::{self, Tls};
+
+/// Largest packet accepted.
+const MAX_PACKET_LEN  : usize = 16 << 20;
+
+/// Maximum wait for the CONNECT packet of a new connection.
+const CONNECT_TIMEOUT : std::time::Duration = std::time::Duration::from_secs (10);
+
+/// Period at which a TLS connection writes the packets queued for it.
+const TLS_POLL_PERIOD : std::time::Duration = std::time::Duration::from_millis (1);
+
+/// Types of the control packets (upper half of their first byte).
+const CONNECT     : u8 = 1;
+const CONNACK     : u8 = 2;
+const PUBLISH     : u8 = 3;
+const PUBACK      : u8 = 4;
+const PUBREC      : u8 = 5;
+const PUBREL      : u8 = 6;
+const PUBCOMP     : u8 = 7;
+const SUBSCRIBE   : u8 = 8;
+const SUBACK      : u8 = 9;
+const UNSUBSCRIBE : u8 = 10;
+const UNSUBACK    : u8 = 11;
+const PINGREQ     : u8 = 12;
+const PINGRESP    : u8 = 13;
+const DISCONNECT  : u8 = 14;
+
+/// Protocol level of MQTT 5.
+const MQTT_5 : u8 = 5;
+
+/// A message published on the broker.
+#[derive(Clone)]
+struct Publish
+{
+    topic   : String,
+    payload : Vec<u8>,
+    qos     : u8,
+    retain  : bool,
+}
+
+/// A topic filter a client subscribed to.
+struct Subscription
+{
+    filter              : String,
+    qos                 : u8,
+
+    /// The messages published by the client itself are not sent back (MQTT 5).
+    no_local            : bool,
+
+    /// The messages keep their retain flag (MQTT 5).
+    retain_as_published : bool,
+}
+
+/// A connected client.
+struct Client
+{
+    client_id      : String,
+
+    /// Protocol level of the connection (3, 4 or 5).
+    version        : u8,
+
+    /// Packets to be written to the client.
+    outbox         : std::sync::mpsc::Sender<Vec<u8>>,
+
+    /// The connection, to close it when taken over.
+    stream         : std::net::TcpStream,
+
+    subscriptions  : Vec<Subscription>,
+
+    /// Identifier of the next packet with QoS 1 sent to the client.
+    next_packet_id : u16,
+}
+
+impl Client
+{
+    /// Queue `publish' for the client, with at most `qos'.
+    fn deliver (&mut self, publish : &Publish, qos : u8, retain : bool)
+    {
+        let qos = qos.min (publish.qos).min (1);
+        let mut body = Vec::new ();
+        put_string (&mut body, publish.topic.as_bytes ());
+        if qos > 0
+        {
+            self.next_packet_id = self.next_packet_id.checked_add (1).unwrap_or (1);
+            body.extend_from_slice (&self.next_packet_id.to_be_bytes ());
+        }
+        if self.version == MQTT_5
+        {
+            body.push (0);
+        }
+        body.extend_from_slice (&publish.payload);
+
+        // A client which is closing is ignored.
+        let _ = self.outbox.send (packet (PUBLISH << 4 | qos << 1 | retain as u8, &body));
+    }
+}
+
+/// The content of a CONNECT packet.
+struct Connect
+{
+    version    : u8,
+    client_id  : String,
+    keep_alive : u16,
+    will       : Option<Publish>,
+}
+
+/// The stream of a connection.
+enum Transport
+{
+    Plain (std::net::TcpStream),
+    Tls (Box<SslStream<std::net::TcpStream>>),
+}
+
+impl Transport
+{
+    fn read_packet (&mut self) -> std::io::Result<(u8, Vec<u8>)>
+    {
+        match self
+        {
+            Transport::Plain (stream) => read_packet (stream),
+            Transport::Tls (stream)   => read_packet (stream.as_mut ()),
+        }
+    }
+
+    fn write_all (&mut self, bytes : &[u8]) -> std::io::Result<()>
+    {
+        match self
+        {
+            Transport::Plain (stream) => stream.write_all (bytes),
+            Transport::Tls (stream)   => stream.write_all (bytes),
+        }
+    }
+}
+
+/// The packets of a connected client.
+enum Packets
+{
+    /// Read by the thread of the connection, while another thread
+    /// writes the packets queued for the client.
+    Plain (std::net::TcpStream),
+
+    /// Read with a short timeout by the thread of the connection,
+    /// which writes in between the packets queued for the client.
+    Tls
+    {
+        stream     : Box<SslStream<std::net::TcpStream>>,
+        inbox      : std::sync::mpsc::Receiver<Vec<u8>>,
+
+        /// The bytes read and not yet parsed.
+        buffer     : Vec<u8>,
+
+        keep_alive : Option<std::time::Duration>,
+        last_read  : std::time::Instant,
+    },
+}
+
+impl Packets
+{
+    /// Set up the reading of `transport', and the writing of the
+    /// packets queued in `inbox'.
+    fn open (transport  : Transport,
+             inbox      : std::sync::mpsc::Receiver<Vec<u8>>,
+             keep_alive : Option<std::time::Duration>) -> std::io::Result<Self>
+    {
+        match transport
+        {
+            Transport::Plain (stream) =>
+                {
+                    stream.set_read_timeout (keep_alive)?;
+                    let mut writer = stream.try_clone ()?;
+                    std::thread::spawn (move ||
+                        {
+                            for bytes in inbox
+                            {
+                                if writer.write_all (&bytes).is_err ()
+                                {
+                                    break;
+                                }
+                            }
+                        });
+                    Ok (Packets::Plain (stream))
+                }
+            Transport::Tls (stream) =>
+                {
+                    stream.get_ref ().set_read_timeout (Some (TLS_POLL_PERIOD))?;
+                    let last_read = std::time::Instant::now ();
+                    Ok (Packets::Tls { stream, inbox, buffer : Vec::new (), keep_alive, last_read })
+                }
+        }
+    }
+
+    /// The next packet of the client.
+    fn next (&mut self) -> std::io::Result<(u8, Vec<u8>)>
+    {
+        let (stream, inbox, buffer, keep_alive, last_read) = match self
+        {
+            Packets::Plain (stream) => return read_packet (stream),
+            Packets::Tls { stream, inbox, buffer, keep_alive, last_read } => (stream, inbox, buffer, keep_alive, last_read),
+        };
+        loop
+        {
+            if let Some (packet) = take_packet (buffer)?
+            {
+                *last_read = std::time::Instant::now ();
+                return Ok (packet);
+            }
+            loop
+            {
+                match inbox.try_recv ()
+                {
+                    Ok (bytes) => stream.write_all (&bytes)?,
+                    Err (std::sync::mpsc::TryRecvError::Empty) => break,
+                    Err (std::sync::mpsc::TryRecvError::Disconnected) =>
+                        return Err (std::io::Error::new (std::io::ErrorKind::ConnectionAborted, "taken over")),
+                }
+            }
+            let mut chunk = [0u8; 4096];
+            match stream.read (&mut chunk)
+            {
+                Ok (0) => return Err (std::io::ErrorKind::UnexpectedEof.into ()),
+                Ok (n) => buffer.extend_from_slice (&chunk[..n]),
+                Err (e) if matches! (e.kind (), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) =>
+                    {
+                        if keep_alive.is_some_and (|keep_alive| last_read.elapsed () > keep_alive)
+                        {
+                            return Err (std::io::Error::new (std::io::ErrorKind::TimedOut, "keep alive expired"));
+                        }
+                    }
+                Err (e) => return Err (e),
+            }
+        }
+    }
+}
+
+/// How a connection goes on after a packet.
+enum Next
+{
+    Continue,
+    Disconnect { with_will : bool },
+}
+
+#[derive(Default)]
+struct BrokerState
+{
+    /// The connected clients, by connection.
+    clients         : std::collections::HashMap<usize, Client>,
+
+    /// The retained messages, by topic.
+    retained        : std::collections::BTreeMap<String, Publish>,
+
+    /// Identifier of the next connection.
+    next_connection : usize,
+}
+
+/// The embedded broker.
+pub struct Broker
+{
+    state : std::sync::Mutex<BrokerState>,
+
+    /// The TLS context of the broker, if the connections use TLS.
+    tls   : Option<Tls>,
+}
+
+impl Broker
+{
+    /// Start a broker listening on `ip_and_port', with TLS if `tls'
+    /// is given, and return the address it is bound to.
+    pub fn start (ip_and_port : &str, tls : Option<Tls>) -> std::io::Result<std::net::SocketAddr>
+    {
+        let listener = std::net::TcpListener::bind (ip_and_port)?;
+        let address  = listener.local_addr ()?;
+        let broker   = std::sync::Arc::new (Broker { state : std::sync::Mutex::new (BrokerState::default ()), tls });
+
+        #[cfg(feature = "print_log")]
+        println! ("embedded_broker - LISTENING on {}", address);
+
+        std::thread::spawn (move ||
+            {
+                for stream in listener.incoming ()
+                {
+                    match stream
+                    {
+                        Ok (stream) =>
+                            {
+                                let broker = broker.clone ();
+                                std::thread::spawn (move || broker.serve (stream));
+                            }
+                        Err (e) => eprintln! ("embedded_broker - connection failed: {e}"),
+                    }
+                }
+            });
+        Ok (address)
+    }
+
+    /// Serve the client connected on `stream', until it disconnects.
+    fn serve (&self, stream : std::net::TcpStream)
+    {
+        // With TLS, the name of the client is the one in its certificate.
+        let transport = stream.set_read_timeout (Some (CONNECT_TIMEOUT))
+            .and_then (|_| match &self.tls
+                {
+                    Some (tls) => tls.accept (stream.try_clone ()?)
+                        .map (|(stream, peer)| (Transport::Tls (Box::new (stream)), Some (peer))),
+                    None => Ok ((Transport::Plain (stream.try_clone ()?), None)),
+                });
+        let (mut transport, peer) = match transport
+        {
+            Ok (transport) => transport,
+            Err (e) =>
+                {
+                    eprintln! ("embedded_broker - connection refused: {e}");
+                    return;
+                }
+        };
+        let connect = transport.read_packet ()
+            .and_then (|(header, body)|
+                if header >> 4 == CONNECT { parse_connect (&body) } else { Err (invalid ("CONNECT expected")) });
+        let connect = match connect
+        {
+            Ok (connect) => connect,
+            Err (e) =>
+                {
+                    eprintln! ("embedded_broker - invalid connection: {e}");
+                    return;
+                }
+        };
+        if !(3..=MQTT_5).contains (&connect.version)
+        {
+            // Unacceptable protocol version, in the MQTT 3 form.
+            let _ = transport.write_all (&packet (CONNACK << 4, &[0, 1]));
+            return;
+        }
+        if let Some (peer) = &peer
+            && connect.client_id != *peer
+            && !connect.client_id.starts_with (&format! ("{}_", peer))
+        {
+            eprintln! ("embedded_broker - client {} REJECTED: its certificate is the one of {}", connect.client_id, peer);
+
+            // Not authorized.
+            let refused : &[u8] = if connect.version == MQTT_5 { &[0, 0x87, 0] } else { &[0, 5] };
+            let _ = transport.write_all (&packet (CONNACK << 4, refused));
+            return;
+        }
+
+        // Without packets for 1.5 times the keep alive, the client is lost.
+        let keep_alive = (connect.keep_alive > 0)
+            .then (|| std::time::Duration::from_millis (connect.keep_alive as u64 * 1_500));
+        let (outbox, inbox) = std::sync::mpsc::channel::<Vec<u8>> ();
+        let (mut packets, closing) = match (Packets::open (transport, inbox, keep_alive), stream.try_clone ())
+        {
+            (Ok (packets), Ok (closing)) => (packets, closing),
+            _ =>
+                {
+                    eprintln! ("embedded_broker - unable to set up the connection of {}", connect.client_id);
+                    return;
+                }
+        };
+
+        let version = connect.version;
+        let (connection, client_id, assigned) = self.open (connect.client_id, version, outbox, closing);
+
+        #[cfg(feature = "print_log")]
+        println! ("embedded_broker - client {} CONNECTED (MQTT level {})", client_id, version);
+
+        // Accepted, with the identifier assigned to an anonymous client.
+        let mut body = vec![0, 0];
+        if version == MQTT_5
+        {
+            let mut properties = Vec::new ();
+            if assigned
+            {
+                properties.push (0x12);
+                put_string (&mut properties, client_id.as_bytes ());
+            }
+            put_varint (&mut body, properties.len ());
+            body.extend_from_slice (&properties);
+        }
+        self.send_to (connection, packet (CONNACK << 4, &body));
+
+        let with_will = loop
+        {
+            let next = packets.next ()
+                .and_then (|(header, body)| self.handle (connection, peer.as_deref (), version, header, &body));
+            match next
+            {
+                Ok (Next::Continue) => {}
+                Ok (Next::Disconnect { with_will }) => break with_will,
+                Err (e) =>
+                    {
+                        if e.kind () != std::io::ErrorKind::UnexpectedEof
+                        {
+                            eprintln! ("embedded_broker - client {} lost: {}", client_id, e);
+                        }
+                        break true;
+                    }
+            }
+        };
+
+        #[cfg(feature = "print_log")]
+        println! ("embedded_broker - client {} DISCONNECTED", client_id);
+
+        self.state.lock ().unwrap ().clients.remove (&connection);
+        let _ = stream.shutdown (std::net::Shutdown::Both);
+        if with_will
+            && let Some (will) = connect.will
+            && is_authentic (peer.as_deref (), &will)
+        {
+            self.route (connection, will);
+        }
+    }
+
+    /// Register a client, and return its connection, its identifier
+    /// and whether it was assigned by the broker. A client with the
+    /// same identifier is disconnected.
+    fn open (&self,
+             client_id : String,
+             version   : u8,
+             outbox    : std::sync::mpsc::Sender<Vec<u8>>,
+             stream    : std::net::TcpStream) -> (usize, String, bool)
+    {
+        let mut state = self.state.lock ().unwrap ();
+        let connection = state.next_connection;
+        state.next_connection += 1;
+
+        let assigned = client_id.is_empty ();
+        let client_id = if assigned { format! ("embedded-{}", connection) } else { client_id };
+        state.clients.retain (|_, client|
+            {
+                let taken_over = client.client_id == client_id;
+                if taken_over
+                {
+                    let _ = client.stream.shutdown (std::net::Shutdown::Both);
+                }
+                !taken_over
+            });
+
+        let client = Client
+        {
+            client_id      : client_id.clone (),
+            version,
+            outbox,
+            stream,
+            subscriptions  : Vec::new (),
+            next_packet_id : 0,
+        };
+        state.clients.insert (connection, client);
+        (connection, client_id, assigned)
+    }
+
+    /// Queue `bytes' for the client of `connection'.
+    fn send_to (&self, connection : usize, bytes : Vec<u8>)
+    {
+        if let Some (client) = self.state.lock ().unwrap ().clients.get (&connection)
+        {
+            let _ = client.outbox.send (bytes);
+        }
+    }
+
+    /// Handle a packet received from the client of `connection', whose
+    /// certificate carries the name `peer' (with TLS).
+    fn handle (&self,
+               connection : usize,
+               peer       : Option<&str>,
+               version    : u8,
+               header     : u8,
+               body       : &[u8]) -> std::io::Result<Next>
+    {
+        let mut reader = Reader { body, version };
+        match header >> 4
+        {
+            PUBLISH =>
+                {
+                    let qos = (header >> 1) & 3;
+                    let topic = reader.string ()?;
+                    if qos == 3 || !valid_topic (&topic)
+                    {
+                        return Err (invalid ("invalid PUBLISH"));
+                    }
+                    if qos > 0
+                    {
+                        let packet_id = reader.u16 ()?;
+                        let ack = if qos == 1 { PUBACK << 4 } else { PUBREC << 4 };
+                        self.send_to (connection, packet (ack, &packet_id.to_be_bytes ()));
+                    }
+                    reader.skip_properties ()?;
+                    let publish = Publish { topic, payload : reader.body.to_vec (), qos, retain : header & 1 == 1 };
+                    if is_authentic (peer, &publish)
+                    {
+                        self.route (connection, publish);
+                    }
+                }
+            PUBREL =>
+                {
+                    let packet_id = reader.u16 ()?;
+                    self.send_to (connection, packet (PUBCOMP << 4, &packet_id.to_be_bytes ()));
+                }
+            // Acknowledgements of the messages sent with QoS 1.
+            PUBACK | PUBREC | PUBCOMP => {}
+            SUBSCRIBE if header & 0x0F == 2 =>
+                {
+                    let packet_id = reader.u16 ()?;
+                    reader.skip_properties ()?;
+                    let mut subscriptions = Vec::new ();
+                    while !reader.body.is_empty ()
+                    {
+                        let filter  = reader.string ()?;
+                        let options = reader.u8 ()?;
+                        subscriptions.push ((filter, options));
+                    }
+                    if subscriptions.is_empty ()
+                    {
+                        return Err (invalid ("SUBSCRIBE without topic"));
+                    }
+                    self.subscribe (connection, version, packet_id, subscriptions);
+                }
+            UNSUBSCRIBE if header & 0x0F == 2 =>
+                {
+                    let packet_id = reader.u16 ()?;
+                    reader.skip_properties ()?;
+                    let mut body = packet_id.to_be_bytes ().to_vec ();
+                    if version == MQTT_5
+                    {
+                        body.push (0);
+                    }
+                    let mut state = self.state.lock ().unwrap ();
+                    let client = state.clients.get_mut (&connection).ok_or_else (|| invalid ("taken over"))?;
+                    while !reader.body.is_empty ()
+                    {
+                        let filter = reader.string ()?;
+                        let subscribed = client.subscriptions.len ();
+                        client.subscriptions.retain (|subscription| subscription.filter != filter);
+                        if version == MQTT_5
+                        {
+                            // Success, or no subscription existed.
+                            body.push (if client.subscriptions.len () < subscribed { 0x00 } else { 0x11 });
+                        }
+                    }
+                    let _ = client.outbox.send (packet (UNSUBACK << 4, &body));
+                }
+            PINGREQ => self.send_to (connection, packet (PINGRESP << 4, &[])),
+            DISCONNECT =>
+                {
+                    // MQTT 5: "disconnect with will message".
+                    let reason = if version == MQTT_5 && !body.is_empty () { body[0] } else { 0 };
+                    return Ok (Next::Disconnect { with_will : reason == 0x04 });
+                }
+            _ => return Err (invalid (&format! ("unexpected packet 0x{:02x}", header))),
+        }
+        Ok (Next::Continue)
+    }
+
+    /// Add the subscriptions of the client of `connection', acknowledge
+    /// them, then send the retained messages they match.
+    fn subscribe (&self, connection : usize, version : u8, packet_id : u16, subscriptions : Vec<(String, u8)>)
+    {
+        let mut state = self.state.lock ().unwrap ();
+        let BrokerState { clients, retained, .. } = &mut *state;
+        let client = match clients.get_mut (&connection)
+        {
+            Some (client) => client,
+            None => return,
+        };
+
+        let mut body = packet_id.to_be_bytes ().to_vec ();
+        if version == MQTT_5
+        {
+            body.push (0);
+        }
+        let mut matching = Vec::new ();
+        for (filter, options) in subscriptions
+        {
+            if !valid_filter (&filter)
+            {
+                body.push (if version == MQTT_5 { 0x8F } else { 0x80 });
+                continue;
+            }
+            let qos = (options & 3).min (1);
+            body.push (qos);
+
+            // Retain handling 2: no retained messages on subscription.
+            if version != MQTT_5 || (options >> 4) & 3 != 2
+            {
+                matching.extend (retained.values ()
+                    .filter (|publish| matches (&filter, &publish.topic))
+                    .map (|publish| (publish.clone (), qos)));
+            }
+            let subscription = Subscription
+            {
+                no_local            : version == MQTT_5 && options & 0x04 != 0,
+                retain_as_published : version == MQTT_5 && options & 0x08 != 0,
+                filter,
+                qos,
+            };
+            client.subscriptions.retain (|existing| existing.filter != subscription.filter);
+            client.subscriptions.push (subscription);
+        }
+        let _ = client.outbox.send (packet (SUBACK << 4, &body));
+        for (publish, qos) in matching
+        {
+            client.deliver (&publish, qos, true);
+        }
+    }
+
+    /// Deliver `publish', sent by the client of `connection', to the
+    /// clients subscribed to its topic, once each, with the highest
+    /// QoS of their matching subscriptions.
+    fn route (&self, connection : usize, publish : Publish)
+    {
+        let mut state = self.state.lock ().unwrap ();
+        if publish.retain
+        {
+            // An empty retained message clears the one of its topic.
+            if publish.payload.is_empty ()
+            {
+                state.retained.remove (&publish.topic);
+            }
+            else
+            {
+                state.retained.insert (publish.topic.clone (), publish.clone ());
+            }
+        }
+
+        for (&subscriber, client) in state.clients.iter_mut ()
+        {
+            let delivery = client.subscriptions.iter ()
+                .filter (|subscription| !(subscription.no_local && subscriber == connection))
+                .filter (|subscription| matches (&subscription.filter, &publish.topic))
+                .fold (None, |delivery : Option<(u8, bool)>, subscription|
+                    {
+                        let (qos, retain) = delivery.unwrap_or ((0, false));
+                        Some ((qos.max (subscription.qos), retain || subscription.retain_as_published))
+                    });
+            if let Some ((qos, retain_as_published)) = delivery
+            {
+                client.deliver (&publish, qos, publish.retain && retain_as_published);
+            }
+        }
+    }
+}
+
+/// The node that `publish' claims to come from, if any: the node of
+/// its node_state_i topic, or the src of a migration or of a local
+/// update, or the node of a heartbeat or of a deadline miss. The other
+/// messages are addressed to a node, and do not name their sender.
+fn claimed_node (publish : &Publish) -> Option<usize>
+{
+    if let Some (node) = publish.topic.strip_prefix ("node_state_")
+    {
+        return node.parse ().ok ();
+    }
+    match protocol::decode_message (&publish.payload).ok ()?
+    {
+        Message::Migration (message)    => Some (message.get_src ()),
+        Message::LocalUpdate (message)  => Some (message.src),
+        Message::DeadlineMiss (message) => Some (message.node),
+        Message::Presence (message)     => Some (message.node),
+        _                               => None,
+    }
+}
+
+/// Whether `publish' may be relayed from the client whose certificate
+/// carries the name `peer': with TLS, a message claiming to come from
+/// a node must be published by that node.
+fn is_authentic (peer : Option<&str>, publish : &Publish) -> bool
+{
+    let (Some (peer), Some (node)) = (peer, claimed_node (publish)) else { return true };
+    if let Err (error) = tls::check_peer (peer, node)
+    {
+        eprintln! ("embedded_broker - message on {} DROPPED: {}", publish.topic, error);
+        return false;
+    }
+    true
+}
+
+fn invalid (message : &str) -> std::io::Error
+{
+    std::io::Error::new (std::io::ErrorKind::InvalidData, message.to_string ())
+}
+
+/// Read a packet: its first byte, then its body.
+fn read_packet (stream : &mut impl Read) -> std::io::Result<(u8, Vec<u8>)>
+{
+    let mut byte = [0u8; 1];
+    stream.read_exact (&mut byte)?;
+    let header = byte[0];
+
+    // The remaining length, on up to 4 bytes of 7 bits.
+    let mut length = 0usize;
+    for shift in [0, 7, 14, 21]
+    {
+        stream.read_exact (&mut byte)?;
+        length |= ((byte[0] & 0x7F) as usize) << shift;
+        if byte[0] & 0x80 == 0
+        {
+            if length > MAX_PACKET_LEN
+            {
+                return Err (invalid ("packet too large"));
+            }
+            let mut body = vec![0; length];
+            stream.read_exact (&mut body)?;
+            return Ok ((header, body));
+        }
+    }
+    Err (invalid ("malformed remaining length"))
+}
+
+/// Take the first packet out of `buffer', once it is complete.
+fn take_packet (buffer : &mut Vec<u8>) -> std::io::Result<Option<(u8, Vec<u8>)>>
+{
+    let mut length = 0usize;
+    for (i, shift) in [0, 7, 14, 21].into_iter ().enumerate ()
+    {
+        let Some (&byte) = buffer.get (1 + i) else { return Ok (None) };
+        length |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0
+        {
+            if length > MAX_PACKET_LEN
+            {
+                return Err (invalid ("packet too large"));
+            }
+            let start = 2 + i;
+            if buffer.len () < start + length
+            {
+                return Ok (None);
+            }
+            let header = buffer[0];
+            let body   = buffer[start..start + length].to_vec ();
+            buffer.drain (..start + length);
+            return Ok (Some ((header, body)));
+        }
+    }
+    Err (invalid ("malformed remaining length"))
+}
+
+/// A packet with the first byte `header' and `body'.
+fn packet (header : u8, body : &[u8]) -> Vec<u8>
+{
+    let mut packet = vec![header];
+    put_varint (&mut packet, body.len ());
+    packet.extend_from_slice (body);
+    packet
+}
+
+fn put_varint (buffer : &mut Vec<u8>, mut value : usize)
+{
+    loop
+    {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0
+        {
+            buffer.push (byte);
+            return;
+        }
+        buffer.push (byte | 0x80);
+    }
+}
+
+fn put_string (buffer : &mut Vec<u8>, string : &[u8])
+{
+    buffer.extend_from_slice (&(string.len () as u16).to_be_bytes ());
+    buffer.extend_from_slice (string);
+}
+
+/// Reader of the body of a packet.
+struct Reader<'a>
+{
+    body    : &'a [u8],
+
+    /// Protocol level of the connection.
+    version : u8,
+}
+
+impl<'a> Reader<'a>
+{
+    fn take (&mut self, n : usize) -> std::io::Result<&'a [u8]>
+    {
+        if self.body.len () < n
+        {
+            return Err (invalid ("truncated packet"));
+        }
+        let (taken, rest) = self.body.split_at (n);
+        self.body = rest;
+        Ok (taken)
+    }
+
+    fn u8 (&mut self) -> std::io::Result<u8>
+    {
+        Ok (self.take (1)?[0])
+    }
+
+    fn u16 (&mut self) -> std::io::Result<u16>
+    {
+        let bytes = self.take (2)?;
+        Ok (u16::from_be_bytes ([bytes[0], bytes[1]]))
+    }
+
+    fn binary (&mut self) -> std::io::Result<Vec<u8>>
+    {
+        let length = self.u16 ()? as usize;
+        Ok (self.take (length)?.to_vec ())
+    }
+
+    fn string (&mut self) -> std::io::Result<String>
+    {
+        String::from_utf8 (self.binary ()?).map_err (|_| invalid ("invalid UTF-8 string"))
+    }
+
+    fn varint (&mut self) -> std::io::Result<usize>
+    {
+        let mut value = 0usize;
+        for shift in [0, 7, 14, 21]
+        {
+            let byte = self.u8 ()?;
+            value |= ((byte & 0x7F) as usize) << shift;
+            if byte & 0x80 == 0
+            {
+                return Ok (value);
+            }
+        }
+        Err (invalid ("malformed variable integer"))
+    }
+
+    /// Skip the properties, present in MQTT 5 only.
+    fn skip_properties (&mut self) -> std::io::Result<()>
+    {
+        if self.version == MQTT_5
+        {
+            let length = self.varint ()?;
+            self.take (length)?;
+        }
+        Ok (())
+    }
+}
+
+fn parse_connect (body : &[u8]) -> std::io::Result<Connect>
+{
+    let mut reader = Reader { body, version : 0 };
+    let protocol = reader.string ()?;
+    let version  = reader.u8 ()?;
+    if protocol != "MQTT" && protocol != "MQIsdp"
+    {
+        return Err (invalid (&format! ("unknown protocol {}", protocol)));
+    }
+    if !(3..=MQTT_5).contains (&version)
+    {
+        // Answered with "unacceptable protocol version".
+        return Ok (Connect { version, client_id : String::new (), keep_alive : 0, will : None });
+    }
+    reader.version = version;
+
+    let flags      = reader.u8 ()?;
+    let keep_alive = reader.u16 ()?;
+    reader.skip_properties ()?;
+    let client_id  = reader.string ()?;
+    let will = if flags & 0x04 != 0
+    {
+        reader.skip_properties ()?;
+        let topic = reader.string ()?;
+        if !valid_topic (&topic)
+        {
+            return Err (invalid ("invalid will topic"));
+        }
+        let payload = reader.binary ()?;
+        Some (Publish { topic, payload, qos : (flags >> 3) & 3, retain : flags & 0x20 != 0 })
+    }
+    else
+    {
+        None
+    };
+    Ok (Connect { version, client_id, keep_alive, will })
+}
+
+/// A topic name has no wildcard.
+fn valid_topic (topic : &str) -> bool
+{
+    !topic.is_empty () && !topic.contains (['+', '#'])
+}
+
+/// In a topic filter, '+' stands for a whole level, and
+/// '#' for the whole last levels.
+fn valid_filter (filter : &str) -> bool
+{
+    let levels : Vec<&str> = filter.split ('/').collect ();
+    !filter.is_empty ()
+        && levels.iter ().enumerate ().all (|(i, level)|
+            match *level
+            {
+                "+" => true,
+                "#" => i == levels.len () - 1,
+                _   => !level.contains (['+', '#']),
+            })
+}
+
+/// Whether `topic' matches `filter'. The topics starting with '$'
+/// are not matched by a wildcard on their first level.
+fn matches (filter : &str, topic : &str) -> bool
+{
+    if topic.starts_with ('$') && filter.starts_with (['+', '#'])
+    {
+        return false;
+    }
+    let mut filter_levels = filter.split ('/');
+    let mut topic_levels  = topic.split ('/');
+    loop
+    {
+        match (filter_levels.next (), topic_levels.next ())
+        {
+            (Some ("#"), _)                         => return true,
+            (Some ("+"), Some (_))                  => {}
+            (Some (f), Some (t)) if f == t          => {}
+            (None, None)                            => return true,
+            _                                       => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::message_bus::{BusMessage, MessageBus, MqttBus};
+    use futures::{executor::block_on, stream::StreamExt};
+
+    #[test]
+    fn topic_filters ()
+    {
+        assert! (matches ("federation/app_1/migration", "federation/app_1/migration"));
+        assert! (matches ("federation/+/migration", "federation/app_1/migration"));
+        assert! (matches ("federation/#", "federation/app_1/migration"));
+        assert! (matches ("federation/#", "federation"));
+        assert! (!matches ("federation/+", "federation/app_1/migration"));
+        assert! (!matches ("#", "$SYS/uptime"));
+        assert! (valid_filter ("federation/+/src/#"));
+        assert! (!valid_filter ("federation/#/src"));
+        assert! (!valid_filter ("federation/app_+"));
+    }
+
+    #[test]
+    fn mqtt_3_client_publishes_with_qos_1 ()
+    {
+        let address = Broker::start ("127.0.0.1:0", None).unwrap ();
+        let mut stream = std::net::TcpStream::connect (address).unwrap ();
+
+        // CONNECT (MQTT 3.1.1, clean session, keep alive 60 s), client "sub".
+        let mut body = Vec::new ();
+        put_string (&mut body, b"MQTT");
+        body.extend_from_slice (&[4, 0x02, 0, 60]);
+        put_string (&mut body, b"sub");
+        stream.write_all (&packet (CONNECT << 4, &body)).unwrap ();
+        assert_eq! (read_packet (&mut stream).unwrap (), (CONNACK << 4, vec![0, 0]));
+
+        // SUBSCRIBE a/+ with QoS 1, then PUBLISH on a/b with QoS 1.
+        let mut body = vec![0, 1];
+        put_string (&mut body, b"a/+");
+        body.push (1);
+        stream.write_all (&packet (SUBSCRIBE << 4 | 2, &body)).unwrap ();
+        assert_eq! (read_packet (&mut stream).unwrap (), (SUBACK << 4, vec![0, 1, 1]));
+
+        let mut body = Vec::new ();
+        put_string (&mut body, b"a/b");
+        body.extend_from_slice (&[0, 7, b'x']);
+        stream.write_all (&packet (PUBLISH << 4 | 2, &body)).unwrap ();
+        assert_eq! (read_packet (&mut stream).unwrap (), (PUBACK << 4, vec![0, 7]));
+
+        let mut expected = Vec::new ();
+        put_string (&mut expected, b"a/b");
+        expected.extend_from_slice (&[0, 1, b'x']);
+        assert_eq! (read_packet (&mut stream).unwrap (), (PUBLISH << 4 | 2, expected));
+    }
+
+    #[test]
+    fn nodes_exchange_through_the_embedded_broker ()
+    {
+        let address = Broker::start ("127.0.0.1:0", None).unwrap ();
+        let connect = |client_id : &str, will : BusMessage|
+            MqttBus::connect (client_id, "127.0.0.1", address.port (), will, None).unwrap ();
+        let node_0 = connect ("node_0", BusMessage::new ("presence", b"gone 0".to_vec ()));
+        let node_1 = connect ("node_1", BusMessage::new ("presence", b"gone 1".to_vec ()));
+
+        let mut strm = block_on (node_1.subscribe (&["federation/app_0/migration".to_string (),
+                                                     "presence".to_string ()])).unwrap ();
+        block_on (node_0.publish (BusMessage::new ("federation/app_0/migration", b"request".to_vec ()))).unwrap ();
+        let msg = block_on (strm.next ()).flatten ().unwrap ();
+        assert_eq! ((msg.topic (), msg.payload ()), ("federation/app_0/migration", &b"request"[..]));
+
+        // A clean disconnection does not publish the will.
+        node_0.disconnect ().unwrap ();
+        block_on (node_1.publish (BusMessage::new ("presence", b"alive 1".to_vec ()))).unwrap ();
+        let msg = block_on (strm.next ()).flatten ().unwrap ();
+        assert_eq! (msg.payload (), b"alive 1");
+    }
+
+    #[test]
+    fn node_taken_over_reconnects_and_subscribes_again ()
+    {
+        let address = Broker::start ("127.0.0.1:0", None).unwrap ();
+        let connect = |client_id : &str|
+            MqttBus::connect (client_id, "127.0.0.1", address.port (), BusMessage::new ("presence", vec![]), None).unwrap ();
+        let node_0 = connect ("node_0");
+        let node_1 = connect ("node_1");
+        let mut strm = block_on (node_0.subscribe (&["federation/app_0/migration".to_string ()])).unwrap ();
+
+        // Another connection as node_0 drops the connection of node_0.
+        let mut stream = std::net::TcpStream::connect (address).unwrap ();
+        let mut body = Vec::new ();
+        put_string (&mut body, b"MQTT");
+        body.extend_from_slice (&[4, 0x02, 0, 60]);
+        put_string (&mut body, b"node_0");
+        stream.write_all (&packet (CONNECT << 4, &body)).unwrap ();
+        assert! (block_on (strm.next ()).unwrap ().is_none ());
+
+        // Once reconnected, node_0 gets the messages again.
+        let received = (0..50).any (|_|
+            {
+                block_on (node_1.publish (BusMessage::new ("federation/app_0/migration", b"request".to_vec ()))).unwrap ();
+                std::thread::sleep (std::time::Duration::from_millis (100));
+                matches! (strm.try_recv (), Ok (Some (_)))
+            });
+        assert! (received);
+    }
+
+    #[test]
+    fn nodes_connect_with_the_name_in_their_certificate ()
+    {
+        let dir = std::env::temp_dir ().join (format! ("broker_tls_test_{}", std::process::id ()));
+        let files = crate::tls::tests::generate (&dir, "ca", &["broker", "node_0", "node_1"]);
+        let address = Broker::start ("127.0.0.1:0", Some (Tls::new (&files[0]).unwrap ())).unwrap ();
+        let connect = |client_id : &str, files : &crate::tls::TlsFiles|
+            MqttBus::connect (client_id, "127.0.0.1", address.port (), BusMessage::new ("presence", vec![]), Some (files));
+
+        let node_0 = connect ("node_0_orc", &files[1]).unwrap ();
+        let mut strm = block_on (node_0.subscribe (&["federation/app_0/migration".to_string ()])).unwrap ();
+        block_on (node_0.publish (BusMessage::new ("federation/app_0/migration", b"request".to_vec ()))).unwrap ();
+        let msg = block_on (strm.next ()).flatten ().unwrap ();
+        assert_eq! (msg.payload (), b"request");
+
+        // node_1 cannot connect as node_0.
+        assert! (connect ("node_0_orc", &files[2]).is_err ());
+        node_0.disconnect ().unwrap ();
+        std::fs::remove_dir_all (dir).unwrap ();
+    }
+
+    #[test]
+    fn message_with_a_spoofed_src_is_dropped ()
+    {
+        let dir = std::env::temp_dir ().join (format! ("broker_spoof_test_{}", std::process::id ()));
+        let files = crate::tls::tests::generate (&dir, "ca", &["broker", "node_0", "node_1"]);
+        let address = Broker::start ("127.0.0.1:0", Some (Tls::new (&files[0]).unwrap ())).unwrap ();
+        let connect = |client_id : &str, files : &crate::tls::TlsFiles|
+            MqttBus::connect (client_id, "127.0.0.1", address.port (), BusMessage::new ("presence", vec![]), Some (files))
+                .unwrap ();
+        let local_update = |src : usize|
+        {
+            let message = Message::LocalUpdate (protocol::MessageLocal { src,
+                                                                        request   : crate::state::RequestId::new (0, 0, 0),
+                                                                        iteration : 0,
+                                                                        local_sum : 1.0 });
+            BusMessage::new ("federation/app_0/local_update", protocol::encode (&message, protocol::Encoding::Json))
+        };
+
+        let node_0 = connect ("node_0_orc", &files[1]);
+        let node_1 = connect ("node_1_orc", &files[2]);
+        let mut strm = block_on (node_0.subscribe (&["federation/app_0/local_update".to_string ()])).unwrap ();
+
+        // node_1 claims to be node_0, then publishes as itself.
+        block_on (node_1.publish (local_update (0))).unwrap ();
+        block_on (node_1.publish (local_update (1))).unwrap ();
+        let msg = block_on (strm.next ()).flatten ().unwrap ();
+        node_0.disconnect ().unwrap ();
+        node_1.disconnect ().unwrap ();
+        std::fs::remove_dir_all (dir).unwrap ();
+
+        assert! (matches! (protocol::decode_message (msg.payload ()), Ok (Message::LocalUpdate (message)) if message.src == 1));
+    }
+}
@@ -0,0 +1,624 @@
+/***************************************/
+/*               JOURNAL               */
+/***************************************/
+
+// Write-ahead journal of the mutations of the requests of an
+// application (admission, lifecycle transitions, progress and
+// departure), one entry per line. Each entry is written to disk
+// before the mutation is applied, so that a node restarted after
+// a crash rebuilds its queue from the journal: the requests are
+// served again from the memories saved in their folder (i.e. from
+// their last checkpoint, or from their start). The journal also
+// keeps the sequence of the next request admitted by the node, so
+// that request identifiers are never reused, and confirms the
+// transfers of the migrated requests: a migrated request whose
+// transfer is not confirmed may not have left the node, so it is
+// served again if its folder is still there.
+
+use std::io::{BufRead, Write};
+use crate::configuration_loader::RequestManifest;
+use crate::request_transfer;
+use crate::state::{millis_since_epoch, ApplicationState, ParseStateError, Request, RequestId, RequestState};
+
+/// A mutation of the requests of an application.
+pub enum JournalEntry
+{
+    /// A request entered the node in `state' (Queued, or InTransit
+    /// while its folder is received). Its arrival is in ms since
+    /// the UNIX epoch.
+    Add { state : RequestState, arrival : u128, request : Request },
+
+    /// A hosted request entered `state'.
+    State { request_id : RequestId, state : RequestState },
+
+    /// A request reached `region'.
+    Region { request_id : RequestId, region : usize },
+
+    /// A request left the node with `outcome'.
+    Remove { request_id : RequestId, outcome : RequestState },
+
+    /// The folder of a migrated request reached its destination.
+    Sent { request_id : RequestId },
+
+    /// The next request admitted by the node takes the sequence `next'.
+    Sequence { next : usize },
+}
+
+impl JournalEntry
+{
+    /// The entry recording the admission of `request' in `state'.
+    pub fn add (request : Request, state : RequestState) -> Self
+    {
+        let arrival = millis_since_epoch (std::time::SystemTime::now ())
+            .saturating_sub (request.get_age ().as_millis ());
+        JournalEntry::Add { state, arrival, request }
+    }
+}
+
+impl std::str::FromStr for JournalEntry
+{
+    type Err = ParseStateError;
+
+    /// The expected strings: add#state#arrival#request,
+    /// state#request_id#state, region#request_id#region,
+    /// remove#request_id#outcome, sent#request_id and sequence#next.
+    fn from_str (s: &str) -> Result<Self, Self::Err>
+    {
+        let malformed = || ParseStateError::InvalidField { what  : "JournalEntry",
+                                                           field : "kind",
+                                                           value : s.to_string () };
+        let (kind, fields) = s.trim ().split_once ('#').ok_or_else (malformed)?;
+        match kind
+        {
+            "add" =>
+                {
+                    let fields = fields.splitn (3, '#').collect::<Vec<&str>> ();
+                    if fields.len () != 3
+                    {
+                        return Err (ParseStateError::FieldCount { what : "JournalEntry", expected : 4, found : fields.len () + 1 });
+                    }
+                    Ok (JournalEntry::Add
+                    {
+                        state   : fields[0].parse ()?,
+                        arrival : fields[1].parse ().map_err (|_| ParseStateError::InvalidField { what  : "JournalEntry",
+                                                                                                  field : "arrival",
+                                                                                                  value : fields[1].to_string () })?,
+                        request : fields[2].parse ()?,
+                    })
+                }
+            "state" | "region" | "remove" =>
+                {
+                    let (request_id, value) = fields.split_once ('#').ok_or_else (malformed)?;
+                    let request_id = request_id.parse ()?;
+                    Ok (match kind
+                    {
+                        "state"  => JournalEntry::State { request_id, state : value.parse ()? },
+                        "region" => JournalEntry::Region
+                            {
+                                request_id,
+                                region : value.parse ().map_err (|_| ParseStateError::InvalidField { what  : "JournalEntry",
+                                                                                                     field : "region",
+                                                                                                     value : value.to_string () })?,
+                            },
+                        _        => JournalEntry::Remove { request_id, outcome : value.parse ()? },
+                    })
+                }
+            "sent" => Ok (JournalEntry::Sent { request_id : fields.parse ()? }),
+            "sequence" =>
+                Ok (JournalEntry::Sequence
+                {
+                    next : fields.parse ().map_err (|_| ParseStateError::InvalidField { what  : "JournalEntry",
+                                                                                        field : "next",
+                                                                                        value : fields.to_string () })?,
+                }),
+            _ => Err (malformed ()),
+        }
+    }
+}
+
+impl std::fmt::Display for JournalEntry
+{
+    fn fmt (&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            JournalEntry::Add { state, arrival, request } => write! (f, "add#{}#{}#{}", state, arrival, request),
+            JournalEntry::State { request_id, state }     => write! (f, "state#{}#{}", request_id, state),
+            JournalEntry::Region { request_id, region }   => write! (f, "region#{}#{}", request_id, region),
+            JournalEntry::Remove { request_id, outcome }  => write! (f, "remove#{}#{}", request_id, outcome),
+            JournalEntry::Sent { request_id }             => write! (f, "sent#{}", request_id),
+            JournalEntry::Sequence { next }               => write! (f, "sequence#{}", next),
+        }
+    }
+}
+
+/// The journal file of an application.
+pub struct Journal
+{
+    file : std::fs::File,
+}
+
+impl Journal
+{
+    fn open (path : &str) -> std::io::Result<Self>
+    {
+        let file = std::fs::OpenOptions::new ()
+            .append (true)
+            .create (true)
+            .open (path)?;
+        Ok (Self { file })
+    }
+
+    /// Write `entry' to disk.
+    pub fn append (&self, entry : &JournalEntry) -> std::io::Result<()>
+    {
+        let mut file = &self.file;
+        writeln! (file, "{}", entry)?;
+        file.sync_data ()
+    }
+
+    /// Forget the entries, once no request is hosted, but the
+    /// sequence `next_sequence' of the next admitted request.
+    pub fn clear (&self, next_sequence : usize) -> std::io::Result<()>
+    {
+        self.file.set_len (0)?;
+        self.append (&JournalEntry::Sequence { next : next_sequence })
+    }
+}
+
+/// A request as rebuilt from the journal.
+struct Replayed
+{
+    request        : Request,
+    state          : RequestState,
+
+    /// Region of the memories saved in the folder of the request:
+    /// the one it entered the node with, or of its last checkpoint.
+    saved_region   : usize,
+
+    /// Entered while its folder was received, and never queued.
+    being_received : bool,
+
+    /// Migrated, and its folder reached the destination.
+    sent           : bool,
+}
+
+/// Replay the journal in `path' (an unreadable entry, e.g. the last
+/// one if the node crashed while writing it, is skipped). Return the
+/// requests, and the sequence of the next admitted request.
+fn replay (path : &str) -> std::io::Result<(std::collections::BTreeMap<RequestId, Replayed>, usize)>
+{
+    let mut requests = std::collections::BTreeMap::new ();
+    let mut next_sequence = 0;
+    let file = match std::fs::File::open (path)
+    {
+        Ok (file) => file,
+        Err (error) if error.kind () == std::io::ErrorKind::NotFound => return Ok ((requests, next_sequence)),
+        Err (error) => return Err (error),
+    };
+
+    let now = millis_since_epoch (std::time::SystemTime::now ());
+    for (number, line) in std::io::BufReader::new (file).lines ().enumerate ()
+    {
+        let line = line?;
+        let entry : JournalEntry = match line.parse ()
+        {
+            Ok (entry) => entry,
+            Err (error) =>
+                {
+                    eprintln! ("journal - {}:{} SKIPPED: {}", path, number + 1, error);
+                    continue;
+                }
+        };
+        match entry
+        {
+            JournalEntry::Add { state, arrival, mut request } =>
+                {
+                    request.set_age (std::time::Duration::from_millis (now.saturating_sub (arrival) as u64));
+                    requests.insert (request.get_id (), Replayed
+                    {
+                        request,
+                        state,
+                        saved_region   : request.get_region (),
+                        being_received : state == RequestState::InTransit,
+                        sent           : false,
+                    });
+                }
+            JournalEntry::State { request_id, state } =>
+                if let Some (replayed) = requests.get_mut (&request_id)
+                {
+                    // Entering InTransit, a served request has saved its
+                    // memories (a queued one has not moved).
+                    if state == RequestState::InTransit
+                    {
+                        replayed.saved_region = replayed.request.get_region ();
+                    }
+                    replayed.being_received &= state != RequestState::Queued;
+                    replayed.state = state;
+                }
+            JournalEntry::Region { request_id, region } =>
+                if let Some (replayed) = requests.get_mut (&request_id)
+                {
+                    replayed.request.set_region (region);
+                }
+            JournalEntry::Remove { request_id, outcome } =>
+                if let Some (replayed) = requests.get_mut (&request_id)
+                {
+                    replayed.state = outcome;
+                }
+            JournalEntry::Sent { request_id } =>
+                if let Some (replayed) = requests.get_mut (&request_id)
+                {
+                    replayed.sent = true;
+                }
+            JournalEntry::Sequence { next } => next_sequence = next_sequence.max (next),
+        }
+    }
+    Ok ((requests, next_sequence))
+}
+
+/// Rebuild the requests of `application_state' from the journal in
+/// `path', then journal its mutations there. A request is served
+/// again from the memories in its folder, unless it has left the
+/// node (completed, failed, or migrated with a confirmed transfer),
+/// or its folder was being received or is no longer valid.
+/// Return the requests which are not recovered, so that their
+/// folder, if any, is not admitted again.
+pub fn recover (application_state : &mut ApplicationState,
+                path              : &str,
+                request_directory : &str) -> std::io::Result<std::collections::BTreeSet<RequestId>>
+{
+    let mut dropped = std::collections::BTreeSet::new ();
+    let (requests, next_sequence) = replay (path)?;
+    if next_sequence > 0
+    {
+        application_state.reserve_sequence (next_sequence - 1);
+    }
+    for (request_id, replayed) in requests
+    {
+        if request_id.get_origin () == application_state.node_index
+        {
+            application_state.reserve_sequence (request_id.get_sequence ());
+        }
+        let mut request = replayed.request;
+        let folder = format! ("{}/{}", request_directory, request_id.folder_name ());
+        let has_folder = std::path::Path::new (&folder).is_dir ();
+        match replayed.state
+        {
+            // The node may have crashed while removing the folder.
+            RequestState::Completed | RequestState::Failed =>
+                {
+                    if has_folder
+                    {
+                        std::fs::remove_dir_all (&folder)?;
+                    }
+                    dropped.insert (request_id);
+                    continue;
+                }
+
+            // The folder is removed once its transfer is confirmed,
+            // it is still here if the node crashed in between.
+            RequestState::Migrated if replayed.sent =>
+                {
+                    if has_folder
+                    {
+                        std::fs::remove_dir_all (&folder)?;
+                    }
+                    dropped.insert (request_id);
+                    continue;
+                }
+            RequestState::Migrated if !has_folder =>
+                {
+                    eprintln! ("journal - request {} DROPPED: its folder is missing", request_id);
+                    dropped.insert (request_id);
+                    continue;
+                }
+
+            // The node crashed while sending the folder: the request has
+            // not left, its stay recorded in its lineage is forgotten.
+            RequestState::Migrated =>
+                {
+                    let lineage = std::fs::read_to_string (format! ("{}/{}", folder, request_transfer::LINEAGE_FILE_NAME))
+                        .unwrap_or_default ();
+                    let stay = format! ("[{};", application_state.node_index);
+                    if lineage.lines ().last ().is_some_and (|hop| hop.starts_with (&stay) && hop.ends_with (";migrated]"))
+                    {
+                        request_transfer::remove_last_lineage_hop (&folder)?;
+                    }
+                    request.set_region (replayed.saved_region);
+                }
+            _ if replayed.being_received =>
+                {
+                    eprintln! ("journal - request {} DROPPED: its transfer was interrupted", request_id);
+                    if has_folder
+                    {
+                        std::fs::remove_dir_all (&folder)?;
+                    }
+                    dropped.insert (request_id);
+                    continue;
+                }
+            RequestState::Queued => (),
+            _ => request.set_region (replayed.saved_region),
+        }
+
+        // An invalid folder is kept aside, as {folder}.dropped.
+        if let Err (error) = RequestManifest::load (&folder, request_id)
+        {
+            eprintln! ("journal - request {} DROPPED: {}", request_id, error);
+            if has_folder
+            {
+                std::fs::rename (&folder, format! ("{}.dropped", folder))?;
+            }
+            dropped.insert (request_id);
+            continue;
+        }
+        application_state.add_request (request);
+
+        #[cfg(feature = "print_log")]
+        println! ("journal - request {} RECOVERED ({}) from region {}", request_id, replayed.state, request.get_region ());
+    }
+
+    // Compact the journal: only the recovered requests are left.
+    let compacted = format! ("{}.new", path);
+    {
+        let journal = Journal::open (&compacted)?;
+        journal.clear (application_state.get_next_sequence ())?;
+        for request in &application_state.requests
+        {
+            journal.append (&JournalEntry::add (*request, RequestState::Queued))?;
+        }
+    }
+    std::fs::rename (&compacted, path)?;
+
+    application_state.journal = Some (std::sync::Arc::new (Journal::open (path)?));
+    Ok (dropped)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::state::Attributes;
+
+    /// An empty request directory, private to the test `name'.
+    fn request_directory (name : &str) -> String
+    {
+        let directory = std::env::temp_dir ().join (format! ("journal_{}_{}", name, std::process::id ()));
+        let _ = std::fs::remove_dir_all (&directory);
+        std::fs::create_dir_all (&directory).unwrap ();
+        directory.to_string_lossy ().to_string ()
+    }
+
+    fn request (sequence : usize) -> Request
+    {
+        Request::new_from (RequestId::new (0, 0, sequence), 200, 250, 22, 128, Attributes::new (), 1.5, 0)
+    }
+
+    /// Write a valid folder for the request `sequence'.
+    fn write_folder (request_directory : &str, sequence : usize) -> String
+    {
+        let folder = format! ("{}/{}", request_directory, RequestId::new (0, 0, sequence).folder_name ());
+        std::fs::create_dir_all (&folder).unwrap ();
+        std::fs::write (format! ("{}/manifest.toml", folder),
+                        "version = 1\n\n[request]\nindex = 0\napplication_index = 0\n\
+                         execution_time = 200\ndesired_completion_time = 250\nmigratable_up_to = 22\n\
+                         required_memory = 128\ndesired_coord = [1.0, 2.0]\nthreshold = 1.5\n\
+                         module = \"module.wasm\"\ndata_files = []\n").unwrap ();
+        std::fs::write (format! ("{}/module.wasm", folder), b"\0asm").unwrap ();
+        folder
+    }
+
+    fn write_journal (path : &str, entries : &[JournalEntry])
+    {
+        std::fs::write (path, entries.iter ().map (|entry| format! ("{}\n", entry)).collect::<String> ()).unwrap ();
+    }
+
+    fn new_state () -> ApplicationState
+    {
+        ApplicationState::new (0, Attributes::new (), 100, 50, 1.0, 1024)
+    }
+
+    /// The recovered requests, with their region.
+    fn recovered (state : &ApplicationState) -> Vec<(RequestId, usize)>
+    {
+        state.requests.iter ().map (|request| (request.get_id (), request.get_region ())).collect ()
+    }
+
+    #[test]
+    fn entries_round_trip ()
+    {
+        let request_id = RequestId::new (1, 0, 2);
+        let entries = [JournalEntry::add (request (2), RequestState::InTransit),
+                       JournalEntry::State { request_id, state : RequestState::Checkpointing },
+                       JournalEntry::Region { request_id, region : 3 },
+                       JournalEntry::Remove { request_id, outcome : RequestState::Migrated },
+                       JournalEntry::Sent { request_id },
+                       JournalEntry::Sequence { next : 4 }];
+        for entry in entries
+        {
+            let line = entry.to_string ();
+            assert_eq! (line.parse::<JournalEntry> ().unwrap ().to_string (), line);
+        }
+    }
+
+    #[test]
+    fn hosted_requests_are_served_again_from_their_saved_region ()
+    {
+        let directory = request_directory ("hosted");
+        let path = format! ("{}/0_journal.log", directory);
+        let id = |sequence| RequestId::new (0, 0, sequence);
+        for sequence in 0..3
+        {
+            write_folder (&directory, sequence);
+        }
+        write_journal (&path, &[
+            // Queued.
+            JournalEntry::add (request (0), RequestState::Queued),
+
+            // Running: its memories are not saved yet.
+            JournalEntry::add (request (1), RequestState::Queued),
+            JournalEntry::State { request_id : id (1), state : RequestState::Running },
+            JournalEntry::Region { request_id : id (1), region : 2 },
+
+            // Checkpointed, then in transit: its memories are saved.
+            JournalEntry::add (request (2), RequestState::Queued),
+            JournalEntry::State { request_id : id (2), state : RequestState::Running },
+            JournalEntry::Region { request_id : id (2), region : 2 },
+            JournalEntry::State { request_id : id (2), state : RequestState::Checkpointing },
+            JournalEntry::State { request_id : id (2), state : RequestState::InTransit }]);
+
+        let mut state = new_state ();
+        let dropped = recover (&mut state, &path, &directory).unwrap ();
+        std::fs::remove_dir_all (&directory).unwrap ();
+
+        assert! (dropped.is_empty ());
+        assert_eq! (recovered (&state), vec! [(id (0), 0), (id (1), 0), (id (2), 2)]);
+        assert! ((0..3).all (|sequence| state.get_request_state (id (sequence)) == Some (RequestState::Queued)));
+    }
+
+    #[test]
+    fn requests_that_left_the_node_are_dropped ()
+    {
+        let directory = request_directory ("left");
+        let path = format! ("{}/0_journal.log", directory);
+        let id = |sequence| RequestId::new (0, 0, sequence);
+        let completed   = write_folder (&directory, 0);
+        let transferred = write_folder (&directory, 2);
+        let received    = write_folder (&directory, 3);
+        let invalid     = format! ("{}/{}", directory, id (5).folder_name ());
+        std::fs::create_dir_all (&invalid).unwrap ();
+        write_journal (&path, &[
+            // Completed (the node crashed before removing the folder).
+            JournalEntry::add (request (0), RequestState::Queued),
+            JournalEntry::Remove { request_id : id (0), outcome : RequestState::Completed },
+
+            // Failed, its folder removed.
+            JournalEntry::add (request (1), RequestState::Queued),
+            JournalEntry::Remove { request_id : id (1), outcome : RequestState::Failed },
+
+            // Migrated, and its transfer confirmed.
+            JournalEntry::add (request (2), RequestState::Queued),
+            JournalEntry::State { request_id : id (2), state : RequestState::InTransit },
+            JournalEntry::Remove { request_id : id (2), outcome : RequestState::Migrated },
+            JournalEntry::Sent { request_id : id (2) },
+
+            // Being received.
+            JournalEntry::add (request (3), RequestState::InTransit),
+
+            // Migrated, without its folder.
+            JournalEntry::add (request (4), RequestState::Queued),
+            JournalEntry::State { request_id : id (4), state : RequestState::InTransit },
+            JournalEntry::Remove { request_id : id (4), outcome : RequestState::Migrated },
+
+            // Queued, without a valid manifest.
+            JournalEntry::add (request (5), RequestState::Queued)]);
+
+        let mut state = new_state ();
+        let dropped = recover (&mut state, &path, &directory).unwrap ();
+        let removed = [&completed, &transferred, &received].iter ().all (|folder| !std::path::Path::new (folder).exists ());
+        let kept_aside = std::path::Path::new (&format! ("{}.dropped", invalid)).is_dir ();
+        std::fs::remove_dir_all (&directory).unwrap ();
+
+        assert! (state.requests.is_empty ());
+        assert_eq! (dropped.into_iter ().collect::<Vec<RequestId>> (), (0..6).map (id).collect::<Vec<RequestId>> ());
+        assert! (removed);
+        assert! (kept_aside);
+    }
+
+    #[test]
+    fn unconfirmed_migration_is_served_again ()
+    {
+        let directory = request_directory ("unconfirmed");
+        let path = format! ("{}/0_journal.log", directory);
+        let request_id = RequestId::new (0, 0, 0);
+        let folder = write_folder (&directory, 0);
+        let lineage = format! ("{}/{}", folder, request_transfer::LINEAGE_FILE_NAME);
+        std::fs::write (&lineage, "[1;0;1;10;20;migrated]\n[0;1;3;20;30;migrated]\n").unwrap ();
+        write_journal (&path, &[
+            JournalEntry::add (request (0), RequestState::Queued),
+            JournalEntry::State { request_id, state : RequestState::Running },
+            JournalEntry::Region { request_id, region : 3 },
+            JournalEntry::State { request_id, state : RequestState::InTransit },
+            JournalEntry::Remove { request_id, outcome : RequestState::Migrated }]);
+
+        let mut state = new_state ();
+        let dropped = recover (&mut state, &path, &directory).unwrap ();
+        let lineage = std::fs::read_to_string (&lineage).unwrap ();
+        std::fs::remove_dir_all (&directory).unwrap ();
+
+        assert! (dropped.is_empty ());
+        assert_eq! (recovered (&state), vec! [(request_id, 3)]);
+        assert_eq! (lineage, "[1;0;1;10;20;migrated]\n");
+    }
+
+    #[test]
+    fn torn_last_line_is_skipped ()
+    {
+        let directory = request_directory ("torn");
+        let path = format! ("{}/0_journal.log", directory);
+        let request_id = RequestId::new (0, 0, 0);
+        write_folder (&directory, 0);
+        write_journal (&path, &[JournalEntry::add (request (0), RequestState::Queued),
+                                JournalEntry::State { request_id, state : RequestState::Running }]);
+        let mut journal = std::fs::OpenOptions::new ().append (true).open (&path).unwrap ();
+        write! (journal, "remove#{}#compl", request_id).unwrap ();
+
+        let mut state = new_state ();
+        let dropped = recover (&mut state, &path, &directory).unwrap ();
+        std::fs::remove_dir_all (&directory).unwrap ();
+
+        assert! (dropped.is_empty ());
+        assert_eq! (recovered (&state), vec! [(request_id, 0)]);
+    }
+
+    #[test]
+    fn compaction_keeps_the_recovered_requests_and_the_sequence ()
+    {
+        let directory = request_directory ("compaction");
+        let path = format! ("{}/0_journal.log", directory);
+        let id = |sequence| RequestId::new (0, 0, sequence);
+        write_folder (&directory, 1);
+        write_journal (&path, &[
+            JournalEntry::Sequence { next : 1 },
+            JournalEntry::add (request (0), RequestState::Queued),
+            JournalEntry::Remove { request_id : id (0), outcome : RequestState::Completed },
+            JournalEntry::Sequence { next : 2 },
+            JournalEntry::add (request (1), RequestState::Queued)]);
+
+        let mut state = new_state ();
+        recover (&mut state, &path, &directory).unwrap ();
+        let compacted = std::fs::read_to_string (&path).unwrap ();
+        let mut again = new_state ();
+        recover (&mut again, &path, &directory).unwrap ();
+        std::fs::remove_dir_all (&directory).unwrap ();
+
+        let kinds = compacted.lines ().map (|line| line.split ('#').next ().unwrap ()).collect::<Vec<&str>> ();
+        assert_eq! (kinds, vec! ["sequence", "add"]);
+        assert_eq! (recovered (&state), vec! [(id (1), 0)]);
+        assert_eq! (recovered (&again), recovered (&state));
+        assert_eq! (again.allocate_sequence (), 2);
+    }
+
+    #[test]
+    fn sequence_survives_the_clearing_of_the_journal ()
+    {
+        let directory = request_directory ("sequence");
+        let path = format! ("{}/0_journal.log", directory);
+
+        let mut state = new_state ();
+        recover (&mut state, &path, &directory).unwrap ();
+        let sequence = state.allocate_sequence ();
+        let mut admitted = request (0);
+        admitted.set_id (RequestId::new (0, 0, sequence));
+        state.add_request (admitted);
+        state.remove_request (admitted.get_id (), RequestState::Failed).unwrap ();
+        let cleared = std::fs::read_to_string (&path).unwrap ();
+
+        let mut restarted = new_state ();
+        recover (&mut restarted, &path, &directory).unwrap ();
+        std::fs::remove_dir_all (&directory).unwrap ();
+
+        assert_eq! (cleared, "sequence#1\n");
+        assert_eq! (restarted.allocate_sequence (), 1);
+    }
+}
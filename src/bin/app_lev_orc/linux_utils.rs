@@ -41,4 +41,60 @@ pub fn get_completion_time (start_time: libc::timespec) -> u64
             time_to_completion = (diff_sec * 1_000_000) as u64 + (diff_nsec / 1_000) as u64;
             time_to_completion
         }
-}
\ No newline at end of file
+}
+/// Directory of the memory cgroup of this process, if any:
+/// the unified hierarchy (v2) first, then the memory
+/// controller of the v1 hierarchy.
+pub fn memory_cgroup () -> Option<std::path::PathBuf>
+{
+    let cgroups = std::fs::read_to_string ("/proc/self/cgroup").ok ()?;
+    for line in cgroups.lines ()
+    {
+        // Each line is: hierarchy-ID:controller-list:cgroup-path
+        let mut fields = line.splitn (3, ':');
+        let (_, controllers, path) = (fields.next ()?, fields.next ()?, fields.next ()?);
+        let root = if controllers.is_empty ()
+        {
+            "/sys/fs/cgroup"
+        }
+        else if controllers.split (',').any (|controller| controller == "memory")
+        {
+            "/sys/fs/cgroup/memory"
+        }
+        else
+        {
+            continue;
+        };
+
+        // Within a container, the root of the hierarchy is often
+        // the cgroup of the process itself.
+        for directory in [format! ("{}{}", root, path), root.to_string ()]
+        {
+            let directory = std::path::PathBuf::from (directory);
+            if directory.join ("memory.max").is_file () || directory.join ("memory.limit_in_bytes").is_file ()
+            {
+                return Some (directory);
+            }
+        }
+    }
+    None
+}
+
+/// Memory still available in the cgroup `directory', in kB:
+/// its limit minus its current usage. None if the cgroup has
+/// no limit, or if it cannot be read.
+pub fn cgroup_memory_headroom (directory : &std::path::Path) -> Option<u32>
+{
+    let read = |file : &str| std::fs::read_to_string (directory.join (file)).ok ()
+        .and_then (|value| value.trim ().parse::<u64> ().ok ());
+
+    // A missing or "max" limit means unlimited; the v1 hierarchy
+    // reports unlimited as a huge value.
+    let (limit, usage) = match read ("memory.max")
+    {
+        Some (limit) => (limit, read ("memory.current")?),
+        None         => (read ("memory.limit_in_bytes").filter (|limit| *limit < (1 << 62))?,
+                         read ("memory.usage_in_bytes")?),
+    };
+    Some (u32::try_from (limit.saturating_sub (usage) / 1024).unwrap_or (u32::MAX))
+}
@@ -13,195 +13,324 @@ mod requests_coordination_loop_c;
 mod mqtt_utils;
 mod linux_utils;
 mod log_writer;
+mod coordination_strategy;
+mod request_transfer;
+mod requests_admission_loop;
+mod shutdown;
+mod distance_metric;
+mod journal;
+mod protocol;
+mod negotiation;
+mod membership;
+mod message_bus;
+mod embedded_broker;
+mod tls;
 
-/// Example of invocation: ./app_lev_orc config_file.conf
+use coordination_strategy::{CoordinationMode, CoordinationStrategy};
+
+/// Example of invocation: ./app_lev_orc node_0
 fn main ()
 {
 
     #[cfg(feature = "timing_log")]
     println!("timing_log : ACTIVE");
-    #[cfg(feature = "centralized")]
-    println!("centralized : ACTIVE");
-    #[cfg(feature = "distributed")]
-    println!("distributed : ACTIVE");
     #[cfg(feature = "print_log")]
     println!("print_log : ACTIVE");
 
     // Parse input arguments.
     let args: Vec<String> = std::env::args ().collect ();
+    let config_file = args.get (1).expect ("Missing configuration file. ");
+
+    let config = match configuration_loader::NodeConfig::load (config_file)
+    {
+        Ok (config) => config,
+        Err (error) =>
+            {
+                eprintln! ("Invalid configuration: {}", error);
+                std::process::exit (1);
+            }
+    };
 
-    let node_index       : usize;
-    let application_index: usize;
-    let node_address     : String;
-    let node_state       : state::NodeState;
-    let affinity         : usize;
-    let penalty          : f32;
-    let node_number      : usize;
-    let broker_address   : String;
-    let is_controller    : bool;
-
-    let lines = configuration_loader::load_config (args[1].clone ());
-
-    node_index        = lines.get (0).expect ("Failed to read node_index. ")
-        .parse ().expect ("Failed to parse node_index. ");
-    application_index = lines.get (1).expect ("Failed to read application_index. ")
-        .parse ().expect ("Failed to parse application_index. ");
-    node_address      = lines.get (2).expect ("Failed to read node_address.").clone ();
-    node_state        = lines.get (3).expect ("Failed to read node_state. ")
-        .parse ().expect ("Failed to parse node_state. ");
-    affinity          = lines.get (4).expect ("Failed to read affinity. ")
-        .parse ().expect ("Failed to parse affinity. ");
-    penalty          = lines.get (5).expect ("Failed to read affinity. ")
-        .parse ().expect ("Failed to parse affinity. ");
-    node_number       = lines.get (6).expect ("Failed to read node_number. ")
-        .parse ().expect ("Failed to parse node_number. ");
-    broker_address    = lines.get (7).expect ("Failed to read broker_address").clone ();
+    let node_index        : usize            = config.node_index;
+    let node_state        : state::NodeState = config.node_state;
+    let affinity          : usize            = config.affinity;
+
+    println!("coordination : {}", config.coordination);
 
     #[cfg(feature = "print_log")]
     {
-        println!("node_index = {} - application_index = {}", node_index, application_index);
-        println!("node_address = {} - node_state = {}", node_address, node_state);
+        println!("node_index = {} - applications = {}", node_index, config.applications.len ());
+        println!("node_address = {} - node_state = {}", config.node_address, node_state);
     }
 
-    match lines.get (8)
+    // Node data.
+    let node_attributes : state::Attributes = node_state.get_attributes ();
+    let node_speedup_factor : f32  = node_state.get_speedup_factor ();
+
+    // Handle SIGTERM and SIGINT, and remove the archives of the
+    // transfers interrupted at the last shutdown.
+    shutdown::install_signal_handlers ();
+    request_transfer::remove_partial_archives ("requests");
+
+    // TLS with client certificates, for the broker and the data plane.
+    let tls = match config.tls.as_ref ().map (tls::Tls::new).transpose ()
     {
-        None =>
+        Ok (tls) => tls,
+        Err (error) =>
             {
-                is_controller = false;
+                eprintln! ("Unable to load the TLS files: {}", error);
+                std::process::exit (1);
             }
-        Some(flag) =>
+    };
+
+    // The node may run the broker of the federation itself.
+    if config.embedded_broker
+    {
+        let broker_address = format! ("0.0.0.0:{}", config.broker_port);
+        if let Err (error) = embedded_broker::Broker::start (&broker_address, tls.clone ())
+        {
+            eprintln! ("Unable to start the embedded broker on {}: {}", broker_address, error);
+            std::process::exit (1);
+        }
+    }
+
+    // The MQTT connection and the data plane listener are
+    // shared by all the applications hosted by the node.
+    let link = match mqtt_utils::MqttLink::connect (node_index, &config.broker_address, config.broker_port, config.encoding, config.tls.as_ref ())
+    {
+        Ok (link) => link,
+        Err (error) =>
             {
-                is_controller = flag.parse ().expect ("Failed to parse is_controller. ");
+                eprintln! ("Unable to connect to the broker: {}", error);
+                std::process::exit (1);
             }
-    }
+    };
+    let ip_and_port = format! ("{}:{}", config.node_address, config.data_port);
 
-    // Node data.
-    let node_coords : state::Coord = node_state.get_coord ();
-    let node_speedup_factor : f32  = node_state.get_speedup_factor ();
+    // The live members of the federation, shared by the applications.
+    let heartbeat_period = std::time::Duration::from_millis (config.heartbeat_period as u64);
+    let membership = std::sync::Arc::new (std::sync::Mutex::new (
+        membership::Membership::new (node_index, heartbeat_period * config.missed_heartbeats)));
+
+    // The data plane, serving the transfers of every application.
+    let data_server = match request_transfer::DataServer::start (&ip_and_port, tls)
+    {
+        Ok (data_server) => data_server,
+        Err (error) =>
+            {
+                eprintln! ("Unable to bind to {}: {}", ip_and_port, error);
+                std::process::exit (1);
+            }
+    };
 
-    // Initialize the application state. 
-    let application_state: std::sync::Arc<std::sync::Mutex<state::ApplicationState>> =
-        std::sync::Arc::new (
-            std::sync::Mutex::new (
-                state::ApplicationState::new (
-                    node_coords, 100, 20, node_speedup_factor, 1_000_000)));
-    configuration_loader::load_requests (application_state.clone ());
-
-    // Initialize the sporadic server barrier.
-    // The first element refers to the number of requests
-    // waiting to be served.
-    let number_of_requests = application_state.lock ().unwrap ().number_of_requests;
-    let barrier : std::sync::Arc<(std::sync::Mutex<u8>, std::sync::Condvar)> =
-        std::sync::Arc::new (
-            (std::sync::Mutex::new (number_of_requests as u8), std::sync::Condvar::new ()));
-
-    // Initialize a barrier for completing the checkpoint.
-    let checkpoint_is_ready = application_state.lock ().unwrap ().checkpoint_is_ready;
-    let checkpoint_barrier : std::sync::Arc<(std::sync::Mutex<bool>, std::sync::Condvar)> =
-        std::sync::Arc::new (
-            (std::sync::Mutex::new (checkpoint_is_ready), std::sync::Condvar::new ()));
-
-    // First activation (10ms in the future).
+    // First activation (by default 10ms in the future).
     let mut first_activation : libc::timespec = unsafe { std::mem::zeroed () };
     unsafe
         {
             libc::clock_gettime (libc::CLOCK_MONOTONIC, &mut first_activation)
         };
-    first_activation.tv_nsec += 10_000_000;
+    first_activation.tv_nsec += config.first_activation_delay as i64 * 1_000;
     if first_activation.tv_nsec >= 1_000_000_000
     {
         first_activation.tv_nsec -= 1_000_000_000;
         first_activation.tv_sec  += 1;
     }
 
-    // Initialize the taskset. 
-    let mut state_monitoring_loop      =
-        state_monitoring_loop::ControlSystem::new (application_index,
-                                                   node_index,
-                                                   50,
-                                                   affinity,
-                                                   broker_address.clone ());
-    let mut requests_monitoring_loop   =
-        requests_monitoring_loop::ControlSystem::new (node_index,
-                                                      application_index,
-                                                      1_000,
-                                                      first_activation,
-                                                      50,
-                                                      affinity,
-                                                      broker_address.clone ());
-    #[cfg(feature = "distributed")]
-    #[allow(unused_variables, unused_mut)]
-    let mut requests_coordination_loop =
-        requests_coordination_loop_d::ControlSystem::new (node_number,
-                                                          application_index,
-                                                          node_index,
-                                                          45,
-                                                          affinity,
-                                                          penalty,
-                                                          node_address.to_string (),
-                                                          broker_address.clone ());
-
-    #[cfg(feature = "centralized")]
-    let mut requests_coordination_loop =
-        requests_coordination_loop_c::ControlSystem::new (node_number,
-                                                          is_controller,
-                                                          application_index,
-                                                          node_index,
-                                                          45,
-                                                          affinity,
-                                                          penalty,
-                                                          node_address.to_string (),
-                                                          broker_address.clone ());
-
-    let mut sporadic_server                         =
-        sporadic_server::ControlSystem::new (application_index,
-                                             20,
-                                             100,
-                                             30,
-                                             affinity,
-                                             "requests".to_string ());
-
-    // Start each task. 
-    let mut handles = vec![];
-    let sml_app_state = std::sync::Arc::clone (&application_state);
-    let sml_handle = std::thread::spawn(move ||
+    let mut application_states = vec![];
+    let mut drained_states     = vec![];
+    for application in &config.applications
+    {
+        let application_index : usize = application.application_index;
+
+        // Initialize the application state. 
+        let application_state: std::sync::Arc<std::sync::Mutex<state::ApplicationState>> =
+            std::sync::Arc::new (
+                std::sync::Mutex::new (
+                    state::ApplicationState::new (
+                        node_index,
+                        node_attributes,
+                        application.sporadic_server_period,
+                        application.sporadic_server_budget,
+                        node_speedup_factor,
+                        application.assigned_memory)));
+        application_state.lock ().unwrap ().memory_cgroup = config.memory_cgroup.clone ();
+
+        // Recover the requests hosted before a crash, if any.
+        let journal_path = format! ("requests/{}_journal.log", application_index);
+        let dropped = match journal::recover (&mut application_state.lock ().unwrap (), &journal_path, "requests")
         {
-            state_monitoring_loop.start (sml_app_state);
+            Ok (dropped) => dropped,
+            Err (error) =>
+                {
+                    eprintln! ("Unable to recover the journal {}: {}", journal_path, error);
+                    std::process::exit (1);
+                }
+        };
+        if let Err (error) = configuration_loader::load_requests (application_state.clone (),
+                                                                  application_index,
+                                                                  node_index,
+                                                                  "requests",
+                                                                  &dropped)
+        {
+            eprintln! ("Invalid request: {}", error);
+            std::process::exit (1);
         }
-    );
-    handles.push (sml_handle);
+        application_states.push (application_state.clone ());
+        drained_states.push ((application_index, application_state.clone ()));
+
+        // Initialize the sporadic server barrier.
+        // The first element refers to the number of requests
+        // waiting to be served.
+        let number_of_requests = application_state.lock ().unwrap ().number_of_requests;
+        let barrier : std::sync::Arc<(std::sync::Mutex<u8>, std::sync::Condvar)> =
+            std::sync::Arc::new (
+                (std::sync::Mutex::new (number_of_requests as u8), std::sync::Condvar::new ()));
+
+        // The sporadic server tells the coordination loop when the job
+        // of a request ends, e.g. a request checkpointed to migrate.
+        let (job_ends, ended_jobs) = futures::channel::mpsc::unbounded ();
 
-    let rml_app_state = std::sync::Arc::clone (&application_state);
-    let rml_handle = std::thread::spawn(move ||
+        // Initialize the taskset of the application. 
+        let mut requests_monitoring_loop   =
+            requests_monitoring_loop::ControlSystem::new (node_index,
+                                                          application_index,
+                                                          config.requests_monitoring_period,
+                                                          first_activation,
+                                                          config.requests_monitoring_priority,
+                                                          affinity,
+                                                          link.clone (),
+                                                          config.distance_metric.clone (),
+                                                          membership.clone ());
+        let mut requests_coordination_loop : Box<dyn CoordinationStrategy> = match config.coordination
         {
-            requests_monitoring_loop.start(rml_app_state);
-        }
-    );
-    handles.push (rml_handle);
+            CoordinationMode::Centralized => Box::new (
+                requests_coordination_loop_c::ControlSystem::new (membership.clone (),
+                                                                  application_index,
+                                                                  node_index,
+                                                                  config.requests_coordination_priority,
+                                                                  affinity,
+                                                                  config.penalty,
+                                                                  config.limits,
+                                                                  config.node_address.to_string (),
+                                                                  config.data_port,
+                                                                  link.clone (),
+                                                                  data_server.clone (),
+                                                                  config.distance_metric.clone ())),
+            CoordinationMode::Distributed => Box::new (
+                requests_coordination_loop_d::ControlSystem::new (membership.clone (),
+                                                                  application_index,
+                                                                  node_index,
+                                                                  config.requests_coordination_priority,
+                                                                  affinity,
+                                                                  config.penalty,
+                                                                  config.limits,
+                                                                  config.node_address.to_string (),
+                                                                  config.data_port,
+                                                                  link.clone (),
+                                                                  data_server.clone (),
+                                                                  config.distance_metric.clone ())),
+        };
+
+        let mut sporadic_server                         =
+            sporadic_server::ControlSystem::new (application.sporadic_server_budget as u64,
+                                                 application.sporadic_server_period as u64,
+                                                 config.sporadic_server_priority as usize,
+                                                 affinity,
+                                                 "requests".to_string ());
+
+        let mut requests_admission_loop =
+            requests_admission_loop::ControlSystem::new (application_index,
+                                                         node_index,
+                                                         config.requests_admission_period,
+                                                         config.requests_admission_priority,
+                                                         affinity,
+                                                         "requests".to_string ());
+
+        // Start each task of the application. 
+        let rml_app_state = std::sync::Arc::clone (&application_state);
+        std::thread::spawn (move ||
+            {
+                requests_monitoring_loop.start(rml_app_state);
+            }
+        );
 
-    let rcl_app_state = std::sync::Arc::clone (&application_state);
-    let rcl_barrier = std::sync::Arc::clone (&barrier);
-    let rcl_cp_barrier = std::sync::Arc::clone (&checkpoint_barrier);
-    let rcl_handle = std::thread::spawn(move ||
+        let rcl_app_state = std::sync::Arc::clone (&application_state);
+        let rcl_barrier = std::sync::Arc::clone (&barrier);
+        std::thread::spawn (move ||
+            {
+                requests_coordination_loop.start (rcl_app_state, rcl_barrier, ended_jobs);
+            }
+        );
+
+        let ss_app_state = std::sync::Arc::clone (&application_state);
+        let ss_barrier = std::sync::Arc::clone (&barrier);
+        std::thread::spawn (move ||
+            {
+                sporadic_server.start (ss_app_state, ss_barrier, job_ends);
+            }
+        );
+
+        let ral_app_state = std::sync::Arc::clone (&application_state);
+        let ral_barrier = std::sync::Arc::clone (&barrier);
+        std::thread::spawn (move ||
+            {
+                requests_admission_loop.start (ral_app_state, ral_barrier);
+            }
+        );
+    }
+
+    // Heartbeat, and track the presence of the other nodes.
+    let mut membership_loop =
+        membership::ControlSystem::new (node_index,
+                                        config.applications.iter ()
+                                            .map (|application| application.application_index).collect (),
+                                        heartbeat_period,
+                                        link.clone (),
+                                        membership.clone ());
+    std::thread::spawn (move ||
         {
-            requests_coordination_loop.start (rcl_app_state, rcl_barrier, rcl_cp_barrier);
+            membership_loop.start ();
         }
     );
-    handles.push (rcl_handle);
 
-    let ss_app_state = std::sync::Arc::clone (&application_state);
-    let ss_barrier = std::sync::Arc::clone (&barrier);
-    let ss_cp_barrier = std::sync::Arc::clone (&checkpoint_barrier);
-    let ss_handle = std::thread::spawn (move ||
+    // The state of the node is monitored once for all the applications.
+    let mut state_monitoring_loop      =
+        state_monitoring_loop::ControlSystem::new (node_index,
+                                                   config.state_monitoring_priority,
+                                                   affinity,
+                                                   link.clone ());
+    std::thread::spawn (move ||
         {
-            sporadic_server.start (ss_app_state, ss_barrier, ss_cp_barrier);
+            state_monitoring_loop.start (application_states);
         }
     );
-    handles.push (ss_handle);
 
-    for handle in handles
+    // Run until SIGTERM or SIGINT, then hand off the requests.
+    shutdown::wait_for_signal ();
+    let requests_left = shutdown::drain (node_index,
+                                         &drained_states,
+                                         &link,
+                                         &membership,
+                                         std::time::Duration::from_millis (config.drain_timeout as u64));
+    if requests_left > 0
+    {
+        eprintln! ("shutdown - drain timeout expired, {} requests dropped", requests_left);
+    }
+    // The requests handed off might still be on their way.
+    if !data_server.wait_sent (std::time::Duration::from_millis (config.drain_timeout as u64))
+    {
+        eprintln! ("shutdown - drain timeout expired, transfers interrupted");
+    }
+    if mqtt_utils::rejected_payloads () > 0
+    {
+        eprintln! ("shutdown - {} malformed payloads rejected", mqtt_utils::rejected_payloads ());
+    }
+    request_transfer::remove_partial_archives ("requests");
+    membership::leave (&link, node_index);
+    if let Err (error) = link.disconnect ()
     {
-        handle.join ().unwrap ();
+        eprintln! ("shutdown - unable to disconnect from the broker: {}", error);
     }
+    std::process::exit (0);
     // End of main. 
 }
\ No newline at end of file
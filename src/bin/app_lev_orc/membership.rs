@@ -0,0 +1,239 @@
+/***************************************/
+/*              MEMBERSHIP             */
+/***************************************/
+
+// The live membership of the federation. Each node heartbeats on the
+// presence topic with the applications it hosts: it is a member from
+// its first heartbeat until it leaves (on shutdown, or through its
+// will message when its connection is lost) or misses its heartbeats.
+// Nodes keep their index (node.index) as a stable identifier, while
+// each negotiation sizes its ADMM from the members at its start.
+// In centralized coordination, the controller of an application is
+// its live node with the lowest index: when it is gone, the next one
+// takes over.
+
+use futures::{executor::block_on, stream::StreamExt};
+use crate::message_bus::BusError;
+use crate::mqtt_utils::{self, MqttLink, PRESENCE_TOPIC};
+use crate::protocol::{self, MessagePresence, Payload};
+
+/// A node heard on the presence topic.
+struct Member
+{
+    /// The applications hosted by the node.
+    applications : Vec<usize>,
+
+    /// Last heartbeat of the node.
+    last_seen    : std::time::Instant,
+}
+
+/// The nodes of the federation known to be alive.
+pub struct Membership
+{
+    /// The index of this node, always a member.
+    node_index : usize,
+
+    /// The other nodes, by index.
+    members    : std::collections::BTreeMap<usize, Member>,
+
+    /// Silence after which a node is considered gone.
+    timeout    : std::time::Duration,
+
+    /// Start of the membership, to let the other nodes be heard.
+    since      : std::time::Instant,
+}
+
+impl Membership
+{
+    pub fn new (node_index : usize, timeout : std::time::Duration) -> Self
+    {
+        Self
+        {
+            node_index,
+            members : std::collections::BTreeMap::new (),
+            timeout,
+            since   : std::time::Instant::now (),
+        }
+    }
+
+    /// Record a heartbeat of `node'.
+    pub fn heard (&mut self, node : usize, applications : Vec<usize>)
+    {
+        if node == self.node_index
+        {
+            return;
+        }
+        let member = Member { applications, last_seen : std::time::Instant::now () };
+        if self.members.insert (node, member).is_none ()
+        {
+            #[cfg(feature = "print_log")]
+            println! ("membership - node {} JOINED", node);
+        }
+    }
+
+    /// Record the leave of `node'.
+    pub fn leave (&mut self, node : usize)
+    {
+        if self.members.remove (&node).is_some ()
+        {
+            eprintln! ("membership - node {} LEFT", node);
+        }
+    }
+
+    /// Forget the nodes which missed their heartbeats.
+    pub fn expire (&mut self)
+    {
+        let timeout = self.timeout;
+        self.members.retain (|node, member|
+            {
+                let alive = member.last_seen.elapsed () < timeout;
+                if !alive
+                {
+                    eprintln! ("membership - node {} GONE (missed heartbeats)", node);
+                }
+                alive
+            });
+    }
+
+    /// The live nodes hosting `application_index', this one
+    /// included, by increasing index.
+    pub fn live (&mut self, application_index : usize) -> Vec<usize>
+    {
+        self.expire ();
+        let mut live : Vec<usize> = self.members.iter ()
+            .filter (|(_, member)| member.applications.contains (&application_index))
+            .map (|(&node, _)| node)
+            .collect ();
+        live.push (self.node_index);
+        live.sort_unstable ();
+        live
+    }
+
+    /// The controller of `application_index': its live node with the
+    /// lowest index. None until every live node had the time to be
+    /// heard, so that two nodes do not both act as the controller.
+    pub fn controller (&mut self, application_index : usize) -> Option<usize>
+    {
+        if self.since.elapsed () < self.timeout
+        {
+            return None;
+        }
+        self.live (application_index).first ().copied ()
+    }
+}
+
+/// Data and functions associated with the membership
+/// of the node.
+pub struct ControlSystem
+{
+    /// The MQTT connection of the node.
+    link         : MqttLink,
+
+    /// The membership, shared with the coordination loops.
+    membership   : std::sync::Arc<std::sync::Mutex<Membership>>,
+
+    /// The index of the node.
+    node_index   : usize,
+
+    /// The applications hosted by the node.
+    applications : Vec<usize>,
+
+    /// Period of the heartbeats.
+    period       : std::time::Duration,
+}
+
+impl ControlSystem
+{
+    pub fn new (node_index   : usize,
+                applications : Vec<usize>,
+                period       : std::time::Duration,
+                link         : MqttLink,
+                membership   : std::sync::Arc<std::sync::Mutex<Membership>>) -> Self
+    {
+        Self
+        {
+            link,
+            membership,
+            node_index,
+            applications,
+            period,
+        }
+    }
+
+    /// Start the heartbeats of the node, then track the
+    /// presence of the other nodes.
+    pub fn start (&mut self)
+    {
+
+        #[cfg(feature = "print_log")]
+        println! ("membership - INIT");
+
+        // Heartbeat, and forget meanwhile the nodes gone silent.
+        let link         = self.link.clone ();
+        let membership   = self.membership.clone ();
+        let node_index   = self.node_index;
+        let applications = self.applications.clone ();
+        let period       = self.period;
+        std::thread::spawn (move ||
+            {
+                loop
+                {
+                    let heartbeat = MessagePresence { node : node_index, applications : applications.clone (), alive : true };
+                    link.send (PRESENCE_TOPIC, heartbeat.into_message ());
+                    membership.lock ().unwrap ().expire ();
+                    std::thread::sleep (period);
+                }
+            });
+
+        if let Err (err) = block_on (async
+            {
+                // Subscribe through the connection of the node.
+                let mut strm = self.link.subscribe (&[PRESENCE_TOPIC.to_string ()]).await?;
+
+                #[cfg(feature = "print_log")]
+                println! ("membership - LOOP");
+
+                while let Some (msg_opt) = strm.next ().await
+                {
+                    if let Some (msg) = msg_opt
+                    {
+                        let presence = match protocol::decode::<MessagePresence> (msg.payload ())
+                        {
+                            Ok (presence) => presence,
+                            Err (error) =>
+                                {
+                                    mqtt_utils::reject_payload (&msg, &error);
+                                    continue;
+                                }
+                        };
+                        let mut membership = self.membership.lock ().unwrap ();
+                        if presence.alive
+                        {
+                            membership.heard (presence.node, presence.applications);
+                        }
+                        else
+                        {
+                            membership.leave (presence.node);
+                        }
+                    }
+                }
+
+                // Explicit return type for the async block.
+                Ok::<(), BusError> (())
+            })
+        {
+            eprintln! ("membership - error creating the client: {:?}", err);
+        }
+    }
+}
+
+/// Announce the leave of the node, before disconnecting
+/// from the broker (the will message is not sent then).
+pub fn leave (link : &MqttLink, node_index : usize)
+{
+    let leave = MessagePresence { node : node_index, applications : Vec::new (), alive : false };
+    if let Err (error) = block_on (link.send (PRESENCE_TOPIC, leave.into_message ()))
+    {
+        eprintln! ("membership - unable to announce the leave: {}", error);
+    }
+}
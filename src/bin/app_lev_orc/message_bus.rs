@@ -0,0 +1,500 @@
+/***************************************/
+/*             MESSAGE BUS             */
+/***************************************/
+
+// The transport of the messages between the nodes, behind the
+// MessageBus trait: a connection publishes payloads on topics and
+// streams the payloads published on the topics it subscribed to.
+// MqttBus goes through an MQTT broker (e.g. Mosquitto), MemoryBus
+// through a broker living in the process, so that several nodes can
+// run in one process (e.g. in tests) without an external broker.
+
+use paho_mqtt::{self as mqtt, MQTT_VERSION_5};
+use futures::{channel::mpsc, executor::block_on, future::BoxFuture, stream::StreamExt};
+use crate::tls::TlsFiles;
+
+/// Bounds of the exponential backoff of the reconnection to the
+/// MQTT broker (the MQTT client counts in seconds).
+const RECONNECT_MIN_INTERVAL : std::time::Duration = std::time::Duration::from_secs (1);
+const RECONNECT_MAX_INTERVAL : std::time::Duration = std::time::Duration::from_secs (32);
+
+/// Messages published while disconnected from the MQTT broker, kept
+/// until the connection is back (the oldest are dropped beyond).
+const MAX_BUFFERED_MESSAGES  : i32 = 1024;
+
+/// A message carried by the bus.
+#[derive(Clone, Debug)]
+pub struct BusMessage
+{
+    /// The topic the message is published on.
+    topic   : String,
+
+    /// The encoded message (see protocol).
+    payload : Vec<u8>,
+}
+
+impl BusMessage
+{
+    pub fn new (topic : impl Into<String>, payload : Vec<u8>) -> Self
+    {
+        Self { topic : topic.into (), payload }
+    }
+
+    pub fn topic (&self) -> &str
+    {
+        &self.topic
+    }
+
+    pub fn payload (&self) -> &[u8]
+    {
+        &self.payload
+    }
+
+    /// The payload as text, for the logs.
+    pub fn payload_str (&self) -> std::borrow::Cow<'_, str>
+    {
+        String::from_utf8_lossy (&self.payload)
+    }
+}
+
+impl From<mqtt::Message> for BusMessage
+{
+    fn from (msg : mqtt::Message) -> Self
+    {
+        Self::new (msg.topic (), msg.payload ().to_vec ())
+    }
+}
+
+/// Error raised by a bus.
+#[derive(Debug)]
+pub enum BusError
+{
+    /// Error of the MQTT client.
+    Mqtt (mqtt::Error),
+
+    /// The connection to the bus is closed.
+    Disconnected,
+}
+
+impl std::fmt::Display for BusError
+{
+    fn fmt (&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            BusError::Mqtt (error) => write! (f, "{}", error),
+            BusError::Disconnected => write! (f, "disconnected from the bus"),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+impl From<mqtt::Error> for BusError
+{
+    fn from (error : mqtt::Error) -> Self
+    {
+        BusError::Mqtt (error)
+    }
+}
+
+/// Incoming messages of a subscriber, None when the
+/// connection to the bus is lost.
+pub type MessageStream = mpsc::UnboundedReceiver<Option<BusMessage>>;
+
+/// Completion of a publication. The message is published
+/// whether or not the delivery is awaited.
+pub struct Delivery (BoxFuture<'static, Result<(), BusError>>);
+
+impl std::future::Future for Delivery
+{
+    type Output = Result<(), BusError>;
+
+    fn poll (mut self : std::pin::Pin<&mut Self>, cx : &mut std::task::Context<'_>) -> std::task::Poll<Self::Output>
+    {
+        self.0.as_mut ().poll (cx)
+    }
+}
+
+/// A connection to the message bus.
+pub trait MessageBus : Send + Sync
+{
+    /// Publish `message'.
+    fn publish (&self, message : BusMessage) -> Delivery;
+
+    /// Subscribe to `topics' and return the stream of the
+    /// messages published on them.
+    fn subscribe (&self, topics : &[String]) -> BoxFuture<'static, Result<MessageStream, BusError>>;
+
+    /// Disconnect from the bus (the will message is not sent).
+    fn disconnect (&self) -> Result<(), BusError>;
+}
+
+type Route = (String, mpsc::UnboundedSender<Option<BusMessage>>);
+
+/// Subscribed topics of a connection, with the stream of each subscriber.
+#[derive(Clone, Default)]
+struct Routes (std::sync::Arc<std::sync::Mutex<Vec<Route>>>);
+
+impl Routes
+{
+    /// Route the messages published on `topics' to a new stream.
+    fn add (&self, topics : &[String]) -> MessageStream
+    {
+        let (sender, receiver) = mpsc::unbounded ();
+        let mut routes = self.0.lock ().unwrap ();
+        routes.extend (topics.iter ().map (|topic| (topic.clone (), sender.clone ())));
+        receiver
+    }
+
+    /// The subscribed topics.
+    fn topics (&self) -> Vec<String>
+    {
+        let routes = self.0.lock ().unwrap ();
+        let mut topics : Vec<String> = routes.iter ().map (|(topic, _)| topic.clone ()).collect ();
+        topics.sort_unstable ();
+        topics.dedup ();
+        topics
+    }
+
+    /// Dispatch `msg_opt' to the subscribers of its topic, or
+    /// once to each of them when the connection is lost (None).
+    fn dispatch (&self, msg_opt : &Option<BusMessage>)
+    {
+        let routes = self.0.lock ().unwrap ();
+        for (i, (topic, sender)) in routes.iter ().enumerate ()
+        {
+            match msg_opt
+            {
+                Some (msg) if msg.topic () != topic => {}
+                None if routes[..i].iter ().any (|(_, other)| other.same_receiver (sender)) => {}
+                _ =>
+                    {
+                        // A subscriber that has terminated is ignored.
+                        let _ = sender.unbounded_send (msg_opt.clone ());
+                    }
+            }
+        }
+    }
+}
+
+/// A connection to an MQTT broker. A lost connection is restored
+/// with an exponential backoff, then the subscriptions are renewed;
+/// meanwhile the subscribers get None, and the messages published
+/// are buffered.
+pub struct MqttBus
+{
+    /// The MQTT client.
+    client : mqtt::AsyncClient,
+
+    /// Subscribed topics.
+    routes : Routes,
+}
+
+impl MqttBus
+{
+    /// Connect to the broker as `client_id', over TLS with the
+    /// certificate of `tls' if any, and start dispatching the
+    /// incoming messages. If the connection is lost, the broker
+    /// publishes `will' on behalf of the client, which reconnects.
+    pub fn connect (client_id      : &str,
+                    broker_address : &str,
+                    broker_port    : u16,
+                    will           : BusMessage,
+                    tls            : Option<&TlsFiles>) -> Result<Self, BusError>
+    {
+        let scheme = if tls.is_some () { "ssl" } else { "mqtt" };
+        let host = format! ("{}://{}:{}", scheme, broker_address, broker_port);
+
+        // Create the client, buffering the messages published while
+        // disconnected. The session is not kept by the broker: each
+        // connection starts clean, then subscribes again.
+        let create_opts = mqtt::CreateOptionsBuilder::new ()
+            .server_uri (host)
+            .client_id (client_id)
+            .send_while_disconnected (true)
+            .max_buffered_messages (MAX_BUFFERED_MESSAGES)
+            .delete_oldest_messages (true)
+            .finalize ();
+        let mut client = mqtt::AsyncClient::new (create_opts)?;
+        let routes = Routes::default ();
+
+        client.set_disconnected_callback (|_, _props, reason|
+            {
+                eprintln! ("mqtt_bus - server disconnected with reason: {}, RECONNECTING", reason);
+            }
+        );
+        client.set_connection_lost_callback (|_|
+            {
+                eprintln! ("mqtt_bus - connection LOST, RECONNECTING");
+            }
+        );
+
+        // On a reconnection, renew the subscriptions.
+        let subscribed = routes.clone ();
+        client.set_connected_callback (move |client|
+            {
+                let topics = subscribed.topics ();
+                if !topics.is_empty ()
+                {
+                    eprintln! ("mqtt_bus - RECONNECTED, subscribing again to {} topics", topics.len ());
+                    subscribe (client, &topics);
+                }
+            }
+        );
+
+        // Get message stream before connecting.
+        let mut strm = client.get_stream (None);
+
+        // Define the set of options for the connection.
+        let lwt = mqtt::Message::new (will.topic, will.payload, mqtt::QOS_1);
+        let mut conn_opts = mqtt::ConnectOptionsBuilder::with_mqtt_version (MQTT_VERSION_5);
+        conn_opts.clean_start (true)
+            .will_message (lwt)
+            .automatic_reconnect (RECONNECT_MIN_INTERVAL, RECONNECT_MAX_INTERVAL);
+
+        // The broker must present a certificate signed by the CA, for
+        // its address, and the client presents its own.
+        if let Some (files) = tls
+        {
+            let ssl_opts = mqtt::SslOptionsBuilder::new ()
+                .trust_store (&files.ca_file)?
+                .key_store (&files.cert_file)?
+                .private_key (&files.key_file)?
+                .enable_server_cert_auth (true)
+                .verify (true)
+                .finalize ();
+            conn_opts.ssl_options (ssl_opts);
+        }
+        let conn_opts = conn_opts.finalize ();
+
+        // Make the connection to the broker.
+        block_on (client.connect (conn_opts))?;
+
+        let bus = Self { client, routes };
+
+        // Dispatch the incoming messages.
+        let routes = bus.routes.clone ();
+        std::thread::spawn (move ||
+            {
+                block_on (async
+                    {
+                        while let Some (msg_opt) = strm.next ().await
+                        {
+                            routes.dispatch (&msg_opt.map (BusMessage::from));
+                        }
+                    });
+            });
+
+        Ok (bus)
+    }
+}
+
+impl MessageBus for MqttBus
+{
+    fn publish (&self, message : BusMessage) -> Delivery
+    {
+        // While disconnected, the message is buffered until the
+        // reconnection: its delivery is not awaited.
+        let connected = self.client.is_connected ();
+        let token = self.client.publish (mqtt::Message::new (message.topic, message.payload, mqtt::QOS_1));
+        Delivery (Box::pin (async move
+            {
+                if connected
+                {
+                    token.await?;
+                }
+                Ok (())
+            }))
+    }
+
+    fn subscribe (&self, topics : &[String]) -> BoxFuture<'static, Result<MessageStream, BusError>>
+    {
+        let receiver = self.routes.add (topics);
+        let token = subscribe (&self.client, topics);
+        Box::pin (async move
+            {
+                token.await?;
+                Ok (receiver)
+            })
+    }
+
+    fn disconnect (&self) -> Result<(), BusError>
+    {
+        block_on (self.client.disconnect (None))?;
+        Ok (())
+    }
+}
+
+/// Subscribe `client' to `topics'.
+fn subscribe (client : &mqtt::AsyncClient, topics : &[String]) -> mqtt::Token
+{
+    let sub_opts = vec![mqtt::SubscribeOptions::with_retain_as_published (); topics.len ()];
+    client.subscribe_many_with_options (
+        topics,
+        &vec![mqtt::QOS_1; topics.len ()],
+        &sub_opts,
+        None)
+}
+
+/// A broker living in the process, shared by its MemoryBus
+/// connections. Messages are delivered in order of publication.
+#[cfg_attr(not(test), allow(dead_code))]
+#[derive(Clone, Default)]
+pub struct MemoryBroker
+{
+    /// The subscribed topics of the live connections, by connection.
+    connections : std::sync::Arc<std::sync::Mutex<std::collections::HashMap<usize, Routes>>>,
+
+    /// Identifier of the next connection.
+    next_id     : std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl MemoryBroker
+{
+    pub fn new () -> Self
+    {
+        Self::default ()
+    }
+
+    /// Open a connection to the broker. If the connection is
+    /// lost (see MemoryBus::lose), `will' is published.
+    pub fn connect (&self, will : Option<BusMessage>) -> MemoryBus
+    {
+        let id = self.next_id.fetch_add (1, std::sync::atomic::Ordering::Relaxed);
+        self.connections.lock ().unwrap ().insert (id, Routes::default ());
+        MemoryBus { broker : self.clone (), id, will }
+    }
+
+    /// Deliver `message' to the subscribers of its topic.
+    fn dispatch (&self, message : &BusMessage)
+    {
+        let connections = self.connections.lock ().unwrap ();
+        for routes in connections.values ()
+        {
+            routes.dispatch (&Some (message.clone ()));
+        }
+    }
+
+    /// Close the connection `id', and return its routes.
+    fn close (&self, id : usize) -> Option<Routes>
+    {
+        self.connections.lock ().unwrap ().remove (&id)
+    }
+}
+
+/// A connection to a MemoryBroker.
+#[cfg_attr(not(test), allow(dead_code))]
+pub struct MemoryBus
+{
+    /// The broker.
+    broker : MemoryBroker,
+
+    /// Identifier of the connection within the broker.
+    id     : usize,
+
+    /// Published when the connection is lost.
+    will   : Option<BusMessage>,
+}
+
+#[cfg_attr(not(test), allow(dead_code))]
+impl MemoryBus
+{
+    /// Lose the connection, as a crashed node would: the
+    /// subscribers of the connection get None, then the will
+    /// message is published.
+    pub fn lose (&self)
+    {
+        if let Some (routes) = self.broker.close (self.id)
+        {
+            routes.dispatch (&None);
+            if let Some (will) = &self.will
+            {
+                self.broker.dispatch (will);
+            }
+        }
+    }
+}
+
+impl MessageBus for MemoryBus
+{
+    fn publish (&self, message : BusMessage) -> Delivery
+    {
+        let connected = self.broker.connections.lock ().unwrap ().contains_key (&self.id);
+        if connected
+        {
+            self.broker.dispatch (&message);
+        }
+        Delivery (Box::pin (async move
+            {
+                if connected { Ok (()) } else { Err (BusError::Disconnected) }
+            }))
+    }
+
+    fn subscribe (&self, topics : &[String]) -> BoxFuture<'static, Result<MessageStream, BusError>>
+    {
+        let receiver = self.broker.connections.lock ().unwrap ()
+            .get (&self.id)
+            .map (|routes| routes.add (topics))
+            .ok_or (BusError::Disconnected);
+        Box::pin (async move { receiver })
+    }
+
+    fn disconnect (&self) -> Result<(), BusError>
+    {
+        self.broker.close (self.id)
+            .map (|_| ())
+            .ok_or (BusError::Disconnected)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn next (strm : &mut MessageStream) -> Option<BusMessage>
+    {
+        block_on (strm.next ()).flatten ()
+    }
+
+    #[test]
+    fn memory_bus_routes_by_topic ()
+    {
+        let broker = MemoryBroker::new ();
+        let node_0 = broker.connect (None);
+        let node_1 = broker.connect (None);
+        let mut strm_0 = block_on (node_0.subscribe (&["a".to_string ()])).unwrap ();
+        let mut strm_1 = block_on (node_1.subscribe (&["a".to_string (), "b".to_string ()])).unwrap ();
+
+        block_on (node_0.publish (BusMessage::new ("b", vec![1]))).unwrap ();
+        block_on (node_1.publish (BusMessage::new ("a", vec![2]))).unwrap ();
+
+        assert_eq! (next (&mut strm_0).unwrap ().payload (), [2]);
+        assert_eq! (next (&mut strm_1).unwrap ().payload (), [1]);
+        assert_eq! (next (&mut strm_1).unwrap ().payload (), [2]);
+    }
+
+    #[test]
+    fn memory_bus_publishes_the_will_of_a_lost_connection ()
+    {
+        let broker = MemoryBroker::new ();
+        let node_0 = broker.connect (Some (BusMessage::new ("presence", vec![0])));
+        let node_1 = broker.connect (None);
+        let mut strm_0 = block_on (node_0.subscribe (&["presence".to_string ()])).unwrap ();
+        let mut strm_1 = block_on (node_1.subscribe (&["presence".to_string ()])).unwrap ();
+
+        node_0.lose ();
+        assert! (block_on (strm_0.next ()).unwrap ().is_none ());
+        assert_eq! (next (&mut strm_1).unwrap ().topic (), "presence");
+        assert! (block_on (node_0.publish (BusMessage::new ("presence", vec![]))).is_err ());
+
+        // A clean disconnection does not publish the will.
+        let node_2 = broker.connect (Some (BusMessage::new ("presence", vec![2])));
+        node_2.disconnect ().unwrap ();
+        node_2.lose ();
+        block_on (node_1.publish (BusMessage::new ("presence", vec![1]))).unwrap ();
+        assert_eq! (next (&mut strm_1).unwrap ().payload (), [1]);
+    }
+}
@@ -2,63 +2,114 @@
 /*       UTILITIES FOR MQTT       */
 /**********************************/
 
-/// This is the message sent through MQTT containing
-/// the local update in the ADMM algorithm.
-pub struct MessageLocal
-{
-    pub src       : usize,
-    pub local_sum : f32,
-}
+use crate::message_bus::{BusError, BusMessage, Delivery, MessageBus, MessageStream, MqttBus};
+use crate::protocol::{self, Encoding, Message, MessagePresence, Payload};
+use crate::tls::{self, TlsFiles};
 
-impl std::str::FromStr for MessageLocal {
-    type Err = std::string::ParseError;
+/// Number of payloads rejected by this node since its start.
+static REJECTED_PAYLOADS : std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new (0);
 
-    fn from_str (s: &str) -> Result<Self, Self::Err>
-    {
-        let strs : Vec<&str> = s.split_terminator ('#').collect ();
-        match (strs.first (), strs.last ())
-        {
-            (Some (&str1), Some (&str2)) =>
-                {
-                    Ok (MessageLocal
-                    {
-                        src: usize::from_str (str1)
-                            .expect ("Unable to convert string to usize"),
-                        local_sum: f32::from_str (str2)
-                            .expect ("Unable to convert string to f32")
-                    })
-                }
-            _ =>
-                {
-                    panic! ("Error while parsing a MessageLocal");
-                }
-        }
-    }
+/// Log a message that cannot be handled, and count it. The
+/// message is then dropped by the caller.
+pub fn reject_payload (msg : &BusMessage, error : &dyn std::fmt::Display)
+{
+    let rejected = REJECTED_PAYLOADS.fetch_add (1, std::sync::atomic::Ordering::Relaxed) + 1;
+    eprintln! ("mqtt - REJECTED payload on {} ({} so far): {}", msg.topic (), rejected, error);
 }
 
-impl std::fmt::Display for MessageLocal
+/// Number of payloads rejected by this node since its start.
+pub fn rejected_payloads () -> u64
 {
-    fn fmt (&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
-    {
-        write! (f, "{}", format! ("{}#{}", self.src, self.local_sum))
-    }
+    REJECTED_PAYLOADS.load (std::sync::atomic::Ordering::Relaxed)
 }
 
+/// Topics of the nodes (centralized coordination), the first five relative
+/// to the federation of the application (see federation_topic). Any node
+/// may be elected as the controller, so each subscribes to all of them.
 pub const BROKER_TOPICS : [&str; 6] =
     [
-        "federation/migration",
-        "federation/local_update",
-        "federation/global_update/",
-        "federation/src/",
-        "federation/dst/",
+        "migration",
+        "local_update",
+        "global_update/",
+        "src/",
+        "dst/",
         "disconnect",
     ];
 
-pub const REGULAR_TOPICS : [&str; 5] =
-    [
-        "federation/migration",
-        "federation/global_update/",
-        "federation/src/",
-        "federation/dst/",
-        "disconnect",
-    ];
\ No newline at end of file
+/// Topic of the presence of the nodes, shared by all the applications.
+pub const PRESENCE_TOPIC : &str = "federation/presence";
+
+/// Topic of the federation of `application_index', so that the
+/// applications hosted by the same nodes coordinate separately,
+/// e.g. federation_topic (1, "migration") = "federation/app_1/migration".
+pub fn federation_topic (application_index : usize, topic : &str) -> String
+{
+    format! ("federation/app_{}/{}", application_index, topic)
+}
+
+/// The connection of a node to the message bus, shared by the tasks
+/// of all the applications it hosts. Incoming messages are dispatched
+/// to the subscribers of their topic.
+#[derive(Clone)]
+pub struct MqttLink
+{
+    /// The connection to the bus.
+    bus      : std::sync::Arc<dyn MessageBus>,
+
+    /// Encoding of the messages sent (see protocol).
+    encoding : Encoding,
+}
+
+impl MqttLink
+{
+    /// Connect to the MQTT broker, over TLS if configured, and start
+    /// dispatching the incoming messages. The client identifier starts
+    /// with the name of the node in its certificate (see tls).
+    pub fn connect (node_index     : usize,
+                    broker_address : &str,
+                    broker_port    : u16,
+                    encoding       : Encoding,
+                    tls            : Option<&TlsFiles>) -> Result<Self, BusError>
+    {
+        let bus = MqttBus::connect (&format! ("{}_orc", tls::node_name (node_index)),
+                                    broker_address,
+                                    broker_port,
+                                    Self::will (node_index, encoding),
+                                    tls)?;
+        Ok (Self::over (std::sync::Arc::new (bus), encoding))
+    }
+
+    /// The link through the connection `bus'.
+    pub fn over (bus : std::sync::Arc<dyn MessageBus>, encoding : Encoding) -> Self
+    {
+        Self { bus, encoding }
+    }
+
+    /// The will message of the node: if its connection is lost,
+    /// the bus announces the leave of the node on its behalf.
+    pub fn will (node_index : usize, encoding : Encoding) -> BusMessage
+    {
+        let leave = MessagePresence { node : node_index, applications : Vec::new (), alive : false };
+        BusMessage::new (PRESENCE_TOPIC, protocol::encode (&leave.into_message (), encoding))
+    }
+
+    /// Subscribe to `topics' and return the stream of the
+    /// messages published on them.
+    pub async fn subscribe (&self, topics : &[String]) -> Result<MessageStream, BusError>
+    {
+        self.bus.subscribe (topics).await
+    }
+
+    /// Publish `message' on `topic', encoded as configured.
+    pub fn send (&self, topic : impl Into<String>, message : Message) -> Delivery
+    {
+        let payload = protocol::encode (&message, self.encoding);
+        self.bus.publish (BusMessage::new (topic, payload))
+    }
+
+    /// Disconnect from the bus (the will message is not sent).
+    pub fn disconnect (&self) -> Result<(), BusError>
+    {
+        self.bus.disconnect ()
+    }
+}
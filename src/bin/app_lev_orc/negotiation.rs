@@ -0,0 +1,651 @@
+/***************************************/
+/*             NEGOTIATION             */
+/***************************************/
+
+// The migrations negotiated by the federation, one session per
+// request. A session is opened by the migration message of the
+// request, runs its own ADMM, then, once the destination is known,
+// waits for the transfer on the source and destination nodes only.
+// Sessions of different requests run in parallel: the messages of
+// the negotiation carry the identifier of the request. The nodes
+// taking part are the live members of the federation seen by the src
+// node when it triggers the migration (see membership), carried by
+// the migration message so that every node agrees on them, the ADMM
+// vectors being indexed by their position among them.
+//
+// A node does not wait forever for the others: a round of the ADMM
+// that is not complete within the round timeout goes on with the
+// nodes that replied, provided they reach the quorum, and is aborted
+// otherwise. A session making no progress at all (e.g. the node
+// computing the global update is gone, or the transfer never starts)
+// is dropped after the session timeout.
+//
+// In distributed coordination, every node performs the global update,
+// hence all must end a round timed out with the same nodes. Only the
+// src node times the rounds out: it publishes the end of the round
+// among the local updates, which the broker relays to every node in
+// the same order, so that every node ends the round with the local
+// updates received before it.
+//
+// Once the negotiation has concluded, the src node asks the request it
+// is serving, if any, to checkpoint. Its session then waits for the end
+// of the job of the request, that the sporadic server posts to the
+// coordination loop, so that the other sessions go on meanwhile.
+
+use crate::admm_solver::{GlobalSolver, LocalSolver};
+use crate::message_bus::BusMessage;
+use crate::distance_metric::DistanceMetric;
+use crate::request_transfer::{self, DataServer};
+use crate::state::{ApplicationState, Attributes, Request, RequestId, RequestState, TransitionError};
+
+/// Period of the ticks checking the timeouts of the sessions.
+pub const TICK_PERIOD : std::time::Duration = std::time::Duration::from_millis (50);
+
+/// The limits on the wait of a negotiation.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits
+{
+    /// Maximum duration of a round of the ADMM.
+    pub round_timeout   : std::time::Duration,
+
+    /// Maximum duration of a session without progress.
+    pub session_timeout : std::time::Duration,
+
+    /// Minimum number of nodes replying in a round for the
+    /// negotiation to go on, by default a majority of the
+    /// nodes taking part.
+    pub quorum          : Option<usize>,
+}
+
+/// The timeout of a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timeout
+{
+    /// The round goes on with the nodes that replied.
+    Round,
+
+    /// Too few nodes replied: the negotiation is aborted.
+    Quorum,
+
+    /// The session made no progress: it is dropped.
+    Session,
+}
+
+/// What becomes of a local update received by a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Local
+{
+    /// Counted in the current round.
+    Counted,
+
+    /// Of another round, or of a node excluded from the placement.
+    Ignored,
+
+    /// The node does not take part in the negotiation.
+    NotMember,
+}
+
+/// An event of a coordination loop.
+pub enum Event
+{
+    /// A message, or None if the connection is lost.
+    Message (Option<BusMessage>),
+
+    /// Time to check the timeouts of the sessions.
+    Tick,
+
+    /// The job of a request has ended on the sporadic server: the
+    /// request has been checkpointed, or it has completed or failed.
+    JobEnd (RequestId),
+}
+
+/// The negotiation of the migration of a request.
+pub struct Session
+{
+    /// The request to migrate, as sent by the node hosting it.
+    pub request       : Request,
+
+    /// The node hosting the request.
+    pub src_node      : usize,
+
+    /// The destination, once the ADMM has terminated.
+    pub dest_node     : Option<usize>,
+
+    /// The nodes taking part in the negotiation, by increasing index.
+    pub members       : Vec<usize>,
+
+    /// Memory the request needs in kB, as measured by the src node.
+    pub memory        : u32,
+
+    /// Whether this node could host the request when the
+    /// negotiation started; if not, its local update is 0.
+    could_host        : bool,
+
+    /// The local update of the ADMM, performed by every node.
+    pub local_solver  : LocalSolver,
+
+    /// The global update of the ADMM, performed by the controller
+    /// (centralized coordination) or by every node (distributed).
+    pub global_solver : GlobalSolver,
+
+    /// The round of the local update of this node.
+    round             : usize,
+
+    /// Start of the current round of the ADMM.
+    round_start       : std::time::Instant,
+
+    /// Whether this node has asked to end the current round.
+    ending            : bool,
+
+    /// On the src node, whether the session waits for the checkpoint
+    /// of its request before signaling the destination.
+    checkpointing     : bool,
+
+    /// Last progress of the session.
+    last_progress     : std::time::Instant,
+
+    /// Start of the negotiation.
+    #[cfg(feature = "timing_log")]
+    pub start_time    : libc::timespec,
+
+    /// Start of the transfer, on the src node.
+    #[cfg(feature = "migration_log")]
+    pub start_send    : libc::timespec,
+}
+
+impl Session
+{
+    /// Open the negotiation of `request', hosted by `src_node', among
+    /// the `members' seen by the src node, the request needing `memory'
+    /// kB: the state of this node (attributes, resources, and the
+    /// expected completion time of the request) is the one of now.
+    pub fn open (request          : Request,
+                 src_node         : usize,
+                 mut members      : Vec<usize>,
+                 memory           : u32,
+                 state            : &ApplicationState,
+                 penalty          : f32,
+                 etc_multiplier   : f32,
+                 iteration_limit  : usize,
+                 metric           : std::sync::Arc<dyn DistanceMetric>) -> Self
+    {
+        // The src node takes part, even if its heartbeat is yet to be heard.
+        if let Err (position) = members.binary_search (&src_node)
+        {
+            members.insert (position, src_node);
+        }
+        let node_number = members.len ();
+
+        let mut local_solver = LocalSolver::new (node_number, 20.0, 0.5, Attributes::new (), metric);
+        local_solver.clear (node_number,
+                            penalty,
+                            etc_multiplier,
+                            state.node_state.get_attributes (),
+                            state.get_expected_completion_time (&request));
+
+        #[cfg(feature = "print_log")]
+        println! ("negotiation - request {} request_etc = {}", request.get_id (), local_solver.request_etc);
+
+        #[cfg(feature = "timing_log")]
+        let mut start_time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        #[cfg(feature = "timing_log")]
+        unsafe
+            {
+                libc::clock_gettime (libc::CLOCK_MONOTONIC, &mut start_time);
+            }
+
+        Self
+        {
+            request,
+            src_node,
+            dest_node     : None,
+            members,
+            memory,
+            could_host    : state.could_host_computation (&request, memory),
+            local_solver,
+            global_solver : GlobalSolver::new (node_number, iteration_limit),
+            round         : 0,
+            round_start   : std::time::Instant::now (),
+            ending        : false,
+            checkpointing : false,
+            last_progress : std::time::Instant::now (),
+            #[cfg(feature = "timing_log")]
+            start_time,
+            #[cfg(feature = "migration_log")]
+            start_send    : libc::timespec { tv_sec: 0, tv_nsec: 0 },
+        }
+    }
+
+    /// Perform the local update, and return x + u to send.
+    pub fn local_update (&mut self) -> f32
+    {
+        if !self.could_host
+        {
+            // If the node has not enough resources to host the request,
+            // speedup the local_update.
+            self.local_solver.local = 0f32;
+        }
+        else
+        {
+            self.local_solver.local_x_update (&self.request);
+        }
+        self.local_solver.get_local () + self.local_solver.get_dual ()
+    }
+
+    /// Update the local solver with the new global value, perform
+    /// the dual update then the local one, and return x + u to send.
+    pub fn global_update (&mut self, global : f32) -> f32
+    {
+        self.last_progress = std::time::Instant::now ();
+        self.round += 1;
+        self.local_solver.set_global (global);
+        self.local_solver.local_dual_update ();
+        self.local_update ()
+    }
+
+    /// The round of the last local update of this node, each global
+    /// value received starting a new one.
+    pub fn round (&self) -> usize
+    {
+        self.round
+    }
+
+    /// Position of `node' in the ADMM, if it takes part.
+    pub fn slot (&self, node : usize) -> Option<usize>
+    {
+        self.members.binary_search (&node).ok ()
+    }
+
+    /// Add the x + u of `node' for the round `iteration' to the
+    /// global update.
+    pub fn add_local (&mut self, node : usize, iteration : usize, local_sum : f32) -> Local
+    {
+        match self.slot (node)
+        {
+            Some (slot) if self.global_solver.add_local_sum (local_sum, slot, iteration) => Local::Counted,
+            Some (_) => Local::Ignored,
+            None     => Local::NotMember,
+        }
+    }
+
+    /// The global value for `node', a member of the negotiation.
+    pub fn global_of (&self, node : usize) -> f32
+    {
+        self.global_solver.get_global_from_index (self.slot (node).expect ("Not a member"))
+    }
+
+    /// The node chosen to host the request.
+    pub fn destination (&self) -> usize
+    {
+        self.members[self.global_solver.get_max_global_index ()]
+    }
+
+    /// Exclude from the placement the nodes silent in the
+    /// current round, and return them.
+    pub fn exclude_silent (&mut self) -> Vec<usize>
+    {
+        self.global_solver.exclude_silent ().into_iter ()
+            .map (|slot| self.members[slot])
+            .collect ()
+    }
+
+    /// Perform the global update on z, which starts a new round.
+    pub fn z_update (&mut self)
+    {
+        self.global_solver.global_z_updater ();
+        self.ending        = false;
+        self.round_start   = std::time::Instant::now ();
+        self.last_progress = self.round_start;
+    }
+
+    /// Record that this node asks to end the current round, and
+    /// return whether it has not asked yet.
+    pub fn end_round (&mut self) -> bool
+    {
+        !std::mem::replace (&mut self.ending, true)
+    }
+
+    /// Whether enough nodes replied in the current round for the
+    /// negotiation to go on.
+    pub fn has_quorum (&self, limits : &Limits) -> bool
+    {
+        self.global_solver.locals_len () >= limits.quorum.unwrap_or (self.members.len () / 2 + 1)
+    }
+
+    /// Whether the ADMM is still running.
+    pub fn is_negotiating (&self) -> bool
+    {
+        self.dest_node.is_none ()
+    }
+
+    /// Record the destination, and return whether this node takes part
+    /// in the transfer (hence the session has to be kept until then).
+    pub fn conclude (&mut self, dest_node : usize, node_index : usize) -> bool
+    {
+        self.dest_node     = Some (dest_node);
+        self.last_progress = std::time::Instant::now ();
+        dest_node != self.src_node && (node_index == self.src_node || node_index == dest_node)
+    }
+
+    /// Record a progress of the session, e.g. the src node has
+    /// signaled the destination after a checkpoint.
+    pub fn progress (&mut self)
+    {
+        self.last_progress = std::time::Instant::now ();
+    }
+
+    /// Whether the session waits for the checkpoint of its request.
+    pub fn is_waiting_checkpoint (&self) -> bool
+    {
+        self.checkpointing
+    }
+
+    /// Stop waiting for the checkpoint of the request, its job has ended.
+    pub fn checkpoint_done (&mut self)
+    {
+        self.checkpointing = false;
+        self.progress ();
+    }
+
+    /// The timeout of the session, if any, on a node performing the
+    /// global update (`updates_global') or not.
+    pub fn timeout (&self, limits : &Limits, updates_global : bool) -> Option<Timeout>
+    {
+        if self.is_negotiating () && updates_global
+        {
+            if self.round_start.elapsed () < limits.round_timeout
+            {
+                None
+            }
+            else if self.has_quorum (limits)
+            {
+                Some (Timeout::Round)
+            }
+            else
+            {
+                Some (Timeout::Quorum)
+            }
+        }
+        else if self.last_progress.elapsed () >= limits.session_timeout
+        {
+            Some (Timeout::Session)
+        }
+        else
+        {
+            None
+        }
+    }
+}
+
+/// The sessions of a node, by request.
+pub type Sessions = std::collections::HashMap<RequestId, Session>;
+
+/// Whether the migration message of `request_id' opens a session: it
+/// does, unless the request is already negotiated. A session waiting
+/// for a transfer is replaced, as its source has given up on it.
+pub fn should_open (sessions : &Sessions, request_id : RequestId) -> bool
+{
+    sessions.get (&request_id).is_none_or (|session| !session.is_negotiating ())
+}
+
+/// Prepare the request of a negotiation concluded on its src node to
+/// leave. A queued request is ready to go (InTransit), while the
+/// request being served is asked to checkpoint: its session then waits
+/// for the end of its job. Return whether the request is ready to go.
+pub fn prepare_departure (session           : &mut Session,
+                          application_state : &std::sync::Mutex<ApplicationState>) -> Result<bool, TransitionError>
+{
+    let request_id = session.request.get_id ();
+    let mut state = application_state.lock ().unwrap ();
+    match state.get_request_state (request_id)
+    {
+        Some (served @ (RequestState::Running | RequestState::Checkpointing)) =>
+            {
+                if served == RequestState::Running
+                {
+                    state.set_request_state (request_id, RequestState::Checkpointing)?;
+                }
+                state.set_should_migrate_of_request (request_id, true);
+                session.checkpointing = true;
+                Ok (false)
+            }
+        _ => state.set_request_state (request_id, RequestState::InTransit).map (|_| true),
+    }
+}
+
+/// Queue again a request checkpointed for a negotiation which is no
+/// longer in progress (e.g. its session has timed out meanwhile).
+pub fn requeue_checkpointed (request_id        : RequestId,
+                             application_state : &std::sync::Mutex<ApplicationState>)
+{
+    let mut state = application_state.lock ().unwrap ();
+    if state.get_request_state (request_id) == Some (RequestState::InTransit)
+    {
+        state.set_should_migrate_of_request (request_id, false);
+        if let Err (error) = state.set_request_state (request_id, RequestState::Queued)
+        {
+            eprintln! ("negotiation - {}", error);
+        }
+    }
+}
+
+/// Drop a session which made no progress. If this node is the src of
+/// a concluded negotiation, the transfer has never started: the
+/// request, checkpointed if it was served, is queued again, and the
+/// request being served is no longer asked to checkpoint.
+pub fn abandon (session           : &Session,
+                node_index        : usize,
+                application_state : &std::sync::Mutex<ApplicationState>)
+{
+    eprintln! ("negotiation - request {} TIMEOUT: session dropped", session.request.get_id ());
+
+    if session.is_negotiating () || session.src_node != node_index
+    {
+        return;
+    }
+    let request_id = session.request.get_id ();
+    if session.is_waiting_checkpoint ()
+    {
+        let mut state = application_state.lock ().unwrap ();
+        if state.get_request (request_id).is_some ()
+        {
+            state.set_should_migrate_of_request (request_id, false);
+        }
+    }
+    requeue_checkpointed (request_id, application_state);
+}
+
+/// A stream of ticks, every `period', until it is dropped.
+pub fn ticks (period : std::time::Duration) -> futures::channel::mpsc::UnboundedReceiver<()>
+{
+    let (sender, receiver) = futures::channel::mpsc::unbounded ();
+    std::thread::spawn (move ||
+        {
+            while sender.unbounded_send (()).is_ok ()
+            {
+                std::thread::sleep (period);
+            }
+        });
+    receiver
+}
+
+/// Expect the folder of `request_id' from `src_node' on the data server, then queue
+/// the request once received. The coordination loop goes on meanwhile,
+/// so that the transfers of different requests do not wait for each
+/// other. The request fails if its transfer does not start in `timeout'.
+pub fn receive_request (request_id        : RequestId,
+                        src_node          : usize,
+                        data_server       : &std::sync::Arc<DataServer>,
+                        timeout           : std::time::Duration,
+                        application_state : std::sync::Arc<std::sync::Mutex<ApplicationState>>,
+                        barrier           : std::sync::Arc<(std::sync::Mutex<u8>, std::sync::Condvar)>)
+{
+    #[cfg(feature = "migration_log")]
+    let mut start_receive = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    #[cfg(feature = "migration_log")]
+    unsafe
+        {
+            libc::clock_gettime (libc::CLOCK_MONOTONIC, &mut start_receive);
+        }
+
+    data_server.expect (request_id, src_node, timeout, Box::new (move |received|
+        {
+            // Decompress the archive received as a folder.
+            let received = received
+                .and_then (|compressed_file_name| request_transfer::decompress_request (&compressed_file_name, request_id));
+
+            let mut state = application_state.lock ().unwrap ();
+            if let Err (error) = received
+            {
+                eprintln! ("negotiation - unable to receive request {}: {}", request_id, error);
+                if let Err (error) = state.remove_request (request_id, RequestState::Failed)
+                {
+                    eprintln! ("negotiation - {}", error);
+                }
+                return;
+            }
+
+            #[cfg(feature = "print_log")]
+            println! ("negotiation - request {} RECEIVED", request_id);
+
+            // Finally, the request can be served: update the
+            // barrier of the sporadic server.
+            if let Err (error) = state.set_request_state (request_id, RequestState::Queued)
+            {
+                eprintln! ("negotiation - {}", error);
+            }
+            drop (state);
+            {
+                let (number_of_requests, barrier) = &*barrier;
+                *number_of_requests.lock ().unwrap () += 1;
+                barrier.notify_all ();
+            }
+
+            #[cfg(feature = "migration_log")]
+            {
+                let receive_time = crate::linux_utils::get_completion_time (start_receive);
+                crate::log_writer::save_receive_time (receive_time);
+            }
+        }));
+}
+
+/// Send the folder of `request' to the data server of `dest_node'
+/// at `dst' in the background, then journal the transfer and remove
+/// the folder. The request has already left the state of the
+/// application, with its stay recorded in its lineage: if the
+/// transfer fails, its folder is kept, the stay is forgotten and
+/// the request is queued again.
+pub fn send_request (request           : Request,
+                     data_server       : &std::sync::Arc<DataServer>,
+                     dst               : String,
+                     dest_node         : usize,
+                     application_state : std::sync::Arc<std::sync::Mutex<ApplicationState>>,
+                     barrier           : std::sync::Arc<(std::sync::Mutex<u8>, std::sync::Condvar)>,
+                     #[cfg(feature = "migration_log")]
+                     start_send        : libc::timespec)
+{
+    let request_id = request.get_id ();
+
+    #[cfg(feature = "print_log")]
+    println! ("negotiation - SENDING request {} to {}", request_id, dst);
+
+    data_server.send (request_id, dst, dest_node, move |sent|
+        {
+            let request_dir = format! ("requests/{}", request_id.folder_name ());
+            if let Err (error) = sent
+            {
+                eprintln! ("negotiation - unable to send request {}: {}", request_id, error);
+
+                // The request has not left the node: serve it again.
+                if let Err (error) = request_transfer::remove_last_lineage_hop (&request_dir)
+                {
+                    eprintln! ("negotiation - unable to restore the lineage of request {}: {}", request_id, error);
+                }
+                application_state.lock ().unwrap ().requeue_request (request);
+                let (number_of_requests, barrier) = &*barrier;
+                *number_of_requests.lock ().unwrap () += 1;
+                barrier.notify_all ();
+                return;
+            }
+
+            #[cfg(feature = "print_log")]
+            println! ("negotiation - request {} SENT", request_id);
+
+            // Until then, a crash leaves the request to be served again
+            // by this node (see journal::recover).
+            application_state.lock ().unwrap ().confirm_transfer (request_id);
+
+            // Remove the directory corresponding to the request.
+            if let Err (error) = std::fs::remove_dir_all (&request_dir)
+            {
+                eprintln! ("negotiation - unable to remove {}: {}", request_dir, error);
+            }
+
+            #[cfg(feature = "migration_log")]
+            {
+                let send_time = crate::linux_utils::get_completion_time (start_send);
+                crate::log_writer::save_send_time (send_time);
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn request_not_sent_is_kept_and_queued_again ()
+    {
+        // A request of an application that no node hosts, so that
+        // its folder does not collide with the ones of the repository.
+        let request_id  = RequestId::new (0, 97, 0);
+        let request_dir = format! ("requests/{}", request_id.folder_name ());
+        std::fs::create_dir_all (&request_dir).unwrap ();
+        std::fs::write (format! ("{}/manifest.toml", request_dir),
+                        "version = 1\n\n[request]\nindex = 0\napplication_index = 97\n\
+                         execution_time = 200\ndesired_completion_time = 250\nmigratable_up_to = 22\n\
+                         required_memory = 128\ndesired_coord = [1.0, 2.0]\nthreshold = 1.5\n\
+                         module = \"module.wasm\"\ndata_files = []\n").unwrap ();
+        std::fs::write (format! ("{}/module.wasm", request_dir), b"\0asm").unwrap ();
+
+        let request = Request::new_from (request_id, 200, 250, 22, 128, Attributes::new (), 1.5, 0);
+        let application_state = std::sync::Arc::new (std::sync::Mutex::new (
+            ApplicationState::new (0, Attributes::new (), 100, 50, 1.0, 1024)));
+        let barrier = std::sync::Arc::new ((std::sync::Mutex::new (0), std::sync::Condvar::new ()));
+
+        // The request leaves the node as in the coordination loops.
+        {
+            let mut state = application_state.lock ().unwrap ();
+            state.add_request (request);
+            state.set_request_state (request_id, RequestState::InTransit).unwrap ();
+            let hop = state.get_lineage_hop (request_id, RequestState::Migrated).unwrap ();
+            state.remove_request (request_id, RequestState::Migrated).unwrap ();
+            request_transfer::append_lineage (&request_dir, &hop).unwrap ();
+        }
+
+        // Nobody listens on the port of the destination.
+        let closed = std::net::TcpListener::bind ("127.0.0.1:0").unwrap ().local_addr ().unwrap ();
+        let data_server = DataServer::start ("127.0.0.1:0", None).unwrap ();
+        send_request (request,
+                      &data_server,
+                      closed.to_string (),
+                      1,
+                      application_state.clone (),
+                      barrier.clone (),
+                      #[cfg(feature = "migration_log")]
+                      libc::timespec { tv_sec: 0, tv_nsec: 0 });
+        assert! (data_server.wait_sent (std::time::Duration::from_secs (5)));
+
+        let kept    = std::path::Path::new (&request_dir).join ("module.wasm").is_file ();
+        let lineage = std::fs::read_to_string (format! ("{}/{}", request_dir, request_transfer::LINEAGE_FILE_NAME));
+        std::fs::remove_dir_all (&request_dir).unwrap ();
+
+        assert! (kept);
+        assert_eq! (lineage.unwrap (), "");
+        let state = application_state.lock ().unwrap ();
+        assert_eq! (state.get_request_state (request_id), Some (RequestState::Queued));
+        assert_eq! (state.get_served_request (), Some (request_id));
+        assert_eq! (*barrier.0.lock ().unwrap (), 1);
+    }
+}
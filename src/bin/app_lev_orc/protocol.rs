@@ -0,0 +1,701 @@
+/***************************************/
+/*            WIRE PROTOCOL            */
+/***************************************/
+
+// The messages exchanged through MQTT, and their encoding. Each
+// payload starts with the version of the protocol, checked before
+// the message itself is decoded, so that nodes running different
+// versions reject each other's messages instead of misreading them.
+// A payload is encoded either in JSON (readable, e.g. with
+// mosquitto_sub):
+//
+//   {"version":8,"message":{"region":{"request":{...},"region":5}}}
+//
+// or in a compact binary form: the magic bytes 0xA1 0x0C, the version
+// (u16, big endian), then the message serialized with postcard. A
+// node decodes both, whatever the encoding it sends with.
+
+use serde::{Deserialize, Serialize};
+use crate::state::{NodeState, ParseStateError, Request, RequestId};
+
+/// Version of the protocol, to be increased on any change
+/// of the messages below.
+pub const PROTOCOL_VERSION : u16 = 8;
+
+/// First bytes of a binary payload.
+const BINARY_MAGIC : [u8; 2] = [0xA1, 0x0C];
+
+/// Error raised when decoding a payload received through MQTT.
+#[derive(Debug)]
+pub enum ProtocolError
+{
+    /// The payload is encoded with another version of the protocol.
+    UnsupportedVersion { found : u16, supported : u16 },
+
+    /// The payload does not carry a version (e.g. a text payload
+    /// published by hand).
+    Unversioned,
+
+    /// The message cannot be decoded.
+    Malformed (String),
+
+    /// The message is decoded, but its content is not acceptable.
+    Invalid (ParseStateError),
+
+    /// The message is not the one expected on the topic.
+    Unexpected { expected : &'static str, found : &'static str },
+}
+
+impl std::fmt::Display for ProtocolError
+{
+    fn fmt (&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            ProtocolError::UnsupportedVersion { found, supported } =>
+                write! (f, "protocol version {} is not supported (expected {})", found, supported),
+            ProtocolError::Unversioned =>
+                write! (f, "the payload carries no protocol version"),
+            ProtocolError::Malformed (error) =>
+                write! (f, "malformed message: {}", error),
+            ProtocolError::Invalid (error) =>
+                write! (f, "invalid message: {}", error),
+            ProtocolError::Unexpected { expected, found } =>
+                write! (f, "expected a {} message, found a {} one", expected, found),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ParseStateError> for ProtocolError
+{
+    fn from (error : ParseStateError) -> Self
+    {
+        ProtocolError::Invalid (error)
+    }
+}
+
+/// Encoding of the payloads sent by a node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding
+{
+    Json,
+    Binary,
+}
+
+impl std::str::FromStr for Encoding
+{
+    type Err = String;
+
+    /// The expected string: json or binary.
+    fn from_str (s: &str) -> Result<Self, Self::Err>
+    {
+        match s
+        {
+            "json"   => Ok (Encoding::Json),
+            "binary" => Ok (Encoding::Binary),
+            _        => Err (format! ("unknown encoding '{}' (expected json or binary)", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Encoding
+{
+    fn fmt (&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            Encoding::Json   => write! (f, "json"),
+            Encoding::Binary => write! (f, "binary"),
+        }
+    }
+}
+
+/// The request to migrate, sent by the node hosting it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageRequest
+{
+    src     : usize,
+    request : Request,
+
+    /// The nodes taking part in the negotiation, by increasing index:
+    /// the live members seen by the src node, so that all the nodes
+    /// size and index the ADMM alike.
+    members : Vec<usize>,
+
+    /// Memory the request needs in kB: the larger of its declared
+    /// memory and of the memory measured on the src node.
+    memory  : u32,
+}
+
+impl MessageRequest
+{
+    pub fn new (src: usize, request : Request, members : Vec<usize>, memory : u32) -> Self
+    {
+        Self { src, request, members, memory }
+    }
+
+    pub fn get_members (&self) -> &[usize]
+    {
+        &self.members
+    }
+
+    /// Check that the members are sorted, without duplicates.
+    fn check (&self) -> Result<(), ParseStateError>
+    {
+        self.request.check ()?;
+        if self.members.is_empty () || self.members.windows (2).any (|pair| pair[0] >= pair[1])
+        {
+            return Err (ParseStateError::InvalidField { what  : "MessageRequest",
+                                                        field : "members",
+                                                        value : format! ("{:?}", self.members) });
+        }
+        Ok (())
+    }
+
+    pub fn get_src (&self) -> usize
+    {
+        self.src
+    }
+
+    pub fn get_request (&self) -> &Request
+    {
+        &self.request
+    }
+
+    pub fn get_memory (&self) -> u32
+    {
+        self.memory
+    }
+}
+
+/// This is the message sent through MQTT containing
+/// the local update in the ADMM algorithm.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct MessageLocal
+{
+    pub src       : usize,
+
+    /// The request being negotiated.
+    pub request   : RequestId,
+
+    /// The round of the ADMM the update belongs to.
+    pub iteration : usize,
+    pub local_sum : f32,
+}
+
+/// The message sent by the src node of a negotiation when a round
+/// times out (distributed coordination), on the topic of the local
+/// updates: every node ends the round with the local updates received
+/// before it.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct MessageRoundEnd
+{
+    /// The request being negotiated.
+    pub request   : RequestId,
+
+    /// The round of the ADMM to end.
+    pub iteration : usize,
+}
+
+/// The message sent by the controller to a node (centralized
+/// coordination): either the new global value, the destination
+/// of the request once the ADMM has terminated, or the abort of
+/// the negotiation when too few nodes replied in a round.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageGlobal
+{
+    Update { request : RequestId, global : f32 },
+    Dest { request : RequestId, node : usize },
+    Abort { request : RequestId },
+}
+
+impl MessageGlobal
+{
+    /// The request being negotiated.
+    pub fn get_request (&self) -> RequestId
+    {
+        match self
+        {
+            MessageGlobal::Update { request, .. }
+            | MessageGlobal::Dest { request, .. }
+            | MessageGlobal::Abort { request } => *request,
+        }
+    }
+}
+
+/// The message sent by the node hosting a request to its
+/// destination: the region the request resumes from.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct MessageRegion
+{
+    pub request : RequestId,
+    pub region  : usize,
+}
+
+/// The message sent by the destination of a request to the node
+/// hosting it: the address (ip:port) to send the request to.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct MessageAddress
+{
+    pub request : RequestId,
+    pub address : String,
+}
+
+/// The message published when a request hosted by a node
+/// misses its desired completion time.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct MessageDeadlineMiss
+{
+    pub node     : usize,
+    pub request  : RequestId,
+
+    /// Time elapsed since the deadline, in ms.
+    pub lateness : u128,
+}
+
+/// The message published by a node on the presence topic: a
+/// heartbeat while it is alive, with the applications it hosts,
+/// then its leave (on shutdown, or as its will message).
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct MessagePresence
+{
+    pub node         : usize,
+    pub applications : Vec<usize>,
+    pub alive        : bool,
+}
+
+/// A message exchanged through MQTT, with the topic it is published on.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Message
+{
+    /// federation/app_N/migration
+    Migration (MessageRequest),
+
+    /// federation/app_N/local_update
+    LocalUpdate (MessageLocal),
+
+    /// federation/app_N/local_update
+    RoundEnd (MessageRoundEnd),
+
+    /// federation/app_N/global_update/i
+    GlobalUpdate (MessageGlobal),
+
+    /// federation/app_N/dst/i
+    Region (MessageRegion),
+
+    /// federation/app_N/src/i
+    Address (MessageAddress),
+
+    /// federation/app_N/deadline_miss
+    DeadlineMiss (MessageDeadlineMiss),
+
+    /// node_state_i
+    NodeState (NodeState),
+
+    /// federation/presence
+    Presence (MessagePresence),
+}
+
+impl Message
+{
+    /// Name of the message, for the logs.
+    pub fn name (&self) -> &'static str
+    {
+        match self
+        {
+            Message::Migration (_)    => "migration",
+            Message::LocalUpdate (_)  => "local_update",
+            Message::RoundEnd (_)     => "round_end",
+            Message::GlobalUpdate (_) => "global_update",
+            Message::Region (_)       => "region",
+            Message::Address (_)      => "address",
+            Message::DeadlineMiss (_) => "deadline_miss",
+            Message::NodeState (_)    => "node_state",
+            Message::Presence (_)     => "presence",
+        }
+    }
+
+    /// Check that the content of the message is acceptable (e.g. no
+    /// NaN, which JSON cannot carry, but the binary encoding can).
+    fn check (&self) -> Result<(), ParseStateError>
+    {
+        let finite = |what, field, value : f32|
+            if value.is_finite () { Ok (()) }
+            else { Err (ParseStateError::OutOfRange { what, field, value : value.to_string () }) };
+        match self
+        {
+            Message::Migration (message)                                => message.check (),
+            Message::LocalUpdate (message)                              => finite ("MessageLocal", "local_sum", message.local_sum),
+            Message::GlobalUpdate (MessageGlobal::Update { global, .. }) => finite ("MessageGlobal", "global", *global),
+            Message::NodeState (node_state)                             => node_state.check (),
+            _                                                           => Ok (()),
+        }
+    }
+}
+
+/// A message with its own variant in Message, so that
+/// the message expected on a topic is decoded directly.
+pub trait Payload : Sized
+{
+    const NAME : &'static str;
+
+    fn into_message (self) -> Message;
+
+    fn from_message (message : Message) -> Option<Self>;
+}
+
+macro_rules! payload
+{
+    ($type:ty, $variant:ident, $name:literal) =>
+    {
+        impl Payload for $type
+        {
+            const NAME : &'static str = $name;
+
+            fn into_message (self) -> Message
+            {
+                Message::$variant (self)
+            }
+
+            fn from_message (message : Message) -> Option<Self>
+            {
+                match message
+                {
+                    Message::$variant (payload) => Some (payload),
+                    _                           => None,
+                }
+            }
+        }
+    };
+}
+
+payload! (MessageRequest,      Migration,    "migration");
+payload! (MessageLocal,        LocalUpdate,  "local_update");
+payload! (MessageRoundEnd,     RoundEnd,     "round_end");
+payload! (MessageGlobal,       GlobalUpdate, "global_update");
+payload! (MessageRegion,       Region,       "region");
+payload! (MessageAddress,      Address,      "address");
+payload! (MessageDeadlineMiss, DeadlineMiss, "deadline_miss");
+payload! (NodeState,           NodeState,    "node_state");
+payload! (MessagePresence,     Presence,     "presence");
+
+/// The version header of a JSON payload.
+#[derive(Deserialize)]
+struct Header
+{
+    version : u16,
+}
+
+/// A JSON payload.
+#[derive(Serialize, Deserialize)]
+struct Envelope<M>
+{
+    version : u16,
+    message : M,
+}
+
+/// Encode `message' with `encoding'.
+pub fn encode (message : &Message, encoding : Encoding) -> Vec<u8>
+{
+    match encoding
+    {
+        Encoding::Json =>
+            serde_json::to_vec (&Envelope { version : PROTOCOL_VERSION, message })
+                .expect ("A message is always serializable to JSON"),
+        Encoding::Binary =>
+            {
+                let mut payload = BINARY_MAGIC.to_vec ();
+                payload.extend_from_slice (&PROTOCOL_VERSION.to_be_bytes ());
+                postcard::to_extend (message, payload)
+                    .expect ("A message is always serializable to postcard")
+            }
+    }
+}
+
+/// Decode a payload in either encoding, then check its content.
+pub fn decode_message (payload : &[u8]) -> Result<Message, ProtocolError>
+{
+    let check_version = |found : u16|
+        if found == PROTOCOL_VERSION { Ok (()) }
+        else { Err (ProtocolError::UnsupportedVersion { found, supported : PROTOCOL_VERSION }) };
+
+    let message = if let Some (rest) = payload.strip_prefix (&BINARY_MAGIC)
+    {
+        let (version, body) = rest.split_first_chunk::<2> ().ok_or (ProtocolError::Unversioned)?;
+        check_version (u16::from_be_bytes (*version))?;
+        postcard::from_bytes::<Message> (body)
+            .map_err (|error| ProtocolError::Malformed (error.to_string ()))?
+    }
+    else if payload.trim_ascii_start ().starts_with (b"{")
+    {
+        let header : Header = serde_json::from_slice (payload)
+            .map_err (|error| if error.is_data () { ProtocolError::Unversioned }
+                              else { ProtocolError::Malformed (error.to_string ()) })?;
+        check_version (header.version)?;
+        serde_json::from_slice::<Envelope<Message>> (payload)
+            .map_err (|error| ProtocolError::Malformed (error.to_string ()))?
+            .message
+    }
+    else
+    {
+        return Err (ProtocolError::Unversioned);
+    };
+
+    message.check ()?;
+    Ok (message)
+}
+
+/// Decode a payload expected to carry a `T' message.
+pub fn decode<T : Payload> (payload : &[u8]) -> Result<T, ProtocolError>
+{
+    let message = decode_message (payload)?;
+    let found = message.name ();
+    T::from_message (message).ok_or (ProtocolError::Unexpected { expected : T::NAME, found })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::state::{Attributes, Coord};
+
+    fn node_state () -> NodeState
+    {
+        let mut node_state : NodeState = "[(0,0);1.5]".parse ().unwrap ();
+        node_state.set_attributes (attributes ());
+        node_state
+    }
+
+    /// The fields of `request' but its age, which keeps running.
+    fn without_age (request : &Request) -> String
+    {
+        let request = request.to_string ();
+        request.rsplit_once (';').unwrap ().0.to_string ()
+    }
+
+    const ENCODINGS : [Encoding; 2] = [Encoding::Json, Encoding::Binary];
+
+    fn attributes () -> Attributes
+    {
+        let mut attributes = Attributes::new_from (Coord::new_from_3d (1.5, -2.0, 3.25));
+        attributes.battery = Some (0.5);
+        attributes.cpu_load = Some (1.0);
+        attributes
+    }
+
+    fn id () -> RequestId
+    {
+        RequestId::new (2, 1, 7)
+    }
+
+    fn region () -> Message
+    {
+        Message::Region (MessageRegion { request : id (), region : 5 })
+    }
+
+    fn request () -> Request
+    {
+        let mut request = Request::new_from (id (), 400, 1_000, 3, 64, attributes (), 0.75, 2);
+        request.set_age (std::time::Duration::from_millis (5_000));
+        request
+    }
+
+    /// Encode `payload', decode it and return the result.
+    fn round_trip<T : Payload> (payload : T, encoding : Encoding) -> T
+    {
+        let encoded = encode (&payload.into_message (), encoding);
+        decode::<T> (&encoded).unwrap_or_else (|error| panic! ("{} ({}): {}", T::NAME, encoding, error))
+    }
+
+    #[test]
+    fn migration_round_trip ()
+    {
+        for encoding in ENCODINGS
+        {
+            let sent = MessageRequest::new (2, request (), vec![0, 2, 5], 640);
+            let received = round_trip (MessageRequest::new (2, request (), vec![0, 2, 5], 640), encoding);
+            assert_eq! (received.get_src (), sent.get_src ());
+            assert_eq! (received.get_members (), sent.get_members ());
+            assert_eq! (received.get_memory (), sent.get_memory ());
+
+            let (sent, received) = (sent.get_request (), received.get_request ());
+            assert_eq! (without_age (received), without_age (sent));
+
+            // The age is carried in ms, and keeps running meanwhile.
+            assert! (received.get_age () >= std::time::Duration::from_millis (5_000));
+            assert! (received.get_age () < std::time::Duration::from_millis (6_000));
+        }
+    }
+
+    #[test]
+    fn request_age_round_trip ()
+    {
+        for encoding in ENCODINGS
+        {
+            for age in [0, 1, 249, 60_000]
+            {
+                let mut sent = request ();
+                sent.set_age (std::time::Duration::from_millis (age));
+                let received = round_trip (MessageRequest::new (2, sent, vec![2], 64), encoding);
+
+                // The deadline is kept, up to the time taken meanwhile.
+                let received = received.get_request ();
+                let (earliest, latest) = if received.get_deadline () < sent.get_deadline ()
+                    { (received.get_deadline (), sent.get_deadline ()) }
+                else
+                    { (sent.get_deadline (), received.get_deadline ()) };
+                assert! (received.get_age () >= std::time::Duration::from_millis (age), "{} ({} ms)", encoding, age);
+                assert! (latest - earliest < std::time::Duration::from_millis (100), "{} ({} ms)", encoding, age);
+            }
+        }
+    }
+
+    #[test]
+    fn local_update_round_trip ()
+    {
+        for encoding in ENCODINGS
+        {
+            let message = || MessageLocal { src : 3, request : id (), iteration : 2, local_sum : -0.125 };
+            assert_eq! (round_trip (message (), encoding), message ());
+        }
+    }
+
+    #[test]
+    fn round_end_round_trip ()
+    {
+        for encoding in ENCODINGS
+        {
+            let message = || MessageRoundEnd { request : id (), iteration : 3 };
+            assert_eq! (round_trip (message (), encoding), message ());
+        }
+    }
+
+    #[test]
+    fn global_update_round_trip ()
+    {
+        for encoding in ENCODINGS
+        {
+            let update = || MessageGlobal::Update { request : id (), global : 4.5 };
+            assert_eq! (round_trip (update (), encoding), update ());
+            let dest = || MessageGlobal::Dest { request : id (), node : 9 };
+            assert_eq! (round_trip (dest (), encoding), dest ());
+            let abort = || MessageGlobal::Abort { request : id () };
+            assert_eq! (round_trip (abort (), encoding), abort ());
+        }
+    }
+
+    #[test]
+    fn region_round_trip ()
+    {
+        for encoding in ENCODINGS
+        {
+            let message = || MessageRegion { request : id (), region : 5 };
+            assert_eq! (round_trip (message (), encoding), message ());
+        }
+    }
+
+    #[test]
+    fn address_round_trip ()
+    {
+        for encoding in ENCODINGS
+        {
+            let message = || MessageAddress { request : id (), address : "10.0.0.2:8080".to_string () };
+            assert_eq! (round_trip (message (), encoding), message ());
+        }
+    }
+
+    #[test]
+    fn deadline_miss_round_trip ()
+    {
+        for encoding in ENCODINGS
+        {
+            let message = || MessageDeadlineMiss { node : 1, request : RequestId::new (0, 2, 11), lateness : 1_234 };
+            assert_eq! (round_trip (message (), encoding), message ());
+        }
+    }
+
+    #[test]
+    fn presence_round_trip ()
+    {
+        for encoding in ENCODINGS
+        {
+            let message = || MessagePresence { node : 4, applications : vec![0, 2], alive : true };
+            assert_eq! (round_trip (message (), encoding), message ());
+        }
+    }
+
+    #[test]
+    fn node_state_round_trip ()
+    {
+        for encoding in ENCODINGS
+        {
+            assert_eq! (round_trip (node_state (), encoding), node_state ());
+        }
+    }
+
+    #[test]
+    fn json_is_versioned ()
+    {
+        let encoded = encode (&region (), Encoding::Json);
+        assert_eq! (String::from_utf8 (encoded).unwrap (),
+                    r#"{"version":8,"message":{"region":{"request":{"origin":2,"application":1,"sequence":7},"region":5}}}"#);
+    }
+
+    #[test]
+    fn other_versions_are_rejected ()
+    {
+        // Version 1 had no request in the region message.
+        let json = br#"{"version":1,"message":{"region":{"region":5}}}"#;
+        assert! (matches! (decode_message (json), Err (ProtocolError::UnsupportedVersion { found : 1, supported : 8 })));
+
+        let mut binary = encode (&region (), Encoding::Binary);
+        binary[2..4].copy_from_slice (&3u16.to_be_bytes ());
+        assert! (matches! (decode_message (&binary), Err (ProtocolError::UnsupportedVersion { found : 3, supported : 8 })));
+
+        // The version is checked before the message: an unknown
+        // message of another version is reported as such.
+        let json = br#"{"version":9,"message":{"handover":{}}}"#;
+        assert! (matches! (decode_message (json), Err (ProtocolError::UnsupportedVersion { .. })));
+    }
+
+    #[test]
+    fn unversioned_payloads_are_rejected ()
+    {
+        assert! (matches! (decode_message (b"0#1.5"), Err (ProtocolError::Unversioned)));
+        assert! (matches! (decode_message (br#"{"message":{"region":{"region":5}}}"#), Err (ProtocolError::Unversioned)));
+        assert! (matches! (decode_message (&BINARY_MAGIC), Err (ProtocolError::Unversioned)));
+    }
+
+    #[test]
+    fn invalid_messages_are_rejected ()
+    {
+        for encoding in ENCODINGS
+        {
+            let encoded = encode (&region (), encoding);
+            assert! (matches! (decode::<MessageLocal> (&encoded),
+                               Err (ProtocolError::Unexpected { expected : "local_update", found : "region" })));
+        }
+
+        // NaN can be carried by the binary encoding only.
+        let encoded = encode (&Message::LocalUpdate (MessageLocal { src : 0, request : id (), iteration : 0, local_sum : f32::NAN }), Encoding::Binary);
+        assert! (matches! (decode_message (&encoded), Err (ProtocolError::Invalid (_))));
+        let encoded = encode (&Message::GlobalUpdate (MessageGlobal::Update { request : id (), global : f32::INFINITY }), Encoding::Json);
+        assert! (matches! (decode_message (&encoded), Err (ProtocolError::Malformed (_))));
+
+        let mut node_state = node_state ();
+        node_state.set_attributes (Attributes { battery : Some (1.5), ..attributes () });
+        let encoded = encode (&Message::NodeState (node_state), Encoding::Json);
+        assert! (matches! (decode_message (&encoded), Err (ProtocolError::Invalid (_))));
+
+        // The members of a negotiation index its ADMM.
+        let encoded = encode (&Message::Migration (MessageRequest::new (2, request (), vec![2, 0], 640)), Encoding::Json);
+        assert! (matches! (decode_message (&encoded), Err (ProtocolError::Invalid (_))));
+
+        let encoded = encode (&region (), Encoding::Binary);
+        assert! (matches! (decode_message (&encoded[..encoded.len () - 1]), Err (ProtocolError::Malformed (_))));
+    }
+}
@@ -0,0 +1,583 @@
+/****************************************/
+/*           REQUEST TRANSFER           */
+/****************************************/
+
+// Utilities shared by the coordination loops to move the
+// folder of a request (manifest, module, data files and
+// memories) from the source node to the destination node.
+
+use std::io::{Read, Write};
+use crate::configuration_loader::RequestManifest;
+use crate::state::{LineageHop, RequestId};
+use crate::tls::{self, Tls};
+
+/// Memories produced by a checkpoint, they are optional.
+#[cfg_attr(feature = "no_live_migration", allow(dead_code))]
+const MEMORY_FILES : [&str; 2] = ["main_memory.b", "checkpoint_memory.b"];
+
+/// Lineage of a request within its folder: one LineageHop per
+/// line, for each node the request has left.
+pub const LINEAGE_FILE_NAME : &str = "lineage.txt";
+
+fn zip_error (error : zip::result::ZipError) -> std::io::Error
+{
+    std::io::Error::other (error)
+}
+
+/// Compress the folder of a request in requests/{app}_{origin}_{sequence}_req.zip,
+/// including the files listed in its manifest and its lineage.
+pub fn compress_request (request_id : RequestId) -> std::io::Result<std::path::PathBuf>
+{
+    let path_to_req_dir = format! ("requests/{}", request_id.folder_name ());
+    let manifest = RequestManifest::load (&path_to_req_dir, request_id)
+        .map_err (std::io::Error::other)?;
+
+    let zip_archive_path =
+        std::path::PathBuf::from (format! ("requests/{}.zip", request_id.folder_name ()));
+    let zip_archive = std::fs::File::create (&zip_archive_path)?;
+    let mut zip = zip::ZipWriter::new (zip_archive);
+
+    // The files that might be compressed (memories are optional).
+    #[allow(unused_mut)]
+    let mut files_to_compress : Vec<String> = manifest.files_to_transfer ();
+    files_to_compress.push (LINEAGE_FILE_NAME.to_string ());
+    #[cfg(not(feature = "no_live_migration"))]
+    files_to_compress.extend (MEMORY_FILES.iter ().map (|file| file.to_string ()));
+
+    let options: zip::write::FileOptions<()> = zip::write::FileOptions::default ()
+        .compression_method (zip::CompressionMethod::DEFLATE);
+
+    for file_name in &files_to_compress
+    {
+        let file = std::fs::File::open (format! ("{}/{}", path_to_req_dir, file_name));
+        match file
+        {
+            Ok (mut file) =>
+                {
+                    #[cfg(feature = "print_log")]
+                    println! ("request_transfer - COMPRESSING = {}", file_name);
+
+                    zip.start_file (file_name.as_str (), options).map_err (zip_error)?;
+
+                    let mut buffer = Vec::new ();
+                    file.read_to_end (&mut buffer)?;
+
+                    zip.write_all (&buffer)?;
+                }
+            Err (_) =>
+                {
+                    // The file is missing, proceed.
+                    continue;
+                }
+        }
+    }
+    zip.finish ().map_err (zip_error)?;
+
+    Ok (zip_archive_path)
+}
+
+/// Maximum length of the header identifying the request.
+const MAX_HEADER_LEN : usize = 64;
+
+/// Maximum wait for the next bytes of a transfer.
+const READ_TIMEOUT : std::time::Duration = std::time::Duration::from_secs (10);
+
+/// A connection of the data plane, over TLS if configured.
+enum DataStream
+{
+    Plain (std::net::TcpStream),
+    Tls (Box<openssl::ssl::SslStream<std::net::TcpStream>>),
+}
+
+impl DataStream
+{
+    /// Close the connection, once the archive is sent.
+    fn close (&mut self) -> std::io::Result<()>
+    {
+        match self
+        {
+            DataStream::Plain (stream) => stream.shutdown (std::net::Shutdown::Both),
+            DataStream::Tls (stream) =>
+                {
+                    stream.shutdown ().map_err (|e| std::io::Error::other (e.to_string ()))?;
+                    stream.get_ref ().shutdown (std::net::Shutdown::Both)
+                }
+        }
+    }
+}
+
+impl Read for DataStream
+{
+    fn read (&mut self, buffer : &mut [u8]) -> std::io::Result<usize>
+    {
+        match self
+        {
+            DataStream::Plain (stream) => stream.read (buffer),
+            DataStream::Tls (stream) => stream.read (buffer),
+        }
+    }
+}
+
+impl Write for DataStream
+{
+    fn write (&mut self, buffer : &[u8]) -> std::io::Result<usize>
+    {
+        match self
+        {
+            DataStream::Plain (stream) => stream.write (buffer),
+            DataStream::Tls (stream) => stream.write (buffer),
+        }
+    }
+
+    fn flush (&mut self) -> std::io::Result<()>
+    {
+        match self
+        {
+            DataStream::Plain (stream) => stream.flush (),
+            DataStream::Tls (stream) => stream.flush (),
+        }
+    }
+}
+
+/// Send the archive of `request_id' on `writer'. The archive is
+/// preceded by the identifier of the request and a newline, so
+/// that the server can route it.
+fn send_archive (zip_archive_path : &std::path::Path, request_id : RequestId, writer : &mut DataStream) -> std::io::Result<()>
+{
+    writer.write_all (format! ("{}\n", request_id).as_bytes ())?;
+    let mut buffer = [0; 512];
+    let mut compressed_file =
+        std::fs::OpenOptions::new ()
+            .read (true)
+            .open (zip_archive_path)?;
+    loop
+    {
+        let n = compressed_file.read (&mut buffer)?;
+        if n == 0
+        {
+            writer.close ()?;
+            break;
+        }
+        writer.write_all (&buffer[..n])?;
+    }
+    Ok (())
+}
+
+/// Called once the archive of a request is received, with its
+/// path, or once its transfer has failed.
+pub type OnReceived = Box<dyn FnOnce (std::io::Result<std::path::PathBuf>) + Send>;
+
+/// Server of the data plane of a node, shared by the applications
+/// it hosts. A dedicated thread accepts the connections all the time,
+/// each served by its own thread, so that many transfers run at once.
+/// Each connection carries the archive of one request, identified by
+/// its header, and is only accepted if the request is expected. With
+/// TLS, the archive is only accepted from the node expected to send
+/// it, and only sent to the node expected to receive it.
+pub struct DataServer
+{
+    /// The transfers expected, by request, with their token (see
+    /// expect) and their source node.
+    expected : std::sync::Mutex<std::collections::HashMap<RequestId, (u64, usize, OnReceived)>>,
+
+    /// Token of the next transfer expected.
+    tokens   : std::sync::atomic::AtomicU64,
+
+    /// Number of archives being sent.
+    sending  : std::sync::atomic::AtomicUsize,
+
+    /// TLS contexts, if configured.
+    tls      : Option<Tls>,
+}
+
+impl DataServer
+{
+    /// Bind the server to `ip_and_port', and start accepting.
+    pub fn start (ip_and_port : &str, tls : Option<Tls>) -> std::io::Result<std::sync::Arc<Self>>
+    {
+        let listener = std::net::TcpListener::bind (ip_and_port)?;
+        let server = std::sync::Arc::new (Self
+        {
+            expected : std::sync::Mutex::new (std::collections::HashMap::new ()),
+            tokens   : std::sync::atomic::AtomicU64::new (0),
+            sending  : std::sync::atomic::AtomicUsize::new (0),
+            tls,
+        });
+
+        let accepting = server.clone ();
+        std::thread::spawn (move ||
+            {
+                for stream in listener.incoming ()
+                {
+                    match stream
+                    {
+                        Ok (stream) =>
+                            {
+                                let server = accepting.clone ();
+                                std::thread::spawn (move || server.serve (stream));
+                            }
+                        Err (e) => eprintln! ("request_transfer - connection failed: {e}"),
+                    }
+                }
+            });
+        Ok (server)
+    }
+
+    /// Expect the archive of `request_id' from `src_node', then call
+    /// `on_received'. A transfer not started within `timeout' fails,
+    /// unless the request is expected again meanwhile (e.g. it is
+    /// negotiated again after a failed transfer): each expectation has
+    /// its own token, so that its timeout leaves the next one alone.
+    pub fn expect (self        : &std::sync::Arc<Self>,
+                   request_id  : RequestId,
+                   src_node    : usize,
+                   timeout     : std::time::Duration,
+                   on_received : OnReceived)
+    {
+        let token = self.tokens.fetch_add (1, std::sync::atomic::Ordering::SeqCst);
+        self.expected.lock ().unwrap ().insert (request_id, (token, src_node, on_received));
+
+        let server = self.clone ();
+        std::thread::spawn (move ||
+            {
+                std::thread::sleep (timeout);
+                let expected =
+                {
+                    let mut expected = server.expected.lock ().unwrap ();
+                    match expected.get (&request_id)
+                    {
+                        Some (&(current, _, _)) if current == token => expected.remove (&request_id),
+                        _ => None,
+                    }
+                };
+                if let Some ((_, _, on_received)) = expected
+                {
+                    on_received (Err (std::io::Error::new (std::io::ErrorKind::TimedOut, "transfer not started")));
+                }
+            });
+    }
+
+    /// Receive the archive sent on `stream', if expected, in the
+    /// file named after its request.
+    fn serve (&self, stream : std::net::TcpStream)
+    {
+        let accepted = stream.set_read_timeout (Some (READ_TIMEOUT))
+            .and_then (|_| match &self.tls
+            {
+                Some (tls) => tls.accept (stream)
+                    .map (|(stream, peer)| (DataStream::Tls (Box::new (stream)), Some (peer))),
+                None => Ok ((DataStream::Plain (stream), None)),
+            });
+        let (mut stream, peer) = match accepted
+        {
+            Ok (accepted) => accepted,
+            Err (e) =>
+                {
+                    eprintln! ("request_transfer - connection refused: {e}");
+                    return;
+                }
+        };
+        let request_id = match read_header (&mut stream)
+        {
+            Ok (request_id) => request_id,
+            Err (e) =>
+                {
+                    eprintln! ("request_transfer - invalid connection: {e}");
+                    return;
+                }
+        };
+
+        // With TLS, only the source of the request may send it.
+        let on_received =
+        {
+            let mut expected = self.expected.lock ().unwrap ();
+            let authorized = match (expected.get (&request_id), &peer)
+            {
+                (None, _) =>
+                    {
+                        eprintln! ("request_transfer - unexpected transfer of request {}", request_id);
+                        return;
+                    }
+                (Some (&(_, src_node, _)), Some (peer)) => tls::check_peer (peer, src_node),
+                (Some (_), None) => Ok (()),
+            };
+            if let Err (e) = authorized
+            {
+                eprintln! ("request_transfer - transfer of request {} REJECTED: {}", request_id, e);
+                return;
+            }
+            expected.remove (&request_id).expect ("Missing transfer").2
+        };
+
+        #[cfg(feature = "print_log")]
+        println! ("request_transfer - RECEIVING request {}", request_id);
+
+        let compressed_file_name = std::path::PathBuf::from (format! ("{}.zip", request_id.folder_name ()));
+        on_received (receive_archive (&mut stream, &compressed_file_name).map (|_| compressed_file_name));
+    }
+
+    /// Compress the folder of `request_id' and send it to the data
+    /// server of `dst_node' at `dst' in the background, then call
+    /// `on_sent'.
+    pub fn send (self       : &std::sync::Arc<Self>,
+                 request_id : RequestId,
+                 dst        : String,
+                 dst_node   : usize,
+                 on_sent    : impl FnOnce (std::io::Result<()>) + Send + 'static)
+    {
+        self.sending.fetch_add (1, std::sync::atomic::Ordering::SeqCst);
+        let server = self.clone ();
+        std::thread::spawn (move ||
+            {
+                let sent = compress_request (request_id)
+                    .and_then (|zip_archive_path|
+                        {
+                            let sent = server.connect (&dst, dst_node)
+                                .and_then (|mut writer| send_archive (&zip_archive_path, request_id, &mut writer));
+                            std::fs::remove_file (zip_archive_path).and (sent)
+                        });
+                on_sent (sent);
+                server.sending.fetch_sub (1, std::sync::atomic::Ordering::SeqCst);
+            });
+    }
+
+    /// Connect to the data server of `dst_node' at `dst'. With TLS,
+    /// the server must prove to be `dst_node'.
+    fn connect (&self, dst : &str, dst_node : usize) -> std::io::Result<DataStream>
+    {
+        let stream = std::net::TcpStream::connect (dst)?;
+        match &self.tls
+        {
+            Some (tls) =>
+                {
+                    let (stream, peer) = tls.connect (stream)?;
+                    tls::check_peer (&peer, dst_node)?;
+                    Ok (DataStream::Tls (Box::new (stream)))
+                }
+            None => Ok (DataStream::Plain (stream)),
+        }
+    }
+
+    /// Wait, for at most `timeout', until no archive is being sent.
+    /// Return whether none is.
+    pub fn wait_sent (&self, timeout : std::time::Duration) -> bool
+    {
+        let deadline = std::time::Instant::now () + timeout;
+        while self.sending.load (std::sync::atomic::Ordering::SeqCst) > 0
+        {
+            if std::time::Instant::now () >= deadline
+            {
+                return false;
+            }
+            std::thread::sleep (std::time::Duration::from_millis (50));
+        }
+        true
+    }
+}
+
+/// Store the archive read from `stream' in `compressed_file_name'.
+fn receive_archive (stream               : &mut impl Read,
+                    compressed_file_name : &std::path::Path) -> std::io::Result<()>
+{
+    let mut compressed_file = std::fs::OpenOptions::new ()
+        .write (true)
+        .create (true)
+        .truncate (true)
+        .open (compressed_file_name)?;
+
+    // Then loop on the incoming data from the stream.
+    let mut buffer = [0; 512];
+    loop
+    {
+        let n = stream.read (&mut buffer)?;
+        compressed_file.write_all (&buffer[0..n])?;
+
+        #[cfg(feature = "print_log")]
+        println! ("request_transfer - NEW CHUNK of size {}", n);
+
+        if n == 0
+        {
+            return Ok (());
+        }
+    }
+}
+
+/// Read the identifier of the request sent on `stream'.
+fn read_header (stream : &mut impl Read) -> std::io::Result<RequestId>
+{
+    let mut header = Vec::new ();
+    let mut byte   = [0u8; 1];
+    loop
+    {
+        if stream.read (&mut byte)? == 0 || header.len () >= MAX_HEADER_LEN
+        {
+            return Err (std::io::Error::new (std::io::ErrorKind::InvalidData, "missing request identifier"));
+        }
+        if byte[0] == b'\n'
+        {
+            break;
+        }
+        header.push (byte[0]);
+    }
+
+    String::from_utf8 (header).ok ()
+        .and_then (|header| header.parse::<RequestId> ().ok ())
+        .ok_or_else (|| std::io::Error::new (std::io::ErrorKind::InvalidData, "invalid request identifier"))
+}
+
+/// Decompress the archive in the folder of the request,
+/// then remove the archive.
+pub fn decompress_request (compressed_file_name : &std::path::Path,
+                           request_id           : RequestId) -> std::io::Result<()>
+{
+    let fname : &std::path::Path = compressed_file_name;
+    let file  : std::fs::File    = std::fs::File::open (fname)?;
+
+    let mut archive = zip::ZipArchive::new (file).map_err (zip_error)?;
+
+    #[cfg(feature = "print_log")]
+    println! ("request_transfer - archive len = {}", archive.len ());
+
+    // Add the folder path.
+    let request_folder = format! ("requests/{}", request_id.folder_name ());
+    for i in 0..archive.len ()
+    {
+        let mut file = archive.by_index (i).map_err (zip_error)?;
+        let outpath : std::path::PathBuf = match file.enclosed_name ()
+        {
+            Some (path) => format! ("{}/{}", request_folder, path.display ()).into (),
+            None => continue,
+        };
+
+        if file.is_dir ()
+        {
+
+            #[cfg(feature = "print_log")]
+            println! ("request_transfer - File {} extracted to \"{}\"", i, outpath.display ());
+
+            std::fs::create_dir_all (&outpath)?;
+        }
+        else
+        {
+
+            #[cfg(feature = "print_log")]
+            println! ("request_transfer - File {} extracted to \"{}\" ({} bytes)", i, outpath.display (), file.size ());
+
+            if let Some (p) = outpath.parent ().filter (|p| !p.exists ())
+            {
+                std::fs::create_dir_all (p)?;
+            }
+            let mut outfile = std::fs::File::create (&outpath)?;
+            std::io::copy (&mut file, &mut outfile)?;
+        }
+    }
+
+    // Remove the archive after decompressing it.
+    std::fs::remove_file (fname)?;
+
+    Ok (())
+}
+
+/// Record in the lineage of the request in `request_folder' its
+/// stay on this node.
+pub fn append_lineage (request_folder : &str, hop : &LineageHop) -> std::io::Result<()>
+{
+    let mut lineage = std::fs::OpenOptions::new ()
+        .append (true)
+        .create (true)
+        .open (format! ("{}/{}", request_folder, LINEAGE_FILE_NAME))?;
+    writeln! (lineage, "{}", hop)
+}
+
+/// Remove from the lineage of the request in `request_folder' the
+/// last stay recorded, when the request has not left the node after all.
+pub fn remove_last_lineage_hop (request_folder : &str) -> std::io::Result<()>
+{
+    let path = format! ("{}/{}", request_folder, LINEAGE_FILE_NAME);
+    let lineage = std::fs::read_to_string (&path)?;
+    let mut hops : Vec<&str> = lineage.lines ().collect ();
+    hops.pop ();
+    std::fs::write (&path, hops.iter ().map (|hop| format! ("{}\n", hop)).collect::<String> ())
+}
+
+/// Rename the folder `folder_name' of a request admitted with a
+/// name given by an operator ({app}_{label}_req) to the name
+/// derived from its identifier, so that it cannot collide with
+/// the folder of a request coming from another node.
+pub fn rename_request_folder (request_directory : &str,
+                              folder_name       : &str,
+                              request_id        : RequestId) -> std::io::Result<()>
+{
+    if folder_name == request_id.folder_name ()
+    {
+        return Ok (());
+    }
+    let target = format! ("{}/{}", request_directory, request_id.folder_name ());
+    if std::path::Path::new (&target).exists ()
+    {
+        return Err (std::io::Error::new (std::io::ErrorKind::AlreadyExists,
+                                         format! ("{} already exists", target)));
+    }
+
+    #[cfg(feature = "print_log")]
+    println! ("request_transfer - RENAME {} to {}", folder_name, request_id.folder_name ());
+
+    std::fs::rename (format! ("{}/{}", request_directory, folder_name), target)
+}
+
+/// Remove the archives left behind by an interrupted transfer: the
+/// ones being sent (in `request_directory') and the ones being
+/// received (in the working directory).
+pub fn remove_partial_archives (request_directory : &str)
+{
+    for directory in [request_directory, "."]
+    {
+        let entries = match std::fs::read_dir (directory)
+        {
+            Ok (entries) => entries,
+            Err (_)      => continue,
+        };
+        for entry in entries.flatten ()
+        {
+            let file_name = entry.file_name ().to_string_lossy ().to_string ();
+            if entry.path ().is_file () && file_name.ends_with ("_req.zip")
+            {
+                #[cfg(feature = "print_log")]
+                println! ("request_transfer - REMOVE {}", entry.path ().display ());
+
+                if let Err (error) = std::fs::remove_file (entry.path ())
+                {
+                    eprintln! ("request_transfer - unable to remove {}: {}", entry.path ().display (), error);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn stale_timeout_leaves_the_next_expectation ()
+    {
+        let server = DataServer::start ("127.0.0.1:0", None).unwrap ();
+        let request_id = RequestId::new (0, 0, 0);
+        let (timed_out, timeouts) = std::sync::mpsc::channel ();
+
+        // Expected a first time, then again before the first timeout.
+        let first = timed_out.clone ();
+        server.expect (request_id, 1, std::time::Duration::from_millis (100),
+                       Box::new (move |received| first.send (("first", received.is_err ())).unwrap ()));
+        server.expect (request_id, 1, std::time::Duration::from_millis (400),
+                       Box::new (move |received| timed_out.send (("second", received.is_err ())).unwrap ()));
+
+        std::thread::sleep (std::time::Duration::from_millis (250));
+        assert! (server.expected.lock ().unwrap ().contains_key (&request_id));
+
+        // Only the second expectation times out.
+        assert_eq! (timeouts.recv_timeout (std::time::Duration::from_secs (5)).unwrap (), ("second", true));
+        assert! (!server.expected.lock ().unwrap ().contains_key (&request_id));
+    }
+}
@@ -0,0 +1,228 @@
+/***************************************/
+/*       REQUESTS ADMISSION LOOP       */
+/***************************************/
+
+// After the startup, requests enter a node only through
+// migration. This loop scans the requests directory so that
+// operators can feed new requests to a running node: every
+// new {app}_{label}_req folder with a valid manifest is added
+// to the application state and served by the sporadic server.
+// The request takes the next sequence of the node, which is
+// never reused, and its folder is renamed {app}_{node}_{seq}_req
+// after its identifier in the federation.
+
+use crate::configuration_loader::RequestManifest;
+use crate::{linux_utils, request_transfer};
+use crate::state::{ApplicationState, RequestFolder, RequestId};
+
+/// Data and functions associated with the
+/// requests_admission_loop.
+pub struct ControlSystem
+{
+    /// Index of the application.
+    application_index : usize,
+
+    /// Index of the node.
+    node_index        : usize,
+
+    /// Period of the scan, in ms.
+    period            : u32,
+
+    /// The priority of this thread.
+    priority          : i32,
+
+    /// Affinity of this thread.
+    affinity          : usize,
+
+    /// Directory with the requests folders.
+    request_directory : String,
+
+    /// Folders already known to the loop, either admitted or
+    /// hosted by the node when first scanned.
+    known_folders     : std::collections::HashSet<String>,
+
+    /// Folders rejected at the last scan, with the reason (so
+    /// that the same error is reported only once).
+    rejected_folders  : std::collections::HashMap<String, String>,
+}
+
+impl ControlSystem
+{
+    pub fn new (application_index : usize,
+                node_index        : usize,
+                period            : u32,
+                priority          : i32,
+                affinity          : usize,
+                request_directory : String) -> Self
+    {
+        Self
+        {
+            application_index,
+            node_index,
+            period,
+            priority,
+            affinity,
+            request_directory,
+            known_folders    : std::collections::HashSet::new (),
+            rejected_folders : std::collections::HashMap::new (),
+        }
+    }
+
+    /// Start the requests admission loop.
+    pub fn start (&mut self,
+                  application_state : std::sync::Arc<std::sync::Mutex<ApplicationState>>,
+                  barrier           : std::sync::Arc<(std::sync::Mutex<u8>, std::sync::Condvar)>)
+    {
+
+        #[cfg(feature = "print_log")]
+        println! ("requests_admission_loop - INIT");
+
+        if self.period == 0
+        {
+            #[cfg(feature = "print_log")]
+            println! ("requests_admission_loop - DISABLED");
+
+            return;
+        }
+
+        linux_utils::set_priority (self.priority, self.affinity);
+
+        loop
+        {
+            std::thread::sleep (std::time::Duration::from_millis (self.period as u64));
+
+            // Stop admitting work once the node is shutting down.
+            if application_state.lock ().unwrap ().draining
+            {
+                #[cfg(feature = "print_log")]
+                println! ("requests_admission_loop - STOPPED");
+
+                return;
+            }
+            self.scan (&application_state, &barrier);
+        }
+    }
+
+    /// Admit the requests whose folder appeared since the last scan.
+    fn scan (&mut self,
+             application_state : &std::sync::Arc<std::sync::Mutex<ApplicationState>>,
+             barrier           : &std::sync::Arc<(std::sync::Mutex<u8>, std::sync::Condvar)>)
+    {
+        let entries = match std::fs::read_dir (&self.request_directory)
+        {
+            Ok (entries) => entries,
+            Err (error) =>
+                {
+                    eprintln! ("requests_admission_loop - unable to read {}: {}", self.request_directory, error);
+                    return;
+                }
+        };
+
+        let prefix = format! ("{}_", self.application_index);
+        let mut current_folders = std::collections::HashSet::new ();
+        for entry in entries.flatten ()
+        {
+            let folder_name = entry.file_name ().to_string_lossy ().to_string ();
+            if !entry.path ().is_dir () || !folder_name.starts_with (&prefix) || !folder_name.ends_with ("_req")
+            {
+                continue;
+            }
+            current_folders.insert (folder_name);
+        }
+
+        // Forget the folders that have been removed (served, migrated
+        // or renamed), so that a folder of the same name is admitted
+        // again, as a new request.
+        self.known_folders.retain (|folder| current_folders.contains (folder));
+        self.rejected_folders.retain (|folder, _| current_folders.contains (folder));
+
+        for folder_name in current_folders
+        {
+            if self.known_folders.contains (&folder_name)
+            {
+                continue;
+            }
+
+            match self.admit (&folder_name, application_state)
+            {
+                Ok (admitted) =>
+                    {
+                        self.rejected_folders.remove (&folder_name);
+                        self.known_folders.insert (folder_name);
+
+                        if admitted
+                        {
+                            // Update the barrier of the sporadic server.
+                            let (number_of_requests, cvar) = &**barrier;
+                            *number_of_requests.lock ().unwrap () += 1;
+                            cvar.notify_all ();
+                        }
+                    }
+                Err (reason) =>
+                    {
+                        // The folder might still be being copied: retry at
+                        // the next scan, but report each reason once.
+                        if self.rejected_folders.get (&folder_name) != Some (&reason)
+                        {
+                            eprintln! ("requests_admission_loop - {} not admitted: {}", folder_name, reason);
+                            self.rejected_folders.insert (folder_name, reason);
+                        }
+                    }
+            }
+        }
+    }
+
+    /// Validate the request in the folder `folder_name' and add it to
+    /// the application state. Return false if the request is already
+    /// hosted (e.g. it has just migrated to this node), or if the
+    /// node is shutting down.
+    fn admit (&self,
+              folder_name       : &str,
+              application_state : &std::sync::Arc<std::sync::Mutex<ApplicationState>>) -> Result<bool, String>
+    {
+        let request_folder = RequestFolder::from_folder_name (folder_name)
+            .ok_or ("invalid folder name (expected {app}_{label}_req)".to_string ())?;
+
+        // The label of a new request stands in for its sequence until
+        // it is admitted.
+        let request_id = match request_folder
+        {
+            RequestFolder::Identified (request_id)    => request_id,
+            RequestFolder::New { application, label } => RequestId::new (self.node_index, application, label),
+        };
+        let path_to_req_folder = format! ("{}/{}", self.request_directory, folder_name);
+        let manifest = RequestManifest::load (&path_to_req_folder, request_id)
+            .map_err (|error| error.to_string ())?;
+        let mut request = manifest.request;
+
+        let mut state = application_state.lock ().unwrap ();
+        let is_new = matches! (request_folder, RequestFolder::New { .. });
+        if state.draining || (!is_new && state.get_request (request_id).is_some ())
+        {
+            return Ok (false);
+        }
+        let available_memory = state.available_memory ();
+        if request.get_required_memory () > available_memory
+        {
+            return Err (format! ("request {} requires {} kB, {} kB available",
+                                 folder_name, request.get_required_memory (), available_memory));
+        }
+        if is_new
+        {
+            let request_id = RequestId::new (self.node_index, self.application_index, state.allocate_sequence ());
+            request_transfer::rename_request_folder (&self.request_directory, folder_name, request_id)
+                .map_err (|error| format! ("unable to rename the folder ({})", error))?;
+            request.set_id (request_id);
+        }
+        else if request_id.get_origin () == self.node_index
+        {
+            state.reserve_sequence (request_id.get_sequence ());
+        }
+        state.add_request (request);
+
+        #[cfg(feature = "print_log")]
+        println! ("requests_admission_loop - request {} ADMITTED", request);
+
+        Ok (true)
+    }
+}
@@ -2,23 +2,27 @@
 /*   R E Q U E S T S  C O O R D I N A T I O N  L O O P   */
 /*********************************************************/
 
-use paho_mqtt::{self as mqtt, MQTT_VERSION_5};
+use crate::message_bus::BusError;
 use futures::{executor::block_on, stream::StreamExt};
-use std::io::{Read, Write};
-use crate::{admm_solver::{GlobalSolver, LocalSolver}, log_writer, state::{ApplicationState, Coord, NodeState, Request}};
-use crate::mqtt_utils::{MessageLocal, BROKER_TOPICS, REGULAR_TOPICS};
-use crate::linux_utils;
-use crate::state::MessageRequest;
+use crate::{log_writer, state::{ApplicationState, RequestId, RequestState}};
+use crate::mqtt_utils::{federation_topic, MqttLink, BROKER_TOPICS};
+use crate::{linux_utils, mqtt_utils, protocol};
+use crate::protocol::{MessageAddress, MessageGlobal, MessageLocal, MessageRegion, MessageRequest, Payload};
+use crate::request_transfer::{self, DataServer};
+use crate::membership::Membership;
+use crate::negotiation::{self, Event, Limits, Local, Session, Sessions, Timeout};
+use crate::coordination_strategy::CoordinationStrategy;
+use crate::distance_metric::DistanceMetric;
 
 /// Data and functions associated with the
 /// requests_coordination_loop.
 pub struct ControlSystem
 {
-    /// The MQTT client.
-    client            : mqtt::AsyncClient,
+    /// The MQTT connection of the node.
+    link              : MqttLink,
 
-    /// Whether the current node is the central controller.
-    is_controller     : bool,
+    /// The server of the data plane of the node.
+    data_server       : std::sync::Arc<DataServer>,
 
     /// Address (ip and port) of the current node.
     ip_and_port       : String,
@@ -26,9 +30,8 @@ pub struct ControlSystem
     /// MQTT topics this application has to interact with.
     topics            : [String; 7],
 
-    /// The number of nodes in the federation assigned
-    /// to this application.
-    node_number       : usize,
+    /// The live members of the federation.
+    membership        : std::sync::Arc<std::sync::Mutex<Membership>>,
 
     /// The index of the node.
     node_index        : usize,
@@ -50,115 +53,312 @@ pub struct ControlSystem
     /// for a request in the current node.
     etc_multiplier    : f32,
 
+    /// Timeouts and quorum of the negotiations.
+    limits            : Limits,
+
     /// The maximum number of iterations in the ADMM algorithm.
     iteration_limit   : usize,
+
+    /// The metric of the distance from the desired attributes
+    /// of a request, in the cost term of the ADMM.
+    metric            : std::sync::Arc<dyn DistanceMetric>,
 }
 
 impl ControlSystem
 {
 
-    pub fn new (node_number      : usize,
-                is_controller    : bool,
+    pub fn new (membership       : std::sync::Arc<std::sync::Mutex<Membership>>,
                 application_index: usize,
                 node_index       : usize,
                 priority         : i32,
                 affinity         : usize,
                 penalty          : f32,
+                limits           : Limits,
                 local_ip         : String,
-                broker_address   : String) -> Self
+                data_port        : u16,
+                link             : MqttLink,
+                data_server      : std::sync::Arc<DataServer>,
+                metric           : std::sync::Arc<dyn DistanceMetric>) -> Self
     {
 
         #[cfg(feature = "print_log")]
         println! ("requests_coordination_loop - new START");
 
         // Initialization.
-        let host        = format! ("mqtt://{}:1883", broker_address).to_string ();
-        let ip_and_port = format! ("{}:8888", local_ip).to_string ();
+        let ip_and_port = format! ("{}:{}", local_ip, data_port).to_string ();
+
+        // Any node may be elected as the controller, hence
+        // subscribes to the topics of the controller too.
+        let topics : [String; 7] = [
+            federation_topic (application_index, BROKER_TOPICS[0]),
+            federation_topic (application_index, BROKER_TOPICS[1]),
+            federation_topic (application_index, &format! ("{}{}", BROKER_TOPICS[2], node_index)),
+            federation_topic (application_index, &format! ("{}{}", BROKER_TOPICS[3], node_index)),
+            federation_topic (application_index, &format! ("{}{}", BROKER_TOPICS[4], node_index)),
+            BROKER_TOPICS[5].to_string (),
+            "federation/node_available".to_string ()
+        ];
 
-        let client_id = format! ("node_{}_app_{}_rcl", node_index, application_index);
+        #[cfg(feature = "print_log")]
+        println! ("requests_coordination_loop - new END");
 
-        // Create the client. Use an ID for a persistent session. 
-        let create_opts = mqtt::CreateOptionsBuilder::new ()
-            .server_uri (host)
-            .client_id (client_id)
-            .finalize ();
+        Self 
+        {
+            link,
+            data_server,
+            ip_and_port,
+            topics,
+            membership,
+            node_index,
+            priority,
+            affinity,
+            application_index,
+            penalty,
+            etc_multiplier  : 0.05,
+            limits,
+            iteration_limit : 20,
+            metric,
+        }
+    }
 
-        // Create the subscriber connection. 
-        let client = mqtt::AsyncClient::new (create_opts)
-            .unwrap_or_else (|e|
-                {
-                    panic! ("requests_monitoring_loop - error creating the client: {:?}", e);
-                }
-            );
+    /// Perform the global update on Z of a round of `session', then
+    /// send either the new globals or, if the ADMM has terminated,
+    /// the destination of the request to all the nodes.
+    async fn complete_round (&self, session : &mut Session) -> Result<(), BusError>
+    {
+        let request_id = session.request.get_id ();
+        session.z_update ();
+        let global_solver = &session.global_solver;
+        if global_solver.terminated ()
+        {
 
-        client.set_disconnected_callback (|_, _props, reason|
+            #[cfg(feature = "timing_log")]
             {
-                panic! ("requests_monitoring_loop - server disconnected with reason: {}", reason);
+                let iterations = global_solver.get_iterations ();
+                let completion_time = linux_utils::get_completion_time (session.start_time);
+                log_writer::save_admm_data (true, completion_time, iterations);
             }
-        );
 
-        // Now depending on whether the current node is the broker
-        // or not, configure the topics accordingly.
-        let topics : [String; 7];
-        if is_controller
-        {
+            // Load the value of the dest_node.
+            let dest_node = session.destination ();
 
             #[cfg(feature = "print_log")]
-            println! ("requests_coordination_loop - it is CONTROLLER");
-
-            topics        = [
-                BROKER_TOPICS[0].to_string (),
-                BROKER_TOPICS[1].to_string (),
-                format! ("{}{}", BROKER_TOPICS[2], node_index).to_string (),
-                format! ("{}{}", BROKER_TOPICS[3], node_index).to_string (),
-                format! ("{}{}", BROKER_TOPICS[4], node_index).to_string (),
-                BROKER_TOPICS[5].to_string (),
-                "federation/node_available".to_string ()
-            ]
+            println! ("requests_coordination_loop - global_solver.terminated() = {}", dest_node);
+
+            // Update all the other nodes.
+            for &node in &session.members
+            {
+                let topic =
+                    federation_topic (self.application_index, &format! ("global_update/{}", node));
+                let message_global = MessageGlobal::Dest { request : request_id, node : dest_node };
+                self.link.send (topic, message_global.into_message ()).await?;
+            }
         }
         else
         {
+            // Update all the other nodes.
+            for &node in &session.members
+            {
+                // Get the new global for the node `node'.
+                let new_global_for_node = session.global_of (node);
+
+                // Send it to its specific channel.
+                let topic =
+                    federation_topic (self.application_index, &format! ("global_update/{}", node));
+                let message_global = MessageGlobal::Update { request : request_id, global : new_global_for_node };
+                self.link.send (topic, message_global.into_message ()).await?;
+            }
+        }
+        Ok (())
+    }
 
-            #[cfg(feature = "print_log")]
-            println! ("requests_coordination_loop - it is REGULAR");
-
-            topics        = [
-                REGULAR_TOPICS[0].to_string (),
-                format! ("{}{}", REGULAR_TOPICS[1], node_index).to_string (),
-                format! ("{}{}", REGULAR_TOPICS[2], node_index).to_string (),
-                format! ("{}{}", REGULAR_TOPICS[3], node_index).to_string (),
-                REGULAR_TOPICS[4].to_string (),
-                "unused".to_string (),
-                "federation/node_available".to_string ()
-            ]
+    /// Handle the sessions which timed out: the controller goes on
+    /// with the nodes that replied in the round, or aborts the
+    /// negotiation if they are too few; a session without progress
+    /// is dropped.
+    async fn check_timeouts (&self,
+                             sessions          : &mut Sessions,
+                             is_controller     : bool,
+                             application_state : &std::sync::Mutex<ApplicationState>) -> Result<(), BusError>
+    {
+        let expired : Vec<(RequestId, Timeout)> = sessions.iter ()
+            .filter_map (|(&request_id, session)|
+                session.timeout (&self.limits, is_controller).map (|timeout| (request_id, timeout)))
+            .collect ();
+        for (request_id, timeout) in expired
+        {
+            match timeout
+            {
+                Timeout::Round =>
+                    {
+                        let session = sessions.get_mut (&request_id).expect ("Missing session");
+                        let silent = session.exclude_silent ();
+                        eprintln! ("requests_coordination_loop - request {} ROUND TIMEOUT: nodes {:?} excluded",
+                                   request_id, silent);
+                        self.complete_round (session).await?;
+                    }
+                Timeout::Quorum =>
+                    {
+                        // The src node keeps running the request.
+                        eprintln! ("requests_coordination_loop - request {} ROUND TIMEOUT: no quorum, negotiation ABORTED",
+                                   request_id);
+                        self.abort (sessions, request_id).await?;
+                    }
+                Timeout::Session =>
+                    {
+                        let session = sessions.remove (&request_id).expect ("Missing session");
+                        negotiation::abandon (&session, self.node_index, application_state);
+                    }
+            }
         }
+        Ok (())
+    }
+
+    /// Signal the destination of the negotiation concluded by `session',
+    /// whose request is ready to go, so that it expects the request.
+    async fn signal_destination (&self,
+                                 session           : &mut Session,
+                                 application_state : &std::sync::Mutex<ApplicationState>) -> Result<(), BusError>
+    {
+        let request_id = session.request.get_id ();
+        let dest_node  = session.dest_node.expect ("Concluded session");
+
+        #[cfg(feature = "migration_log")]
+        unsafe
+            {
+                libc::clock_gettime (libc::CLOCK_MONOTONIC, &mut session.start_send);
+            }
+
+        // Get the index of the next region
+        // of the request.
+        let next_region = application_state.lock ().unwrap ()
+            .get_cur_region_of_request (request_id);
+
+
+        let dest_topic = federation_topic (self.application_index,
+                                           &format! ("dst/{}", dest_node));
 
         #[cfg(feature = "print_log")]
-        println! ("requests_coordination_loop - new END");
+        println! ("requests_coordination_loop - dest_topic = {dest_topic}");
 
-        Self 
+        // Send your address to the destination node.
+        let message_region = MessageRegion { request : request_id, region : next_region };
+        self.link.send (dest_topic, message_region.into_message ()).await?;
+        session.progress ();
+        Ok (())
+    }
+
+    /// Resume the session waiting for the checkpoint of `request_id',
+    /// whose job has ended: the request goes if it has been
+    /// checkpointed, the migration is aborted otherwise (e.g. the
+    /// request has completed meanwhile).
+    async fn job_ended (&self,
+                        sessions          : &mut Sessions,
+                        request_id        : RequestId,
+                        application_state : &std::sync::Mutex<ApplicationState>) -> Result<(), BusError>
+    {
+        let session = match sessions.get_mut (&request_id)
         {
-            client,
-            is_controller,
-            ip_and_port,
-            topics,
-            node_number,
-            node_index,
-            priority,
-            affinity,
-            application_index,
-            penalty,
-            etc_multiplier  : 0.05,
-            iteration_limit : 20,
+            Some (session) if session.is_waiting_checkpoint () => session,
+            Some (_) => return Ok (()),
+            None =>
+                {
+                    negotiation::requeue_checkpointed (request_id, application_state);
+                    return Ok (());
+                }
+        };
+        session.checkpoint_done ();
+
+        let request_state = application_state.lock ().unwrap ()
+            .get_request_state (request_id);
+        if request_state != Some (RequestState::InTransit)
+        {
+            eprintln! ("requests_coordination_loop - migration of request {} ABORTED ({})",
+                       request_id,
+                       request_state.map_or ("unknown".to_string (), |state| state.to_string ()));
+            sessions.remove (&request_id);
+            return Ok (());
+        }
+        self.signal_destination (session, application_state).await
+    }
+
+    /// Abort the negotiation of `request_id', on this node and on the
+    /// other nodes taking part: the src node keeps running the request,
+    /// whose migration is negotiated again at the next trigger.
+    async fn abort (&self, sessions : &mut Sessions, request_id : RequestId) -> Result<(), BusError>
+    {
+        let session = sessions.remove (&request_id).expect ("Missing session");
+        for &node in session.members.iter ().filter (|&&node| node != self.node_index)
+        {
+            let topic =
+                federation_topic (self.application_index, &format! ("global_update/{}", node));
+            let message_global = MessageGlobal::Abort { request : request_id };
+            self.link.send (topic, message_global.into_message ()).await?;
         }
+        Ok (())
     }
 
-    /// Start the request coordination loop implementing the
-    /// ADMM consensus algorithm. 
-    pub fn start (&mut self,
-                  application_state : std::sync::Arc<std::sync::Mutex<ApplicationState>>,
-                  barrier           : std::sync::Arc<(std::sync::Mutex<u8>, std::sync::Condvar)>,
-                  checkpoint_barrier: std::sync::Arc<(std::sync::Mutex<bool>, std::sync::Condvar)>)
+    /// Follow the election of the controller. A node elected while
+    /// negotiations are running cannot resume their rounds, whose
+    /// local updates went to the former controller: it restarts them
+    /// cleanly, aborting them so that their next trigger opens them
+    /// again.
+    async fn follow_election (&self,
+                              sessions   : &mut Sessions,
+                              controller : &mut Option<usize>) -> Result<(), BusError>
+    {
+        let elected = self.membership.lock ().unwrap ().controller (self.application_index);
+        if elected == *controller
+        {
+            return Ok (());
+        }
+        *controller = elected;
+        if elected != Some (self.node_index)
+        {
+            #[cfg(feature = "print_log")]
+            println! ("requests_coordination_loop - the CONTROLLER is {:?}", elected);
+            return Ok (());
+        }
+
+        eprintln! ("requests_coordination_loop - ELECTED as the controller");
+        let negotiating : Vec<RequestId> = sessions.iter ()
+            .filter (|(_, session)| session.is_negotiating ())
+            .map (|(&request_id, _)| request_id)
+            .collect ();
+        for request_id in negotiating
+        {
+            eprintln! ("requests_coordination_loop - request {} negotiation RESTARTED", request_id);
+            self.abort (sessions, request_id).await?;
+        }
+        Ok (())
+    }
+
+    /// Abort the negotiations in progress when the connection to the
+    /// broker is lost, since their messages are lost meanwhile: their
+    /// sources keep their requests until the next migration trigger.
+    /// The aborts reach the other nodes once the connection is back.
+    /// The transfers already decided go on through the data plane.
+    async fn connection_lost (&self, sessions : &mut Sessions) -> Result<(), BusError>
+    {
+        let negotiating : Vec<RequestId> = sessions.iter ()
+            .filter (|(_, session)| session.is_negotiating ())
+            .map (|(&request_id, _)| request_id)
+            .collect ();
+        for request_id in negotiating
+        {
+            eprintln! ("requests_coordination_loop - request {} negotiation ABORTED (connection lost)", request_id);
+            self.abort (sessions, request_id).await?;
+        }
+        Ok (())
+    }
+}
+
+impl CoordinationStrategy for ControlSystem
+{
+    fn start (&mut self,
+              application_state : std::sync::Arc<std::sync::Mutex<ApplicationState>>,
+              barrier           : std::sync::Arc<(std::sync::Mutex<u8>, std::sync::Condvar)>,
+              job_ends          : futures::channel::mpsc::UnboundedReceiver<RequestId>)
     {
 
         #[cfg(feature = "print_log")]
@@ -169,709 +369,418 @@ impl ControlSystem
 
         // To avoid re-computing the same topics' names
         // we define the most used here.
-        let federation_src = format! ("federation/src/{}", self.node_index);
-        let federation_dst = format! ("federation/dst/{}", self.node_index);
-        let federation_global_upd = format! ("federation/global_update/{}", self.node_index);
+        let federation_migration = federation_topic (self.application_index, "migration");
+        let federation_local_upd = federation_topic (self.application_index, "local_update");
+        let federation_src = federation_topic (self.application_index, &format! ("src/{}", self.node_index));
+        let federation_dst = federation_topic (self.application_index, &format! ("dst/{}", self.node_index));
+        let federation_global_upd =
+            federation_topic (self.application_index, &format! ("global_update/{}", self.node_index));
 
 
         if let Err (err) = block_on (async {
 
-            // Get message stream before connecting. 
-            let mut strm = self.client.get_stream (15);
-
-            // Define the set of options for the connection
-            let lwt = mqtt::Message::new (
-                self.topics[5].clone (),
-                "[LWT] Async subscriber 'requests_coordination_loop' lost connection",
-                mqtt::QOS_1,
-            );
-
-            // Connect with MQTT v5 and a persistent server session (no clean start).
-            let conn_opts =
-                mqtt::ConnectOptionsBuilder::with_mqtt_version (MQTT_VERSION_5)
-                    .clean_start (true)
-                    .properties (mqtt::properties![mqtt::PropertyCode::SessionExpiryInterval => 3600])
-                    .will_message (lwt)
-                    .finalize ();
-
-            // Make the connection to the broker. 
-            self.client.connect (conn_opts).await?;
-
-            let topics_len = self.topics.len ();
-            let sub_opts =
-                vec![mqtt::SubscribeOptions::with_retain_as_published (); topics_len];
-            self.client.subscribe_many_with_options (
-                &self.topics,
-                &vec![mqtt::QOS_1; topics_len],
-                &sub_opts,
-                None).await?;
-
-            // Variables used in the ADMM consensus algorithm. 
-            let mut incoming_request : Option<Request> = None;
-            let mut src_node         : Option<usize>   = None;
-            let mut local_solver = 
-                LocalSolver::new(self.node_number, 20.0, 0.5, Coord::new ());
-            let mut global_solver =
-                GlobalSolver::new (self.node_number, self.iteration_limit);
-            let mut node_state         : NodeState;
-            let mut could_host_request : bool = false;
-            let mut request_etc        : u32;
-            let mut dest_node          : Option<usize>;
+            // Subscribe through the connection of the node, and
+            // check the timeouts of the sessions between messages,
+            // as well as the end of the jobs of the requests.
+            let messages = self.link.subscribe (&self.topics).await?.map (Event::Message);
+            let ticks = negotiation::ticks (negotiation::TICK_PERIOD).map (|_| Event::Tick);
+            let mut strm = futures::stream::select (futures::stream::select (messages, ticks),
+                                                    job_ends.map (Event::JobEnd));
 
-            #[cfg(feature = "timing_log")]
-            let mut start_time    = libc::timespec { tv_sec: 0, tv_nsec: 0 };
-            let mut start_send    = libc::timespec { tv_sec: 0, tv_nsec: 0 };
-            let mut start_receive = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            // The migrations being negotiated, by request.
+            let mut sessions = Sessions::new ();
+
+            // The controller elected among the live nodes, if any.
+            let mut controller = None;
 
             #[cfg(feature = "print_log")]
             println! ("requests_coordination_loop - LOOP");
 
             // Loop on incoming messages.
-            while let Some (msg_opt) = strm.next ().await 
+            while let Some (event) = strm.next ().await 
             {
+                let msg_opt = match event
+                {
+                    Event::Message (msg_opt) => msg_opt,
+                    Event::Tick =>
+                        {
+                            self.follow_election (&mut sessions, &mut controller).await?;
+                            self.check_timeouts (&mut sessions, controller == Some (self.node_index), &application_state).await?;
+                            continue;
+                        }
+                    Event::JobEnd (request_id) =>
+                        {
+                            self.job_ended (&mut sessions, request_id, &application_state).await?;
+                            continue;
+                        }
+                };
+                if msg_opt.is_none ()
+                {
+                    self.connection_lost (&mut sessions).await?;
+                    continue;
+                }
                 if let Some (msg) = msg_opt 
                 {
-                    if msg.topic () == "federation/migration"
+                    if msg.topic () == federation_migration
                     {
 
                         #[cfg(feature = "print_log")]
                         println! ("requests_coordination_loop - federation/migration MIGRATION");
 
-                        #[cfg(feature = "timing_log")]
-                        unsafe
-                            {
-                                libc::clock_gettime (libc::CLOCK_MONOTONIC, &mut start_time);
-                            }
-
                         // Parse the received message.
-                        let message_request = 
-                            msg.payload_str ().parse::<crate::state::MessageRequest> ()
-                                .expect ( "Unable to parse message into Request");
-                        let &request = message_request.get_request ();
-                        src_node = Some (message_request.get_src ());
-
-                        // If nodes are already deciding where to migrate a request, 
-                        // hence incoming_request is Some (...), simply ignore any 
-                        // further requests. 
-                        match incoming_request
-                        {
-                            None =>
-                                {
+                        let message_request =
+                            match protocol::decode::<MessageRequest> (msg.payload ())
+                            {
+                                Ok (message_request) => message_request,
+                                Err (error) =>
                                     {
-                                        // Acquire a copy of the node state.
-                                        // It will be preserved during the execution of
-                                        // the consensus algorithm.
-                                        let state =
-                                            application_state.lock ().unwrap ();
-                                        node_state = state.node_state.clone ();
-
-                                        // Then check if this node could possibly host
-                                        // the incoming request.
-                                        could_host_request = state.could_host_computation (&request);
-
-                                        // And an estimates of the time to complete the computation.
-                                        request_etc =
-                                            state.get_expected_completion_time (request.get_execution_time ());
-
-                                        // Drop the value to force relinquishing the lock. 
-                                        drop (state);
+                                        mqtt_utils::reject_payload (&msg, &error);
+                                        continue;
                                     }
+                            };
+                        let &request = message_request.get_request ();
 
-                                    #[cfg(feature = "print_log")]
-                                    println! ("requests_coordination_loop - request_etc = {}", request_etc);
-
-                                    // Initialize the variables for a new execution. 
-                                    incoming_request = Some (request);
-                                    local_solver.clear (self.node_number,
-                                                        self.penalty,
-                                                        self.etc_multiplier,
-                                                        node_state.get_coord (),
-                                                        request_etc);
+                        // If nodes are already deciding where to migrate this
+                        // request, simply ignore the message (e.g. the migration
+                        // is triggered again meanwhile).
+                        if !negotiation::should_open (&sessions, request.get_id ())
+                        {
+                            #[cfg(feature = "print_log")]
+                            println! ("requests_coordination_loop - federation/migration IGNORE {}", request.get_id ());
+                            continue;
+                        }
 
-                                    if self.is_controller
-                                    {
-                                        global_solver.clear ();
-                                    }
-    
-                                    // Perform the local update.
-                                    if !could_host_request
-                                    {
-                                        // If the node has not enough resources to host the request,
-                                        // speedup the local_update.
-                                        local_solver.local = 0f32;
-                                    }
-                                    else
-                                    {
-                                        local_solver.local_x_update (&request);
-                                    }
+                        // Open a session for the request among the members seen
+                        // by the src node, with a copy of the node state
+                        // preserved during the execution of the consensus
+                        // algorithm.
+                        let mut session = Session::open (request,
+                                                         message_request.get_src (),
+                                                         message_request.get_members ().to_vec (),
+                                                         message_request.get_memory (),
+                                                         &application_state.lock ().unwrap (),
+                                                         self.penalty,
+                                                         self.etc_multiplier,
+                                                         self.iteration_limit,
+                                                         self.metric.clone ());
+
+                        // A node unknown to the src node does not take part,
+                        // but the controller still performs the global update.
+                        if session.slot (self.node_index).is_none ()
+                        {
+                            #[cfg(feature = "print_log")]
+                            println! ("requests_coordination_loop - request {} NOT A MEMBER of the negotiation", request.get_id ());
 
-                                    // Send x + u, note that the client will receive 
-                                    // its own message. 
-                                    let local_sum =
-                                        local_solver.get_local() + local_solver.get_dual();
-                                    let message_local = MessageLocal
-                                    {
-                                        src: self.node_index,
-                                        local_sum,
-                                    };
-                                    let msg = mqtt::Message::new (
-                                        "federation/local_update",
-                                        message_local.to_string (),
-                                        paho_mqtt::QOS_1);
-                                    self.client.publish (msg).await?;
-                                }
-                            _ =>
-                                {
-                                    #[cfg(feature = "print_log")]
-                                    println! ("requests_coordination_loop - federation/migration IGNORE");
-                                    // Ignore the request.
-                                }
+                            if controller == Some (self.node_index)
+                            {
+                                sessions.insert (request.get_id (), session);
+                            }
+                            continue;
                         }
+
+                        // Perform the local update, and send x + u, note that
+                        // the client will receive its own message.
+                        let message_local = MessageLocal
+                        {
+                            src       : self.node_index,
+                            request   : request.get_id (),
+                            iteration : session.round (),
+                            local_sum : session.local_update (),
+                        };
+                        sessions.insert (request.get_id (), session);
+                        self.link.send (federation_local_upd.as_str (), message_local.into_message ()).await?;
                     }
-                    else if msg.topic () == "federation/local_update"
+                    else if msg.topic () == federation_local_upd
                     {
-                        // Only the controller subscribes to this topic.
-                        assert!(self.is_controller);
+                        // Only the controller performs the global update.
+                        if controller != Some (self.node_index)
+                        {
+                            continue;
+                        }
 
                         #[cfg(feature = "print_log")]
                         println! ("requests_coordination_loop - federation/local_update LOCAL {:?}", msg.payload_str ());
 
                         // Parse the received message. 
-                        let message_local = 
-                            msg.payload_str ().parse::<MessageLocal> ()
-                                .expect ("Unable to parse message into f32 (in federation/local_update)");
-                        let x   : f32   = message_local.local_sum;
-                        let src : usize = message_local.src;
-
-                        // Add x to X, the set of local variables. 
-                        global_solver.add_local_sum (x, src);
-
-                        #[cfg(feature = "print_log")]
-                        println! ("requests_coordination_loop - global_solver.locals_len () = {}", global_solver.locals_len ());
-
-                        // Wait until all nodes have completed their local update. 
-                        if global_solver.locals_len () == self.node_number
-                        {
-                            // Global update on Z. 
-                            global_solver.global_z_updater ();
-                            if global_solver.terminated ()
+                        let message_local =
+                            match protocol::decode::<MessageLocal> (msg.payload ())
                             {
-
-                                #[cfg(feature = "timing_log")]
+                                Ok (message_local) => message_local,
+                                Err (error) =>
+                                    {
+                                        mqtt_utils::reject_payload (&msg, &error);
+                                        continue;
+                                    }
+                            };
+                        let request_id = message_local.request;
+                        let session = match sessions.get_mut (&request_id)
+                        {
+                            Some (session) if session.is_negotiating () => session,
+                            _ =>
                                 {
-                                    let iterations = global_solver.get_iterations ();
-                                    let completion_time = linux_utils::get_completion_time (start_time);
-                                    log_writer::save_admm_data (true, completion_time, iterations);
+                                    mqtt_utils::reject_payload (&msg, &format! ("request {} is not being negotiated", request_id));
+                                    continue;
                                 }
+                        };
 
-                                // Load the value of the dest_node.
-                                dest_node = Some (global_solver.get_max_global_index ());
-                                let dest_node = dest_node.expect ("Destination node missing. ");
-
-                                #[cfg(feature = "print_log")]
-                                println! ("requests_coordination_loop - global_solver.terminated() = {}", global_solver.get_max_global_index());
-
-                                // Update all the other nodes.
-                                for index in 0..self.node_number
+                        // Add x to X, the set of local variables, if it
+                        // belongs to the current round.
+                        match session.add_local (message_local.src, message_local.iteration, message_local.local_sum)
+                        {
+                            Local::Counted => (),
+                            Local::Ignored =>
                                 {
-                                    let topic =
-                                        format! ("federation/global_update/{}", index);
-                                    let msg = mqtt::Message::new (
-                                        topic.as_str (),
-                                        format! ("dest={}", dest_node).to_string (),
-                                        paho_mqtt::QOS_1);
-                                    self.client.publish (msg).await?;
+                                    #[cfg(feature = "print_log")]
+                                    println! ("requests_coordination_loop - local update of node {} (round {}) IGNORED",
+                                              message_local.src, message_local.iteration);
+                                    continue;
                                 }
-                            }
-                            else
-                            {
-                                // Update all the other nodes.
-                                for index in 0..self.node_number
+                            Local::NotMember =>
                                 {
-                                    // Get the new global for the node `index'.
-                                    let new_global_for_index =
-                                        global_solver.get_global_from_index (index);
-
-                                    // Send it to its specific channel.
-                                    let topic =
-                                        format! ("federation/global_update/{}", index);
-                                    let msg = mqtt::Message::new (
-                                        topic.as_str (),
-                                        format! ("update={}", new_global_for_index).to_string (),
-                                        paho_mqtt::QOS_1);
-                                    self.client.publish (msg).await?;
+                                    mqtt_utils::reject_payload (&msg, &format! ("node {} does not take part in the negotiation of request {}",
+                                                                                message_local.src, request_id));
+                                    continue;
                                 }
-                            }
+                        }
+
+                        #[cfg(feature = "print_log")]
+                        println! ("requests_coordination_loop - global_solver.locals_len () = {}", session.global_solver.locals_len ());
+
+                        // Wait until all the nodes taking part in the placement
+                        // have completed their local update. 
+                        if session.global_solver.is_round_complete ()
+                        {
+                            self.complete_round (session).await?;
                         }
                     }
-                    // federation/global_update/i -> update=f32 or dest=usize
+                    // federation/global_update/i -> MessageGlobal
                     else if msg.topic () == federation_global_upd
                     {
                         println!("msg_payload = {}", msg.payload_str ());
-                        let msg_payload = msg.payload_str ().to_string ();
-                        let msg_info = msg_payload.split ("=").collect::<Vec<&str>> ();
-                        if msg_info[0] == "update"
+                        let message_global = match protocol::decode::<MessageGlobal> (msg.payload ())
                         {
-                            let new_global = (*msg_info[1]).parse::<f32> ().unwrap ();
-
-                            // Update the local_solver with the new global value,
-                            // then perform the dual update.
-                            local_solver.set_global (new_global);
-                            local_solver.local_dual_update ();
-
-                            // Finally, do the local update and send it.
-                            if !could_host_request
-                            {
-                                local_solver.local = 0f32;
-                            }
-                            else
-                            {
-                                local_solver.local_x_update (&incoming_request.unwrap ());
-                            }
-
-                            // Send x + u, note that the client will receive
-                            // its own message.
-                            let local_sum =
-                                local_solver.get_local () + local_solver.get_dual ();
-                            let message_local = MessageLocal
-                            {
-                                src: self.node_index,
-                                local_sum,
-                            };
-                            let msg = mqtt::Message::new (
-                                "federation/local_update",
-                                message_local.to_string (),
-                                paho_mqtt::QOS_1);
-                            self.client.publish (msg).await?;
-
-
-                            #[cfg(feature = "print_log")]
-                            println! ("requests_coordination_loop - SENT local update");
-
-                        }
-                        else if msg_info[0] == "dest"
+                            Ok (message_global) => message_global,
+                            Err (error) =>
+                                {
+                                    mqtt_utils::reject_payload (&msg, &error);
+                                    continue;
+                                }
+                        };
+                        let request_id = message_global.get_request ();
+                        let session = match sessions.get_mut (&request_id)
+                        {
+                            Some (session) if session.is_negotiating () => session,
+                            _ =>
+                                {
+                                    mqtt_utils::reject_payload (&msg, &format! ("request {} is not being negotiated", request_id));
+                                    continue;
+                                }
+                        };
+                        match message_global
                         {
-                            // The algorithm has terminated.
+                            MessageGlobal::Update { global, .. } =>
+                                {
+                                    // Update the local_solver with the new global value,
+                                    // perform the dual update, then the local update and
+                                    // send it, note that the client will receive its own
+                                    // message.
+                                    let local_sum = session.global_update (global);
+                                    let message_local = MessageLocal
+                                    {
+                                        src       : self.node_index,
+                                        request   : request_id,
+                                        iteration : session.round (),
+                                        local_sum,
+                                    };
+                                    self.link.send (federation_local_upd.as_str (), message_local.into_message ()).await?;
 
-                            // Check if this node is the src node, hence the one
-                            // that has to send the request.
-                            match src_node
-                            {
-                                Some (src) =>
+                                    #[cfg(feature = "print_log")]
+                                    println! ("requests_coordination_loop - SENT local update");
+                                }
+                            MessageGlobal::Dest { node : dest_node, .. } =>
+                                {
+                                    // The algorithm has terminated. Only the src node
+                                    // and the destination take part in the transfer.
+                                    if !session.conclude (dest_node, self.node_index)
                                     {
-                                        if src == self.node_index
+                                        #[cfg(feature = "print_log")]
+                                        if session.src_node == self.node_index
                                         {
+                                            // The migration is not convenient after all.
+                                            println! ("requests_coordination_loop - src == dest");
+                                        }
 
-                                            #[cfg(feature = "print_log")]
-                                            println! ("requests_coordination_loop - src == self.node_index");
-
-                                            // Extract the destination node.
-                                            let dest_node = msg_info[1].parse::<usize> ().unwrap ();
-
-                                            if dest_node == self.node_index
-                                            {
+                                        sessions.remove (&request_id);
+                                        continue;
+                                    }
 
-                                                #[cfg(feature = "print_log")]
-                                                println! ("requests_coordination_loop - src == dest");
+                                    // Check if this node is the src node, hence the one
+                                    // that has to send the request.
+                                    if session.src_node == self.node_index
+                                    {
 
-                                                // The migration is not convenient after all.
-                                                // Continue as usual.
+                                        #[cfg(feature = "print_log")]
+                                        println! ("requests_coordination_loop - src == self.node_index");
 
-                                            }
-                                            else
-                                            {
-                                                // The migration is convenient.
-                                                // First, prepare for the checkpoint.
-                                                let mut state =
-                                                    application_state.lock ().unwrap ();
-                                                let index_incoming_request =
-                                                    incoming_request.unwrap ().get_index ();
-                                                if index_incoming_request ==
-                                                    state.requests.first().unwrap ().get_index ()
+                                        // The migration is convenient. The request being served
+                                        // is checkpointed first: the session waits for the end of
+                                        // its job, while the other sessions go on. A queued
+                                        // request leaves as it is.
+                                        match negotiation::prepare_departure (session, &application_state)
+                                        {
+                                            Ok (true)  => self.signal_destination (session, &application_state).await?,
+                                            Ok (false) =>
+                                                {
+                                                    #[cfg(feature = "print_log")]
+                                                    println! ("requests_coordination_loop - request {} WAITING for its checkpoint", request_id);
+                                                }
+                                            Err (error) =>
                                                 {
-                                                    state.set_should_migrate_of_request (index_incoming_request, true);
+                                                    eprintln! ("requests_coordination_loop - migration REFUSED: {}", error);
+                                                    sessions.remove (&request_id);
                                                 }
-                                                drop(state);
-
-                                                // Wait for the checkpoint to complete.
-                                                let (barrier, cvar) = &*checkpoint_barrier;
-                                                let _r = cvar.wait_while (barrier.lock ().unwrap (),
-                                                                          |&mut is_ready| { !is_ready }).unwrap ();
-
-                                                #[cfg(feature = "migration_log")]
-                                                unsafe
-                                                    {
-                                                        libc::clock_gettime (libc::CLOCK_MONOTONIC, &mut start_send);
-                                                    }
-
-                                                // Get the index of the next region
-                                                // of the request.
-                                                let request_index = incoming_request.unwrap ().get_index ();
-                                                let next_region = application_state.lock ().unwrap ()
-                                                    .get_cur_region_of_request (request_index);
-
-
-                                                let dest_topic = format! ("{}/{}",
-                                                                          "federation/dst",
-                                                                          dest_node);
-
-                                                #[cfg(feature = "print_log")]
-                                                println! ("requests_coordination_loop - dest_topic = {dest_topic}");
-
-                                                // Send your address to the destination node.
-                                                let msg = mqtt::Message::new (
-                                                    dest_topic,
-                                                    next_region.to_string (),
-                                                    paho_mqtt::QOS_1);
-                                                self.client.publish (msg).await?;
-                                            }
                                         }
                                     }
-                                _ => {
-                                    panic! ("Src node unknown");
-                                    }
-                            }
-                        }
-                        else
-                        {
-                            panic! ("Wrong message format to {}. ", msg.topic ());
+                                }
+                            MessageGlobal::Abort { .. } =>
+                                {
+                                    // Too few nodes replied in a round: the src node
+                                    // keeps running the request.
+                                    eprintln! ("requests_coordination_loop - request {} negotiation ABORTED", request_id);
+                                    sessions.remove (&request_id);
+                                }
                         }
                     }
-                    // federation/src/i -> ip:port.
+                    // federation/src/i -> MessageAddress (ip:port).
                     else if msg.topic () == federation_src
                     {
 
                         #[cfg(feature = "print_log")]
                         println! ("requests_coordination_loop - federation/src SEND");
 
-                        match incoming_request
+                        let message_address = match protocol::decode::<MessageAddress> (msg.payload ())
                         {
-                            Some (request) =>
+                            Ok (message_address) => message_address,
+                            Err (error) =>
                                 {
-                                    // We need to remove the request from the
-                                    // pool of requests served in this node for this
-                                    // application.
-                                    {
-                                        let mut state =
-                                            application_state.lock ().unwrap ();
-                                        state.remove_request (request.get_index ());
-                                        drop (state);
-                                    }
-
-                                    // Then, update the barrier for the sporadic server.
-                                    {
-                                        let (number_of_requests, cvar) = &*barrier;
-                                        *number_of_requests.lock ().unwrap () -= 1;
-                                        cvar.notify_all ();
-                                    }
-
-                                    incoming_request = None;
-
-                                    #[cfg(feature = "print_log")]
-                                    println! ("requests_coordination_loop - incoming_request = None");
-
-                                    // Compress the folder of the request.
-
-                                    let zip_archive_name =
-                                        format! ("{}_{}_req.zip", self.application_index, request.get_index ());
-                                    let zip_archive_path_str = format! ("requests/{}", zip_archive_name);
-                                    let zip_archive_path =
-                                        std::path::Path::new (&zip_archive_path_str);
-                                    let zip_archive = std::fs::File::create (&zip_archive_path).unwrap ();
-
-                                    let mut zip = zip::ZipWriter::new (zip_archive);
-
-                                    // The files that might be compressed (memories are optional).
-                                    let path_to_req_dir = format! ("requests/{}_{}_req/",
-                                                                   self.application_index,
-                                                                   request.get_index ());
-                                    let module_path = format! ("{}/module.wasm", path_to_req_dir);
-                                    let main_mem_path = format! ("{}/main_memory.b", path_to_req_dir);
-                                    let checkpoint_mem_path = format! ("{}/checkpoint_memory.b", path_to_req_dir);
-                                    let input_img_path = format! ("{}/input_small.pgm", path_to_req_dir);
-
-                                    let files_to_compress: Vec<std::path::PathBuf> = vec![
-                                        std::path::PathBuf::from (module_path.clone ()),
-                                        std::path::PathBuf::from (main_mem_path),
-                                        std::path::PathBuf::from (checkpoint_mem_path),
-                                        std::path::PathBuf::from (input_img_path),
-                                    ];
-
-                                    #[cfg(feature = "no_live_migration")]
-                                    let files_to_compress: Vec<std::path::PathBuf> = vec![
-                                        std::path::PathBuf::from (module_path.clone ())
-                                    ];
-
-                                    let options: zip::write::FileOptions<()> = zip::write::FileOptions::default ()
-                                        .compression_method (zip::CompressionMethod::DEFLATE);
-
-                                    for file_path in &files_to_compress
-                                    {
-                                        let file = std::fs::File::open (file_path);
-                                        match file
-                                        {
-                                            Ok(mut file) =>
-                                                {
-                                                    // Rather ugly, but.
-                                                    let file_name =
-                                                        file_path.file_name ().unwrap ()
-                                                            .to_str ().unwrap ();
-
-                                                    #[cfg(feature = "print_log")]
-                                                    println! ("requests_coordination_loop - COMPRESSING = {}", file_name);
-
-                                                    zip.start_file (file_name, options).unwrap ();
-
-                                                    let mut buffer = Vec::new ();
-                                                    file.read_to_end (&mut buffer).unwrap ();
-
-                                                    zip.write_all (&buffer).unwrap ();
-                                                }
-                                            Err(_) =>
-                                                {
-                                                    // The file is missing, proceed.
-                                                    continue;
-                                                }
-                                        }
-                                    }
-                                    zip.finish ().unwrap ();
-
-                                    #[cfg(feature = "print_log")]
-                                    println! ("requests_coordination_loop - FILE COMPRESSED");
-
-                                    // Connect to the listener.
-                                    let dst = msg.payload_str ()
-                                        .parse::<String> ()
-                                        .expect ("Unable to parse message into String");
-
-                                    #[cfg(feature = "print_log")]
-                                    println! ("requests_coordination_loop - dst is {}", dst);
-
-                                    let mut writer = std::net::TcpStream::connect (dst)
-                                        .expect ("Unable to bind to address");
-                                    let mut buffer = [0; 512];
-                                    let mut compressed_file =
-                                        std::fs::OpenOptions::new ()
-                                            .read (true)
-                                            .open (&zip_archive_path)
-                                            .expect ("Unable to open compressed_file. ");
-                                    loop
-                                    {
-                                        let n = compressed_file.read (&mut buffer)?;
-                                        if n == 0
-                                        {
-                                            writer.shutdown (std::net::Shutdown::Both)?;
-                                            break;
-                                        }
-                                        writer.write_all (&buffer[..n])?;
-                                    }
-
-                                    #[cfg(feature = "print_log")]
-                                    println! ("requests_coordination_loop - END TRANSMISSION");
-
-                                    // Remove the directory corresponding to the request.
-                                    let request_dir =
-                                        format! ("requests/{}_{}_req", self.application_index, request.get_index ());
-
-                                    #[cfg(feature = "print_log")]
-                                    println! ("requests_coordination_loop - REMOVE {}", request_dir);
-
-                                    std::fs::remove_dir_all (request_dir).unwrap ();
-                                    std::fs::remove_file (zip_archive_path).unwrap ();
+                                    mqtt_utils::reject_payload (&msg, &error);
+                                    continue;
                                 }
-                            None =>
+                        };
+
+                        // Only the src node of a concluded negotiation sends the request.
+                        let (request, dest_node) = match sessions.get (&message_address.request)
+                        {
+                            Some (session) if !session.is_negotiating () && session.src_node == self.node_index =>
+                                (session.request, session.dest_node.expect ("Concluded session")),
+                            _ =>
                                 {
-                                    // Do nothing.
+                                    mqtt_utils::reject_payload (&msg, &format! ("request {} is not to be sent", message_address.request));
+                                    continue;
                                 }
+                        };
+                        #[cfg(feature = "migration_log")]
+                        let start_send = sessions[&message_address.request].start_send;
+                        sessions.remove (&message_address.request);
+
+                        // We need to remove the request from the
+                        // pool of requests served in this node for this
+                        // application.
+                        let (hop, hosted) =
+                        {
+                            let mut state =
+                                application_state.lock ().unwrap ();
+                            let hop = state.get_lineage_hop (request.get_id (), RequestState::Migrated);
+                            let hosted = state.get_request (request.get_id ()).copied ();
+                            if let Err (error) = state.remove_request (request.get_id (), RequestState::Migrated)
+                            {
+                                eprintln! ("requests_coordination_loop - {}", error);
+                            }
+                            drop (state);
+                            (hop, hosted)
+                        };
+
+                        // Then, update the barrier for the sporadic server.
+                        {
+                            let (number_of_requests, cvar) = &*barrier;
+                            *number_of_requests.lock ().unwrap () -= 1;
+                            cvar.notify_all ();
                         }
 
-                        #[cfg(feature = "migration_log")]
+                        // Record the stay of the request on this node in its
+                        // lineage, then send its folder in the background.
+                        let request_dir = format! ("requests/{}", request.get_id ().folder_name ());
+                        if let Some (hop) = hop
+                            && let Err (error) = request_transfer::append_lineage (&request_dir, &hop)
                         {
-                            let send_time = linux_utils::get_completion_time (start_send);
-                            log_writer::save_send_time (send_time);
+                            eprintln! ("requests_coordination_loop - unable to record the lineage of request {}: {}", request.get_id (), error);
                         }
+                        negotiation::send_request (hosted.unwrap_or (request),
+                                                   &self.data_server,
+                                                   message_address.address,
+                                                   dest_node,
+                                                   application_state.clone (),
+                                                   barrier.clone (),
+                                                   #[cfg(feature = "migration_log")]
+                                                   start_send);
                     }
-                    // federation/dst/i -> region_index.
+                    // federation/dst/i -> MessageRegion.
                     else if msg.topic () == federation_dst
                     {
 
                         #[cfg(feature = "print_log")]
                         println! ("requests_coordination_loop - federation/dst RECEIVE");
 
-                        #[cfg(feature = "migration_log")]
-                        unsafe
-                            {
-                                libc::clock_gettime (libc::CLOCK_MONOTONIC, &mut start_receive);
-                            }
-
-                        let region_index : usize = msg.payload_str ().parse().unwrap ();
-
-                        match incoming_request
+                        let message_region = match protocol::decode::<MessageRegion> (msg.payload ())
                         {
-                            Some (request) =>
+                            Ok (message_region) => message_region,
+                            Err (error) =>
                                 {
-
-                                    // First, we need to accept the request, adding it to
-                                    // the pool of requests served in this node for this
-                                    // application.
-                                    // To do so, we need to modify the application state.
-                                    {
-                                        let mut request = request;
-                                        request.set_region (region_index);
-                                        let mut state =
-                                            application_state.lock ().unwrap ();
-                                        state.add_request (request);
-                                        drop (state);
-                                    }
-
-                                    // Then receive the bytecode (and checkpoint).
-
-                                    // Open a TCP stream for receiving the data.
-                                    let listener =
-                                        std::net::TcpListener::bind (self.ip_and_port.to_string ())
-                                        .expect ("Unable to bind to address");
-
-                                    #[cfg(feature = "print_log")]
-                                    println! ("requests_coordination_loop - prepare message for Node {}", src_node.unwrap_or(999));
-
-                                    // Prepare the message to signal the sender that you are ready.
-                                    let msg = mqtt::Message::new (
-                                        format! ("federation/src/{}", src_node.expect ("Missing src node. ")),
-                                        self.ip_and_port.to_string (),
-                                        paho_mqtt::QOS_1);
-
-                                    #[cfg(feature = "print_log")]
-                                    println! ("requests_coordination_loop - START RECEIVING");
-
-                                    // Connect to the src and receive the data (or
-                                    // wait for it).
-                                    let compressed_file_name =
-                                        format! ("{}_{}_req.zip", self.application_index, request.get_index ());
-                                    let mut compressed_file = std::fs::OpenOptions::new ()
-                                        .append (true)
-                                        .write (true)
-                                        .read (true)
-                                        .create (true)
-                                        .open (&compressed_file_name)
-                                        .expect ("Unable to create compressed_file. ");
-
-                                    self.client.publish (msg).await?;
-                                    'handle_connection: for stream in listener.incoming ()
-                                    {
-                                        match stream 
-                                        {
-                                            Ok (mut stream) => 
-                                                {
-                                                    // Then loop on the incoming data from the stream.
-                                                    let mut buffer = [0; 512];
-                                                    loop
-                                                    {
-
-                                                        let n = stream.read (&mut buffer)?;
-                                                        compressed_file.write_all (&buffer[0..n])?;
-
-                                                        #[cfg(feature = "print_log")]
-                                                        println! ("requests_coordination_loop - NEW CHUNK of size {}", n);
-
-                                                        if n == 0
-                                                        {
-                                                            break 'handle_connection;
-                                                        }
-                                                    }
-                                                }
-                                            Err (e) =>
-                                                {
-                                                    eprintln! ("Connection failed: {e}")
-                                                }
-                                        }
-                                    }
-
-                                    #[cfg(feature = "print_log")]
-                                    println! ("requests_coordination_loop - compressed FILE RECEIVED");
-
-                                    // Then decompress the file as a folder.
-                                    let fname : &std::path::Path =
-                                        std::path::Path::new (&compressed_file_name);
-                                    let file  : std::fs::File    =
-                                        std::fs::File::open (fname).unwrap ();
-
-                                    let mut archive =
-                                        zip::ZipArchive::new (file).unwrap ();
-
-                                    #[cfg(feature = "print_log")]
-                                    println! ("requests_coordination_loop - archive len = {}", archive.len ());
-
-                                    // Add the folder path.
-                                    let request_folder =
-                                        format! ("requests/{}_{}_req", self.application_index, request.get_index ());
-                                    for i in 0..archive.len ()
-                                    {
-                                        let mut file = archive.by_index (i).unwrap ();
-                                        let outpath : std::path::PathBuf= match file.enclosed_name ()
-                                        {
-                                            Some (path) => format! ("{}/{}", request_folder, path.display ()).into (),
-                                            None => continue,
-                                        };
-
-                                        if file.is_dir ()
-                                        {
-
-                                            #[cfg(feature = "print_log")]
-                                            println! ("requests_coordination_loop - File {} extracted to \"{}\"", i, outpath.display ());
-
-                                            std::fs::create_dir_all (&outpath).unwrap ();
-                                        }
-                                        else
-                                        {
-
-                                            #[cfg(feature = "print_log")]
-                                            println! ("requests_coordination_loop - File {} extracted to \"{}\" ({} bytes)", i, outpath.display (), file.size ());
-
-                                            if let Some (p) = outpath.parent ()
-                                            {
-                                                if !p.exists ()
-                                                {
-                                                    std::fs::create_dir_all (p).unwrap ();
-                                                }
-                                            }
-                                            let mut outfile = std::fs::File::create (&outpath)
-                                                .unwrap ();
-                                            std::io::copy (&mut file, &mut outfile).unwrap ();
-                                        }
-                                    }
-
-                                    // Remove the archive after decompressing it.
-                                    std::fs::remove_file (fname).unwrap ();
-
-                                    #[cfg(feature = "print_log")]
-                                    println! ("requests_coordination_loop - FILE DECOMPRESSED");
-
-                                    // Finally, update the barrier of the sporadic server.
-                                    {
-                                        let (number_of_requests, barrier) = &*barrier;
-                                        *number_of_requests.lock ().unwrap () += 1;
-                                        barrier.notify_all ();
-                                    }
-
-                                    // This final instruction allows the node to start a new
-                                    // ADMM execution.
-                                    incoming_request = None;
-
-                                    #[cfg(feature = "print_log")]
-                                    println! ("requests_coordination_loop - incoming_request = None");
-
+                                    mqtt_utils::reject_payload (&msg, &error);
+                                    continue;
                                 }
-                            None =>
+                        };
+
+                        // Only the destination of a concluded negotiation receives the request.
+                        let (mut request, src_node, memory) = match sessions.get (&message_region.request)
+                        {
+                            Some (session) if session.dest_node == Some (self.node_index) =>
+                                (session.request, session.src_node, session.memory),
+                            _ =>
                                 {
-                                    // Do nothing.
+                                    mqtt_utils::reject_payload (&msg, &format! ("request {} is not expected", message_region.request));
+                                    continue;
                                 }
-                        }
-
-                        #[cfg(feature = "migration_log")]
+                        };
+                        sessions.remove (&message_region.request);
+
+                        // First, we need to accept the request, adding it to
+                        // the pool of requests served in this node for this
+                        // application.
+                        // It is accounted for the memory measured by the src node
+                        // until it is instantiated here.
+                        request.set_region (message_region.region);
                         {
-                            let receive_time = linux_utils::get_completion_time (start_receive);
-                            log_writer::save_receive_time (receive_time);
+                            let mut state = application_state.lock ().unwrap ();
+                            state.add_incoming_request (request);
+                            state.set_measured_memory (request.get_id (), memory);
                         }
+
+                        // Then receive the bytecode (and checkpoint) in the
+                        // background, and signal the sender that you are ready.
+
+                        #[cfg(feature = "print_log")]
+                        println! ("requests_coordination_loop - START RECEIVING from Node {}", src_node);
+
+                        negotiation::receive_request (request.get_id (),
+                                                      src_node,
+                                                      &self.data_server,
+                                                      self.limits.session_timeout,
+                                                      application_state.clone (),
+                                                      barrier.clone ());
+                        let src_topic = federation_topic (self.application_index, &format! ("src/{}", src_node));
+                        let message_address = MessageAddress { request : request.get_id (), address : self.ip_and_port.to_string () };
+                        self.link.send (src_topic, message_address.into_message ()).await?;
                     }
                     // federation/node_available -> node_index
                     else if msg.topic () == "federation/node_available"
@@ -881,43 +790,38 @@ impl ControlSystem
 
                         // Pick the request that would benefit the most from
                         // a migration.
-                        let state =
-                            application_state.lock ().unwrap ();
+                        let message_request =
+                        {
+                            let state =
+                                application_state.lock ().unwrap ();
 
-                        #[cfg(feature = "print_log")]
-                        println! ("requests_coordination_loop - state.requests_by_dct.len () = {}", state.requests_by_dct.len ());
+                            #[cfg(feature = "print_log")]
+                            println! ("requests_coordination_loop - state.requests_by_dct.len () = {}", state.requests_by_dct.len ());
 
-                        match state.requests_by_dct.first ()
-                        {
-                            Some (&request_index) =>
-                                {
-                                    // Check if the computation has enough remaining computation
-                                    // to benefit from a migration.
-                                    if state.is_request_migratable (request_index)
+                            // Check if the computation has enough remaining computation
+                            // to benefit from a migration.
+                            state.requests_by_dct.first ()
+                                .filter (|&&request_id| state.is_request_migratable (request_id))
+                                .map (|&request_id|
                                     {
-                                        // Start a migration.
-                                        let request = *state.get_request (request_index)
-                                            .expect ("Unable to find request from request_index");
-                                        let message_request =
-                                            MessageRequest::new (self.node_index, request);
-                                        let msg = mqtt::Message::new (
-                                            "federation/migration".to_string (),
-                                            message_request.to_string (),
-                                            paho_mqtt::QOS_1);
-                                        self.client.publish (msg);
-                                    }
-                                }
-                            None =>
-                                {
-                                    // Do nothing.
-                                }
+                                        let request = *state.get_request (request_id)
+                                            .expect ("Unable to find request from request_id");
+                                        let members = self.membership.lock ().unwrap ().live (self.application_index);
+                                        MessageRequest::new (self.node_index, request, members, state.get_needed_memory_of_request (&request))
+                                    })
+                        };
+
+                        // Start a migration.
+                        if let Some (message_request) = message_request
+                        {
+                            self.link.send (federation_migration.clone (), message_request.into_message ()).await?;
                         }
                     }
                 }
             }
             
             // Explicit return type for the async block. 
-            Ok::<(), mqtt::Error> (())
+            Ok::<(), BusError> (())
         })
         {
            eprintln! ("requests_monitoring_loop - error creating the client: {:?}", err);
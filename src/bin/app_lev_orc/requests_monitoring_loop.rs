@@ -2,9 +2,12 @@
 /*       REQUESTS MONITORING LOOP      */
 /***************************************/
 
-use paho_mqtt::{self as mqtt};
+use crate::distance_metric::DistanceMetric;
 use crate::linux_utils;
-use crate::state::{should_migrate, ApplicationState, MessageRequest};
+use crate::membership::Membership;
+use crate::mqtt_utils::{federation_topic, MqttLink};
+use crate::protocol::{MessageDeadlineMiss, MessageRequest, Payload};
+use crate::state::{should_migrate, ApplicationState};
 
 /// Data and functions associated with the
 /// requests_monitoring_loop.
@@ -23,15 +26,22 @@ pub struct ControlSystem
     /// Affinity of this thread.
     affinity         : usize,
 
-    /// The MQTT client.
-    client           : mqtt::AsyncClient,
+    /// The MQTT connection of the node.
+    link             : MqttLink,
 
     /// The index of the current node.
     node_index       : usize,
 
     /// The index of the application.
-    #[allow(dead_code)]
     application_index : usize,
+
+    /// The metric of the distance between the desired attributes
+    /// of a request and the attributes of the node.
+    metric           : std::sync::Arc<dyn DistanceMetric>,
+
+    /// The live members of the federation, among which the
+    /// migrations are negotiated.
+    membership       : std::sync::Arc<std::sync::Mutex<Membership>>,
 }
 
 impl ControlSystem
@@ -42,40 +52,11 @@ impl ControlSystem
                 first_activation  : libc::timespec,
                 priority          : i32,
                 affinity          : usize,
-                broker_address    : String) -> Self
+                link              : MqttLink,
+                metric            : std::sync::Arc<dyn DistanceMetric>,
+                membership        : std::sync::Arc<std::sync::Mutex<Membership>>) -> Self
     {
-
-        #[cfg(feature = "print_log")]
-        println! ("request_monitoring_loop - new START");
-
-        // Initialization of the MQTT link.
-        let host = format! ("mqtt://{}:1883", broker_address).to_string ();
-
-        let client_id = format! ("node_{}_app_{}_rml", node_index, application_index);
-
-        // Create the client. Use an ID for a persistent session.
-        let create_opts = mqtt::CreateOptionsBuilder::new ()
-            .server_uri (host)
-            .client_id (client_id)
-            .finalize ();
-
-        // Create the subscriber connection.
-        let client = mqtt::AsyncClient::new (create_opts).unwrap_or_else (|e| 
-            {
-                panic! ("requests_monitoring_loop - error creating the client: {:?}", e);
-            }
-        );
-
-        client.set_disconnected_callback (|_, _props, reason|
-            {
-                panic! ("requests_monitoring_loop - server disconnected with reason: {}", reason);
-            }
-        );
-
-        #[cfg(feature = "print_log")]
-        println! ("request_monitoring_loop - new END");
-
-        Self { period, first_activation, priority, affinity, client, node_index, application_index }
+        Self { period, first_activation, priority, affinity, link, node_index, application_index, metric, membership }
     }
 
     /// Start the requests monitoring loop.
@@ -127,26 +108,40 @@ impl ControlSystem
                 // Here we access the application state as mutable.
                 let mut app_state = application_state.lock ().unwrap ();
                 let node_state = app_state.node_state;
-                let requests = &mut app_state.requests;
+                let requests = app_state.requests.clone ();
 
-                for &mut mut request in requests
+                for mut request in requests
                 {
-                    if should_migrate (&request, &node_state)
+                    // Requests already leaving are not considered.
+                    if app_state.is_request_migratable (request.get_id ())
+                        && should_migrate (&request, &node_state, self.metric.as_ref ())
                     {
                         // Update the application state.
                         request.set_should_migrate (true);
 
-                        // Then trigger a migration.
+                        // Then trigger a migration, among the live members.
+                        let members = self.membership.lock ().unwrap ().live (self.application_index);
                         let message_request =
-                            MessageRequest::new (self.node_index, request);
-                        let msg = mqtt::Message::new (
-                            "federation/migration".to_string (),
-                            message_request.to_string (),
-                            paho_mqtt::QOS_1);
-                        self.client.publish (msg);
+                            MessageRequest::new (self.node_index, request, members, app_state.get_needed_memory_of_request (&request));
+                        self.link.send (federation_topic (self.application_index, "migration"),
+                                        message_request.into_message ());
                     }
                 }
 
+                // Report the requests that have missed their deadline.
+                for request in app_state.take_deadline_misses ()
+                {
+                    let message_miss = MessageDeadlineMiss
+                    {
+                        node     : self.node_index,
+                        request  : request.get_id (),
+                        lateness : request.get_deadline ().elapsed ().as_millis (),
+                    };
+                    eprintln! ("request_monitoring_loop - request {} MISSED its deadline", request.get_id ());
+                    self.link.send (federation_topic (self.application_index, "deadline_miss"),
+                                    message_miss.into_message ());
+                }
+
                 // Drop the mutex variable, forcing unlocking.
                 drop (app_state);
             }
@@ -0,0 +1,118 @@
+/***************************************/
+/*              SHUTDOWN               */
+/***************************************/
+
+// On SIGTERM or SIGINT the node stops accepting requests and
+// hands off the ones it hosts: the request being served is
+// checkpointed through the should_migrate path, then each
+// migratable request is negotiated with the other nodes.
+// The node exits once no request is left, or when the drain
+// timeout expires. A second signal exits immediately.
+
+use futures::executor::block_on;
+use crate::membership::Membership;
+use crate::mqtt_utils::{federation_topic, MqttLink};
+use crate::protocol::{MessageRequest, Payload};
+use crate::state::{ApplicationState, RequestId};
+
+/// Set by the signal handler.
+static SHUTDOWN_REQUESTED : std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new (false);
+
+/// Interval between two checks of the drain.
+const POLL_PERIOD : std::time::Duration = std::time::Duration::from_millis (100);
+
+/// Time left to the federation to migrate a request before
+/// publishing it again.
+const RETRY_PERIOD : std::time::Duration = std::time::Duration::from_millis (2_000);
+
+extern "C" fn handle_signal (_signal : libc::c_int)
+{
+    if SHUTDOWN_REQUESTED.swap (true, std::sync::atomic::Ordering::SeqCst)
+    {
+        // Second signal: do not wait for the drain.
+        unsafe { libc::_exit (130) };
+    }
+}
+
+/// Install the handler of SIGTERM and SIGINT.
+pub fn install_signal_handlers ()
+{
+    unsafe
+        {
+            let handler = handle_signal as extern "C" fn (libc::c_int) as libc::sighandler_t;
+            libc::signal (libc::SIGTERM, handler);
+            libc::signal (libc::SIGINT, handler);
+        }
+}
+
+/// Block until a shutdown is requested.
+pub fn wait_for_signal ()
+{
+    while !SHUTDOWN_REQUESTED.load (std::sync::atomic::Ordering::SeqCst)
+    {
+        std::thread::sleep (POLL_PERIOD);
+    }
+}
+
+/// Hand off the requests of the applications hosted by the node, for
+/// at most `drain_timeout'. Return the number of requests left.
+pub fn drain (node_index         : usize,
+              application_states : &[(usize, std::sync::Arc<std::sync::Mutex<ApplicationState>>)],
+              link               : &MqttLink,
+              membership         : &std::sync::Mutex<Membership>,
+              drain_timeout      : std::time::Duration) -> u32
+{
+    println! ("shutdown - DRAINING");
+
+    // Stop accepting requests.
+    for (_, application_state) in application_states
+    {
+        application_state.lock ().unwrap ().draining = true;
+    }
+
+    let deadline = std::time::Instant::now () + drain_timeout;
+    let mut last_attempts : Vec<Option<(RequestId, std::time::Instant)>> = vec![None; application_states.len ()];
+    loop
+    {
+        let mut requests_left = 0;
+        for ((application_index, application_state), last_attempt) in application_states.iter ().zip (&mut last_attempts)
+        {
+            let state = application_state.lock ().unwrap ();
+            requests_left += state.number_of_requests;
+
+            // Only the request being served can be checkpointed, the
+            // others follow once it has left. A request that is past
+            // its last migratable region completes here.
+            let request = match state.get_served_request ().and_then (|id| state.get_request (id))
+            {
+                Some (&request) if state.is_request_migratable (request.get_id ()) => request,
+                _ => continue,
+            };
+            let memory = state.get_needed_memory_of_request (&request);
+            drop (state);
+
+            let is_pending = matches! (last_attempt, Some ((id, time))
+                                       if *id == request.get_id () && time.elapsed () < RETRY_PERIOD);
+            if !is_pending
+            {
+                #[cfg(feature = "print_log")]
+                println! ("shutdown - MIGRATE request {} of application {}", request.get_id (), application_index);
+
+                // A migration message not published is tried again at the next check.
+                let members = membership.lock ().unwrap ().live (*application_index);
+                let message_request = MessageRequest::new (node_index, request, members, memory);
+                match block_on (link.send (federation_topic (*application_index, "migration"), message_request.into_message ()))
+                {
+                    Ok (()) => *last_attempt = Some ((request.get_id (), std::time::Instant::now ())),
+                    Err (error) => eprintln! ("shutdown - unable to migrate request {}: {}", request.get_id (), error),
+                }
+            }
+        }
+
+        if requests_left == 0 || std::time::Instant::now () >= deadline
+        {
+            return requests_left;
+        }
+        std::thread::sleep (POLL_PERIOD);
+    }
+}
@@ -3,18 +3,16 @@
 /*         ( I N S T A N C E )         */
 /***************************************/
 use wasmtime_wasi::{DirPerms, FilePerms};
-use crate::state::{ApplicationState, Request};
+use crate::state::{ApplicationState, Request, RequestId, RequestState};
+use crate::configuration_loader::RequestManifest;
 use sporadic_server;
 use sporadic_server::{SporadicServer, SporadicServerController};
-use crate::{linux_utils, log_writer, main};
+use crate::{linux_utils, log_writer, main, request_transfer};
 
 /// Once a node accepts a request, the request
 /// is executed by a thread.
 pub struct ControlSystem
 {
-    /// Index of the application.
-    application_index : usize,
-
     /// Budget of the sporadic server task.
     budget            : u64,
 
@@ -33,8 +31,7 @@ pub struct ControlSystem
 
 impl ControlSystem
 {
-    pub fn new (application_index: usize,
-                budget           : u64,
+    pub fn new (budget           : u64,
                 period           : u64,
                 priority         : usize,
                 affinity         : usize,
@@ -42,7 +39,6 @@ impl ControlSystem
     {
         Self
         {
-            application_index,
             budget,
             period,
             priority,
@@ -54,7 +50,7 @@ impl ControlSystem
     pub fn start (&mut self,
                   application_state : std::sync::Arc<std::sync::Mutex<ApplicationState>>,
                   barrier           : std::sync::Arc<(std::sync::Mutex<u8>, std::sync::Condvar)>,
-                  checkpoint_barrier: std::sync::Arc<(std::sync::Mutex<bool>, std::sync::Condvar)>)
+                  job_ends          : futures::channel::mpsc::UnboundedSender<RequestId>)
     {
 
         #[cfg(feature = "print_log")]
@@ -99,10 +95,9 @@ impl ControlSystem
         handles.push (controller_handle);
 
         // Server thread.
-        let mut workload = WasmWorkload::new (self.application_index,
-                                                            self.request_directory.clone (),
+        let mut workload = WasmWorkload::new (self.request_directory.clone (),
                                                             application_state.clone (),
-                                                            checkpoint_barrier.clone ());
+                                                            job_ends);
         let srv_controller = controller.clone ();
         let server_handle = std::thread::spawn (move ||
             {
@@ -179,17 +174,15 @@ impl ControlSystem
 // provide an implementation of a Workload.
 struct WasmWorkload
 {
-    /// Index of the application.
-    application_index : usize,
-
     /// Path to the request directory.
     request_directory : String,
 
     /// The state of the application.
     application_state: std::sync::Arc<std::sync::Mutex<ApplicationState>>,
 
-    /// Whether or not a checkpoint is ready.
-    checkpoint_barrier: std::sync::Arc<(std::sync::Mutex<bool>, std::sync::Condvar)>,
+    /// Where the end of the job of a request is posted, for the
+    /// coordination loop (e.g. a request checkpointed to migrate).
+    job_ends          : futures::channel::mpsc::UnboundedSender<RequestId>,
 
     /// The current request being served.
     current_request   : std::option::Option<Request>
@@ -197,20 +190,58 @@ struct WasmWorkload
 
 impl WasmWorkload
 {
-    fn new(application_index : usize,
-           request_directory : String,
+    fn new(request_directory : String,
            application_state : std::sync::Arc<std::sync::Mutex<ApplicationState>>,
-           checkpoint_barrier: std::sync::Arc<(std::sync::Mutex<bool>, std::sync::Condvar)>) -> Self
+           job_ends          : futures::channel::mpsc::UnboundedSender<RequestId>) -> Self
     {
         Self
         {
-            application_index,
             request_directory,
             application_state,
-            checkpoint_barrier,
+            job_ends,
             current_request: None,
         }
     }
+
+    /// Remove a request that has left the node with `outcome'.
+    fn finish (&self, request_id : RequestId, outcome : RequestState)
+    {
+        if let Err (error) = self.application_state.lock ().unwrap ().remove_request (request_id, outcome)
+        {
+            eprintln! ("sporadic_server - {}", error);
+        }
+    }
+
+    /// Notify the end of the job of `request_id': a request waiting
+    /// for its checkpoint is now ready to migrate (or has ended).
+    fn job_ended (&self, request_id : RequestId)
+    {
+        // The coordination loop is gone when the node exits.
+        let _ = self.job_ends.unbounded_send (request_id);
+    }
+
+    /// Remove a request that has ended on this node with `outcome',
+    /// and its folder. Its lineage is kept in {folder}.lineage.
+    fn retire (&self, path_to_req_folder : &str, request_id : RequestId, outcome : RequestState)
+    {
+        let hop = self.application_state.lock ().unwrap ().get_lineage_hop (request_id, outcome);
+        let lineage = format! ("{}/{}", path_to_req_folder, request_transfer::LINEAGE_FILE_NAME);
+        let kept = hop.map_or (Ok (()), |hop| request_transfer::append_lineage (path_to_req_folder, &hop))
+            .and_then (|_| std::fs::rename (&lineage, format! ("{}.lineage", path_to_req_folder)));
+        if let Err (error) = kept
+        {
+            eprintln! ("sporadic_server - unable to keep the lineage of request {}: {}", request_id, error);
+        }
+
+        #[cfg(feature = "print_log")]
+        println! ("sporadic_server - request {} {}, lineage in {}.lineage", request_id, outcome, path_to_req_folder);
+
+        // Remove the directory.
+        std::fs::remove_dir_all (path_to_req_folder).unwrap ();
+
+        // Then remove the request from the list.
+        self.finish (request_id, outcome);
+    }
 }
 
 impl sporadic_server::Workload for WasmWorkload
@@ -220,7 +251,10 @@ impl sporadic_server::Workload for WasmWorkload
         #[cfg(feature = "migration_log")]
         let mut start_request    = libc::timespec { tv_sec: 0, tv_nsec: 0 };
 
-        match self.application_state.lock ().unwrap ().requests.first ()
+        // Serve the request with the earliest deadline (EDF), unless
+        // a request is already being served.
+        let mut app_state = self.application_state.lock ().unwrap ();
+        match app_state.get_served_request ().and_then (|request_id| app_state.get_request (request_id))
         {
             None =>
                 {
@@ -240,8 +274,14 @@ impl sporadic_server::Workload for WasmWorkload
             Some (&request) =>
                 {
                     self.current_request = Some(request);
+                    if app_state.get_request_state (request.get_id ()) == Some (RequestState::Queued)
+                    {
+                        app_state.set_request_state (request.get_id (), RequestState::Running)
+                            .expect ("Unable to start a queued request");
+                    }
                 }
         }
+        drop (app_state);
 
         #[cfg(feature = "migration_log")]
         unsafe
@@ -255,21 +295,32 @@ impl sporadic_server::Workload for WasmWorkload
         {
             wasi              : wasmtime_wasi::preview1::WasiP1Ctx,
             application_state : std::sync::Arc<std::sync::Mutex<ApplicationState>>,
-            request_index     : usize,
+            request_id        : RequestId,
             main_memory_file        : Option<String>,
             checkpoint_memory_file  : Option<String>,
+            memory_meter      : MemoryMeter,
         }
 
         // Create the engine.
         let engine = wasmtime::Engine::default ();
 
         // Produce the path to the request folder.
-        let path_to_req_folder = format! ("{}/{}_{}_req",
-                                          self.request_directory.to_string (),
-                                          self.application_index,
-                                          current_request.get_index ());
-        // Load the module.
-        let path_to_module = format! ("{}/{}", path_to_req_folder.to_string (), "module.wasm");
+        let path_to_req_folder = format! ("{}/{}",
+                                          self.request_directory,
+                                          current_request.get_id ().folder_name ());
+        // Load the module listed in the manifest of the request.
+        let manifest = match RequestManifest::load (&path_to_req_folder, current_request.get_id ())
+        {
+            Ok (manifest) => manifest,
+            Err (error) =>
+                {
+                    eprintln! ("sporadic_server - invalid request: {}", error);
+                    self.finish (current_request.get_id (), RequestState::Failed);
+                    self.job_ended (current_request.get_id ());
+                    return;
+                }
+        };
+        let path_to_module = format! ("{}/{}", path_to_req_folder.to_string (), manifest.module);
         let module =
             wasmtime::Module::from_file (&engine, path_to_module)
                 .expect ("Failed to load wasm file. ");
@@ -283,20 +334,20 @@ impl sporadic_server::Workload for WasmWorkload
         linker.func_wrap ("host", "should_migrate", |mut caller: wasmtime::Caller<'_, MyState>|
             {
                 let mut result    : i32   = 0;
-                let request_index : usize = caller.data ().request_index;
+                let request_id : RequestId = caller.data ().request_id;
 
                 #[cfg(feature = "periodic_activation")]
-                println! ("request {} - should_migrate START", request_index);
+                println! ("request {} - should_migrate START", request_id);
 
                 {
                     let mut app_state =
                         caller.data_mut ().application_state.lock ().unwrap ();
 
                     // Update request.current_region.
-                    app_state.advance_cur_region_of_request (request_index);
+                    app_state.advance_cur_region_of_request (request_id);
 
                     // Check if a migration is needed. 
-                    if app_state.get_should_migrate_of_request (request_index)
+                    if app_state.get_should_migrate_of_request (request_id)
                     {
                         result = 1;
                     }
@@ -306,9 +357,9 @@ impl sporadic_server::Workload for WasmWorkload
                 }
 
                 #[cfg(feature = "periodic_activation")]
-                println! ("request {} - should_migrate = {}", request_index, result);
+                println! ("request {} - should_migrate = {}", request_id, result);
 
-                println! ("request {} - should_migrate = {}", request_index, result);
+                println! ("request {} - should_migrate = {}", request_id, result);
 
                 result
             }
@@ -346,14 +397,14 @@ impl sporadic_server::Workload for WasmWorkload
             .expect("Unable to find checkpoint_mem_export. ");
 
         #[cfg(feature = "periodic_activation")]
-        let request_index = current_request.get_index ();
+        let request_id = current_request.get_id ();
 
         // Add the restore_memory, which do nothing right now.
         linker.func_wrap ("host", "restore_memory", move |mut caller: wasmtime::Caller<'_, MyState>|
             {
 
                 #[cfg(feature = "periodic_activation")]
-                println! ("request {} - restore_memory START", request_index);
+                println! ("request {} - restore_memory START", request_id);
 
                 let main_memory = match caller.get_module_export (&main_mem_export)
                 {
@@ -407,7 +458,7 @@ impl sporadic_server::Workload for WasmWorkload
                 }
 
                 #[cfg(feature = "periodic_activation")]
-                println! ("request {} - restore_memory END", request_index);
+                println! ("request {} - restore_memory END", request_id);
 
             }
         )
@@ -429,15 +480,30 @@ impl sporadic_server::Workload for WasmWorkload
         {
             wasi              : wasi_ctx,
             application_state : self.application_state.clone (),
-            request_index     : current_request.get_index (),
+            request_id        : current_request.get_id (),
             main_memory_file,
             checkpoint_memory_file,
+            memory_meter      : MemoryMeter::new (self.application_state.clone (), current_request.get_id ()),
         };
         let mut store = wasmtime::Store::new (&engine, state);
+        store.limiter (|state| &mut state.memory_meter);
+
+        // Instantiate the module. It fails when its initial memory
+        // exceeds the memory available to the request.
+        let instance = match pre.instantiate (&mut store)
+        {
+            Ok (instance) => instance,
+            Err (error) =>
+                {
+                    eprintln! ("sporadic_server - unable to instantiate request {}: {}", current_request.get_id (), error);
 
-        // Instantiate the module.
-        let instance = pre.instantiate (&mut store)
-            .expect ("instantiate failed. ");
+                    // Remove the directory.
+                    std::fs::remove_dir_all (path_to_req_folder).unwrap ();
+                    self.finish (current_request.get_id (), RequestState::Failed);
+                    self.job_ended (current_request.get_id ());
+                    return;
+                }
+        };
 
         // Invoke the start function of the module.
         let func = instance.get_func (&mut store, "_start")
@@ -457,20 +523,25 @@ impl sporadic_server::Workload for WasmWorkload
                     #[cfg(feature = "print_log")]
                     println! ("sporadic_server - REGULAR END");
 
-                    // Remove the directory.
-                    std::fs::remove_dir_all (path_to_req_folder).unwrap ();
+                    // Check the desired completion time.
+                    let lateness = std::time::Instant::now ().saturating_duration_since (current_request.get_deadline ());
+                    if !lateness.is_zero ()
                     {
-                        // Then remove the request from the list.
-                        self.application_state
-                            .lock ()
-                            .unwrap ()
-                            .remove_request (current_request.get_index ())
+                        eprintln! ("sporadic_server - request {} COMPLETED {} ms after its deadline",
+                                   current_request.get_id (), lateness.as_millis ());
                     }
+
+                    self.retire (&path_to_req_folder, current_request.get_id (), RequestState::Completed);
                 }
             Err (error) =>
                 {
-                    let trap = *error.downcast_ref::<wasmtime::Trap> ().unwrap ();
-                    if trap == wasmtime::Trap::UnreachableCodeReached
+                    // A checkpoint ends with a trap, which is an error
+                    // if no checkpoint has been requested.
+                    let is_checkpoint = error.downcast_ref::<wasmtime::Trap> () == Some (&wasmtime::Trap::UnreachableCodeReached)
+                        && self.application_state.lock ().unwrap ()
+                            .set_request_state (current_request.get_id (), RequestState::InTransit)
+                            .is_ok ();
+                    if is_checkpoint
                     {
                         #[cfg(feature = "print_log")]
                         println! ("sporadic_server - CHECKPOINT occurred");
@@ -498,30 +569,19 @@ impl sporadic_server::Workload for WasmWorkload
 
                         #[cfg(feature = "print_log")]
                         println! ("sporadic_server - memories SAVED");
-
-                        // Notify that the computation is ready to migrate.
-                        let (barrier, cvar) = &*self.checkpoint_barrier;
-                        *barrier.lock ().unwrap () = true;
-                        cvar.notify_all ();
                     }
                     else
                     {
                         #[cfg(feature = "print_log")]
                         println! ("sporadic_server - ERROR");
 
-                        // Remove the directory.
-                        std::fs::remove_dir_all (path_to_req_folder).unwrap ();
-                        {
-                            // Then remove the request from the list.
-                            self.application_state
-                                .lock ()
-                                .unwrap ()
-                                .remove_request (current_request.get_index ())
-                        }
+                        self.retire (&path_to_req_folder, current_request.get_id (), RequestState::Failed);
                     }
                 }
         }
 
+        self.job_ended (current_request.get_id ());
+
         #[cfg(feature = "migration_log")]
         {
             let request_time = linux_utils::get_completion_time (start_request);
@@ -531,6 +591,66 @@ impl sporadic_server::Workload for WasmWorkload
     }
 }
 
+/// Measures the linear memory of a request while it runs, so
+/// that the node accounts for the memory actually used, and
+/// denies the growth beyond the memory available to the request.
+struct MemoryMeter
+{
+    /// The state of the application.
+    application_state : std::sync::Arc<std::sync::Mutex<ApplicationState>>,
+
+    /// Identifier of the running request.
+    request_id        : RequestId,
+
+    /// Size of the linear memories of the instance, in bytes.
+    memory_size       : usize,
+}
+
+impl MemoryMeter
+{
+    fn new (application_state : std::sync::Arc<std::sync::Mutex<ApplicationState>>,
+            request_id        : RequestId) -> Self
+    {
+        Self { application_state, request_id, memory_size : 0 }
+    }
+}
+
+impl wasmtime::ResourceLimiter for MemoryMeter
+{
+    fn memory_growing (&mut self,
+                       current  : usize,
+                       desired  : usize,
+                       _maximum : Option<usize>) -> wasmtime::Result<bool>
+    {
+        let memory_size = self.memory_size.saturating_sub (current).saturating_add (desired);
+        let memory      = u32::try_from (memory_size.div_ceil (1024)).unwrap_or (u32::MAX);
+
+        let mut app_state = self.application_state.lock ().unwrap ();
+        let available = app_state.available_memory_for (self.request_id);
+        if memory > available
+        {
+            eprintln! ("sporadic_server - request {} DENIED {} kB of memory ({} kB available)",
+                       self.request_id, memory, available);
+            return Ok (false);
+        }
+        app_state.set_measured_memory (self.request_id, memory);
+        self.memory_size = memory_size;
+
+        #[cfg(feature = "print_log")]
+        println! ("sporadic_server - request {} uses {} kB of memory", self.request_id, memory);
+
+        Ok (true)
+    }
+
+    fn table_growing (&mut self,
+                      _current : usize,
+                      _desired : usize,
+                      _maximum : Option<usize>) -> wasmtime::Result<bool>
+    {
+        Ok (true)
+    }
+}
+
 /// Utility function for configuring priority
 /// and affinity over a Linux system.
 fn set_linux_sched (priority: usize, affinity: usize)
@@ -3,27 +3,106 @@
 /*************************/
 use std::fmt::{Display, Formatter};
 
-/// The main information used to determine the state of
-/// the system in this experimentation is the physical
-/// location of the nodes, expressed as coordinates.
-#[derive(PartialEq, Clone, Copy)]
+use serde::{Deserialize, Serialize};
+
+use crate::distance_metric::DistanceMetric;
+use crate::journal::{Journal, JournalEntry};
+
+/// Error raised when parsing the state exchanged between nodes
+/// (coordinates, node states, requests).
+#[derive(Debug, PartialEq)]
+pub enum ParseStateError
+{
+    /// The number of fields differs from the expected one.
+    FieldCount { what : &'static str, expected : usize, found : usize },
+
+    /// A field cannot be converted to the expected type.
+    InvalidField { what : &'static str, field : &'static str, value : String },
+
+    /// The value of a field is not acceptable (e.g. a NaN coordinate).
+    OutOfRange { what : &'static str, field : &'static str, value : String },
+}
+
+impl Display for ParseStateError
+{
+    fn fmt (&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            ParseStateError::FieldCount { what, expected, found } =>
+                write! (f, "{}: expected {} fields, found {}", what, expected, found),
+            ParseStateError::InvalidField { what, field, value } =>
+                write! (f, "{}: invalid {} '{}'", what, field, value),
+            ParseStateError::OutOfRange { what, field, value } =>
+                write! (f, "{}: {} '{}' is out of range", what, field, value),
+        }
+    }
+}
+
+impl std::error::Error for ParseStateError {}
+
+/// Split `s' in exactly `expected' fields separated by `separator'.
+fn split_fields<'a> (what      : &'static str,
+                     s         : &'a str,
+                     separator : char,
+                     expected  : usize) -> Result<Vec<&'a str>, ParseStateError>
+{
+    let fields : Vec<&str> = s.split (separator).map (|field| field.trim ()).collect ();
+    if fields.len () != expected
+    {
+        return Err (ParseStateError::FieldCount { what, expected, found : fields.len () });
+    }
+    Ok (fields)
+}
+
+/// Convert a field, reporting its name on failure.
+fn parse_field<T : std::str::FromStr> (what  : &'static str,
+                                       field : &'static str,
+                                       value : &str) -> Result<T, ParseStateError>
+{
+    value.parse::<T> ().map_err (|_| ParseStateError::InvalidField { what, field, value : value.to_string () })
+}
+
+/// Convert a field that must be a finite number.
+fn parse_finite (what  : &'static str,
+                 field : &'static str,
+                 value : &str) -> Result<f32, ParseStateError>
+{
+    let number : f32 = parse_field (what, field, value)?;
+    if !number.is_finite ()
+    {
+        return Err (ParseStateError::OutOfRange { what, field, value : value.to_string () });
+    }
+    Ok (number)
+}
+
+/// The physical location of a node (device) in a plant,
+/// or the one desired by a request, in 2D or 3D.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct Coord
 {
     x : f32,
     y : f32,
+
+    /// Height, None for a 2D position.
+    z : Option<f32>,
 }
 
 impl Coord
 {
     pub fn new () -> Self
     {
-        Self { x: 0.0, y: 0.0 }
+        Self { x: 0.0, y: 0.0, z: None }
     }
 
-    #[allow(dead_code)]
     pub fn new_from (x : f32, y : f32) -> Self
     {
-        Self { x, y }
+        Self { x, y, z: None }
+    }
+
+    pub fn new_from_3d (x : f32, y : f32, z : f32) -> Self
+    {
+        Self { x, y, z: Some (z) }
     }
 
     pub fn get_x (&self) -> f32
@@ -35,53 +114,212 @@ impl Coord
     {
         self.y
     }
+
+    /// The height, 0 for a 2D position.
+    pub fn get_z (&self) -> f32
+    {
+        self.z.unwrap_or (0.0)
+    }
+
+    /// Check that the coordinates are finite (e.g. once decoded).
+    pub fn check (&self) -> Result<(), ParseStateError>
+    {
+        for (field, value) in [("x", Some (self.x)), ("y", Some (self.y)), ("z", self.z)]
+        {
+            if let Some (value) = value.filter (|value| !value.is_finite ())
+            {
+                return Err (ParseStateError::OutOfRange { what : "Coord", field, value : value.to_string () });
+            }
+        }
+        Ok (())
+    }
 }
 
 impl std::str::FromStr for Coord
 {
-    type Err = std::convert::Infallible;
+    type Err = ParseStateError;
 
-    /// The expected string: (f32,f32).
+    /// The expected string: (f32,f32) or (f32,f32,f32).
     fn from_str(s: &str) -> Result<Self, Self::Err>
     {
-        let trimmed_s = s.replace ('(', "").replace (')', "");
-        let coords : Vec<&str> = trimmed_s.split_terminator (',').collect ();
+        let trimmed_s = s.trim ().trim_start_matches ('(').trim_end_matches (')');
+        let coords = match split_fields ("Coord", trimmed_s, ',', 3)
+        {
+            Err (ParseStateError::FieldCount { found : 2, .. }) => split_fields ("Coord", trimmed_s, ',', 2)?,
+            coords => coords?,
+        };
 
-        match (coords.first(), coords.last())
+        Ok (Coord
         {
-            (Some (&x), Some (&y)) => Ok (
-                Coord
-                {
-                    x : f32::from_str (x).expect ("Failed to parse x coord. "),
-                    y : f32::from_str (y).expect ("Failed to parse y coord. ")
-                }
-            ),
-            _ => Ok (
-                Coord
+            x : parse_finite ("Coord", "x", coords[0])?,
+            y : parse_finite ("Coord", "y", coords[1])?,
+            z : coords.get (2).map (|z| parse_finite ("Coord", "z", z)).transpose ()?,
+        })
+    }
+}
+
+impl Display for Coord
+{
+    fn fmt (&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.z
+        {
+            Some (z) => write! (f, "({},{},{})", self.x, self.y, z),
+            None     => write! (f, "({},{})", self.x, self.y),
+        }
+    }
+}
+
+/// The attributes of a node, or the ones desired by a request:
+/// the position plus optional attributes of the device. An
+/// attribute that is not set is ignored by the distance metrics
+/// when desired, and counted as the farthest value when missing
+/// from the node (see distance_metric).
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Attributes
+{
+    /// Position in the plant.
+    pub position     : Coord,
+
+    /// Identifier of the zone of the plant.
+    pub zone         : Option<u32>,
+
+    /// Battery level, in [0, 1].
+    pub battery      : Option<f32>,
+
+    /// Quality of the network link, in [0, 1].
+    pub link_quality : Option<f32>,
+
+    /// CPU load, in [0, 1].
+    pub cpu_load     : Option<f32>,
+}
+
+impl Attributes
+{
+    pub fn new () -> Self
+    {
+        Self::new_from (Coord::new ())
+    }
+
+    /// Attributes made of the position only.
+    pub fn new_from (position : Coord) -> Self
+    {
+        Self
+        {
+            position,
+            zone         : None,
+            battery      : None,
+            link_quality : None,
+            cpu_load     : None,
+        }
+    }
+
+    /// Check that the attributes are acceptable (e.g. once decoded):
+    /// a finite position, and ratios in [0, 1].
+    pub fn check (&self) -> Result<(), ParseStateError>
+    {
+        self.position.check ()?;
+        for (field, ratio) in [("battery", self.battery), ("link_quality", self.link_quality), ("cpu_load", self.cpu_load)]
+        {
+            if let Some (ratio) = ratio.filter (|ratio| !(0.0..=1.0).contains (ratio))
+            {
+                return Err (ParseStateError::OutOfRange { what : "Attributes", field, value : ratio.to_string () });
+            }
+        }
+        Ok (())
+    }
+}
+
+/// Convert an attribute that must be in [0, 1].
+fn parse_ratio (field : &'static str, value : &str) -> Result<f32, ParseStateError>
+{
+    let ratio = parse_finite ("Attributes", field, value)?;
+    if !(0.0..=1.0).contains (&ratio)
+    {
+        return Err (ParseStateError::OutOfRange { what : "Attributes", field, value : value.to_string () });
+    }
+    Ok (ratio)
+}
+
+impl std::str::FromStr for Attributes
+{
+    type Err = ParseStateError;
+
+    /// The expected string: the position, optionally followed
+    /// by the other attributes, e.g. (f32,f32,f32){zone=u32,battery=f32}.
+    /// The other attributes are zone, battery, link_quality and cpu_load.
+    fn from_str (s: &str) -> Result<Self, Self::Err>
+    {
+        let (position, others) = match s.trim ().split_once ('{')
+        {
+            Some ((position, others)) =>
                 {
-                    x : -1.0,
-                    y : -1.0
+                    let others = others.strip_suffix ('}')
+                        .ok_or (ParseStateError::InvalidField { what  : "Attributes",
+                                                                field : "attributes",
+                                                                value : s.to_string () })?;
+                    (position, others)
                 }
-            ),
+            None => (s, ""),
+        };
+
+        let mut attributes = Attributes::new_from (position.parse ()?);
+        for attribute in others.split (',').map (|attribute| attribute.trim ()).filter (|attribute| !attribute.is_empty ())
+        {
+            let (name, value) = attribute.split_once ('=')
+                .map (|(name, value)| (name.trim (), value.trim ()))
+                .ok_or (ParseStateError::InvalidField { what  : "Attributes",
+                                                        field : "attribute",
+                                                        value : attribute.to_string () })?;
+            match name
+            {
+                "zone"         => attributes.zone         = Some (parse_field ("Attributes", "zone", value)?),
+                "battery"      => attributes.battery      = Some (parse_ratio ("battery", value)?),
+                "link_quality" => attributes.link_quality = Some (parse_ratio ("link_quality", value)?),
+                "cpu_load"     => attributes.cpu_load     = Some (parse_ratio ("cpu_load", value)?),
+                _ => return Err (ParseStateError::InvalidField { what  : "Attributes",
+                                                                 field : "attribute name",
+                                                                 value : name.to_string () }),
+            }
         }
+        Ok (attributes)
     }
 }
 
-impl Display for Coord
+impl Display for Attributes
 {
-    fn fmt (&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write! (f, "{}", format! ("({},{})", self.x, self.y))
+    /// Only the attributes that are set are written, so that a
+    /// position alone keeps the (f32,f32) format.
+    fn fmt (&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        let mut others : Vec<String> = Vec::new ();
+        if let Some (zone) = self.zone
+        {
+            others.push (format! ("zone={}", zone));
+        }
+        for (name, value) in [("battery", self.battery), ("link_quality", self.link_quality), ("cpu_load", self.cpu_load)]
+        {
+            if let Some (value) = value
+            {
+                others.push (format! ("{}={}", name, value));
+            }
+        }
+
+        write! (f, "{}", self.position)?;
+        if !others.is_empty ()
+        {
+            write! (f, "{{{}}}", others.join (","))?;
+        }
+        Ok (())
     }
 }
 
-/// The state of the hosting node, as a set of
-/// coordinates corresponding to the position
-/// of a node (device) in a plant.
-#[derive(PartialEq, Clone, Copy)]
+/// The state of the hosting node, as the attributes
+/// (position, zone, ...) of a node (device) in a plant.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct NodeState
 {
-    /// Coordinates of the current node.
-    node_coords    : Coord,
+    /// Attributes of the current node.
+    node_attributes : Attributes,
 
     /// Speedup factor with respect to a reference node.
     speedup_factor : f32,
@@ -89,53 +327,59 @@ pub struct NodeState
 
 impl NodeState
 {
-    pub fn set_coords (&mut self, coord : Coord)
+    pub fn set_attributes (&mut self, attributes : Attributes)
     {
-        self.node_coords = coord;
+        self.node_attributes = attributes;
     }
 
-    pub fn get_coord (&self) -> Coord
+    pub fn get_attributes (&self) -> Attributes
     {
-        self.node_coords
+        self.node_attributes
     }
 
     pub fn get_speedup_factor (&self) -> f32
     {
         self.speedup_factor
     }
+
+    /// Check that the state is acceptable (e.g. once decoded).
+    pub fn check (&self) -> Result<(), ParseStateError>
+    {
+        self.node_attributes.check ()?;
+        if !self.speedup_factor.is_finite () || self.speedup_factor <= 0.0
+        {
+            return Err (ParseStateError::OutOfRange { what  : "NodeState",
+                                                      field : "speedup_factor",
+                                                      value : self.speedup_factor.to_string () });
+        }
+        Ok (())
+    }
 }
 
 impl std::str::FromStr for NodeState
 {
-    type Err = std::convert::Infallible;
+    type Err = ParseStateError;
 
-    /// The expected string: [(f32,f32);f32]
+    /// The expected string: [attributes;f32], e.g. [(f32,f32);f32]
+    /// (see Attributes for the format of the attributes).
     fn from_str (s: &str) -> Result<Self, Self::Err>
     {
-        let trimmed_s = s.replace ('[', "").replace (']', "");
-        let fields : Vec<&str> = trimmed_s.split_terminator (';').collect ();
+        let trimmed_s = s.trim ().trim_start_matches ('[').trim_end_matches (']');
+        let fields = split_fields ("NodeState", trimmed_s, ';', 2)?;
 
-        match (fields.first (), fields.last ())
+        let speedup_factor = parse_finite ("NodeState", "speedup_factor", fields[1])?;
+        if speedup_factor <= 0.0
         {
-            (Some (&coord), Some (&speedup_factor)) => Ok (
-                NodeState
-                {
-                    node_coords   : coord.parse ().unwrap (),
-                    speedup_factor: speedup_factor.parse::<f32> ().unwrap (),
-                }
-            ),
-            _ => Ok (
-                NodeState
-                {
-                    node_coords: Coord
-                    {
-                        x : -1.0,
-                        y : -1.0
-                    },
-                    speedup_factor: f32::MAX,
-                }
-            ),
+            return Err (ParseStateError::OutOfRange { what  : "NodeState",
+                                                      field : "speedup_factor",
+                                                      value : fields[1].to_string () });
         }
+
+        Ok (NodeState
+        {
+            node_attributes : fields[0].parse ()?,
+            speedup_factor,
+        })
     }
 }
 
@@ -143,18 +387,117 @@ impl std::fmt::Display for NodeState
 {
     fn fmt (&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
     {
-        write! (f, "{}", format! ("[({},{});{}]", self.node_coords.x, self.node_coords.y, self.speedup_factor))
+        write! (f, "[{};{}]", self.node_attributes, self.speedup_factor)
+    }
+}
+
+
+/// Federation-wide identifier of a request: the node where the
+/// request was admitted, its application, and its sequence among
+/// the requests of that application admitted by that node (never
+/// reused, see ApplicationState::allocate_sequence).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct RequestId
+{
+    origin      : usize,
+    application : usize,
+    sequence    : usize,
+}
+
+impl RequestId
+{
+    pub fn new (origin : usize, application : usize, sequence : usize) -> Self
+    {
+        Self { origin, application, sequence }
+    }
+
+    pub fn get_origin (&self) -> usize
+    {
+        self.origin
+    }
+
+    pub fn get_application (&self) -> usize
+    {
+        self.application
+    }
+
+    pub fn get_sequence (&self) -> usize
+    {
+        self.sequence
+    }
+
+    /// Name of the folder of the request: {app}_{origin}_{sequence}_req.
+    pub fn folder_name (&self) -> String
+    {
+        format! ("{}_{}_{}_req", self.application, self.origin, self.sequence)
+    }
+
+}
+
+/// The request in a request folder, after the name of the folder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestFolder
+{
+    /// {app}_{label}_req, as written by operators: a new request,
+    /// given its identifier when admitted. The label only tells
+    /// the folders apart.
+    New { application : usize, label : usize },
+
+    /// {app}_{origin}_{sequence}_req: an identified request.
+    Identified (RequestId),
+}
+
+impl RequestFolder
+{
+    pub fn from_folder_name (folder_name : &str) -> Option<Self>
+    {
+        let numbers : Vec<usize> = folder_name.strip_suffix ("_req")?
+            .split ('_')
+            .map (|number| number.parse::<usize> ().ok ())
+            .collect::<Option<Vec<usize>>> ()?;
+        match numbers[..]
+        {
+            [application, origin, sequence] => Some (RequestFolder::Identified (RequestId::new (origin, application, sequence))),
+            [application, label]            => Some (RequestFolder::New { application, label }),
+            _                               => None,
+        }
+    }
+}
+
+impl std::str::FromStr for RequestId
+{
+    type Err = ParseStateError;
+
+    /// Expected string: origin.application.sequence
+    fn from_str (s: &str) -> Result<Self, Self::Err>
+    {
+        let fields = split_fields ("RequestId", s.trim (), '.', 3)?;
+        Ok (RequestId
+        {
+            origin      : parse_field ("RequestId", "origin", fields[0])?,
+            application : parse_field ("RequestId", "application", fields[1])?,
+            sequence    : parse_field ("RequestId", "sequence", fields[2])?,
+        })
+    }
+}
+
+impl Display for RequestId
+{
+    fn fmt (&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        write! (f, "{}.{}.{}", self.origin, self.application, self.sequence)
     }
 }
 
 
 /// A migratable request, in this experimentation
 /// in the form of a Wasm function within a module.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(into = "WireRequest", from = "WireRequest")]
 pub struct Request
 {
-    /// Request index (application-wise).
-    index : usize,
+    /// Identifier of the request, unique in the federation.
+    id : RequestId,
 
     /// Estimated WCET in millisec (ms).
     execution_time  : u32,
@@ -169,13 +512,13 @@ pub struct Request
     /// Required memory in kB.
     required_memory : u32,
 
-    /// Desired geographical position.
-    desired_coord   : Coord,
+    /// Desired attributes of the hosting node.
+    desired_attributes : Attributes,
 
     /// Threshold for triggering a migration as
-    /// maximum allowed difference from the desired
-    /// coordinates (of a request) and the actual
-    /// coordinates (of the hosting node).
+    /// maximum allowed distance between the desired
+    /// attributes (of a request) and the actual
+    /// attributes (of the hosting node).
     threshold       : f32,
 
     /// Migration flag: 'true' that this request has
@@ -186,34 +529,38 @@ pub struct Request
     current_region  : usize,
 
     /// Arrival time.
-    arrival_time    : std::time::Instant
+    arrival_time    : std::time::Instant,
+
+    /// Whether the miss of the desired completion time has
+    /// already been reported by this node.
+    deadline_missed : bool,
 }
 
 impl Request
 {
-    #[allow(dead_code)]
     pub fn new_from (
-        index                   : usize,
+        id                      : RequestId,
         execution_time          : u32,
         desired_completion_time : u32,
         migratable_up_to        : usize,
         required_memory         : u32,
-        desired_coord           : Coord,
+        desired_attributes      : Attributes,
         threshold               : f32,
         current_region          : usize) -> Self
     {
         Self
         {
-            index,
+            id,
             execution_time,
             desired_completion_time,
             migratable_up_to,
             required_memory,
-            desired_coord,
+            desired_attributes,
             threshold,
             should_migrate : false,
             current_region,
-            arrival_time   : std::time::Instant::now ()
+            arrival_time   : std::time::Instant::now (),
+            deadline_missed: false,
         }
     }
 
@@ -226,111 +573,169 @@ impl Request
     {
         self.current_region = region;
     }
+
+    pub fn get_region (&self) -> usize
+    {
+        self.current_region
+    }
     
     pub fn get_should_migrate (&self) -> bool
     {
         self.should_migrate
     }
 
-    pub fn get_desired_coord(&self) -> Coord
+    pub fn get_desired_attributes(&self) -> Attributes
+    {
+        self.desired_attributes
+    }
+
+    pub fn get_id(&self) -> RequestId
     {
-        self.desired_coord
+        self.id
     }
 
-    pub fn get_index(&self) -> usize
+    /// Give its identifier to a new request, once admitted.
+    pub fn set_id (&mut self, id : RequestId)
     {
-        self.index
+        self.id = id;
     }
 
+    #[allow(dead_code)]
     pub fn get_execution_time(&self) -> u32
     {
         self.execution_time
     }
+
+    pub fn get_required_memory(&self) -> u32
+    {
+        self.required_memory
+    }
+
+    /// Absolute deadline: the arrival time plus the desired
+    /// completion time.
+    pub fn get_deadline(&self) -> std::time::Instant
+    {
+        self.arrival_time + std::time::Duration::from_millis (self.desired_completion_time as u64)
+    }
+
+    /// Time elapsed since the arrival of the request.
+    pub fn get_age(&self) -> std::time::Duration
+    {
+        self.arrival_time.elapsed ()
+    }
+
+    /// Set the arrival of the request `age' ago.
+    pub fn set_age(&mut self, age : std::time::Duration)
+    {
+        let now = std::time::Instant::now ();
+        self.arrival_time = now.checked_sub (age).unwrap_or (now);
+    }
+
+    /// Check that the request is acceptable (e.g. once decoded).
+    pub fn check(&self) -> Result<(), ParseStateError>
+    {
+        self.desired_attributes.check ()?;
+        if !self.threshold.is_finite ()
+        {
+            return Err (ParseStateError::OutOfRange { what  : "Request",
+                                                      field : "threshold",
+                                                      value : self.threshold.to_string () });
+        }
+        Ok (())
+    }
+}
+
+/// The fields of a request sent to another node: its arrival
+/// is replaced by its age in ms, which does not depend on the
+/// clock of the node (see Request::from_str).
+#[derive(Serialize, Deserialize)]
+struct WireRequest
+{
+    id                      : RequestId,
+    execution_time          : u32,
+    desired_completion_time : u32,
+    migratable_up_to        : usize,
+    required_memory         : u32,
+    desired_attributes      : Attributes,
+    threshold               : f32,
+    current_region          : usize,
+    age                     : u64,
+}
+
+impl From<Request> for WireRequest
+{
+    fn from (request : Request) -> Self
+    {
+        Self
+        {
+            id                      : request.id,
+            execution_time          : request.execution_time,
+            desired_completion_time : request.desired_completion_time,
+            migratable_up_to        : request.migratable_up_to,
+            required_memory         : request.required_memory,
+            desired_attributes      : request.desired_attributes,
+            threshold               : request.threshold,
+            current_region          : request.current_region,
+            age                     : request.get_age ().as_millis () as u64,
+        }
+    }
+}
+
+impl From<WireRequest> for Request
+{
+    fn from (wire : WireRequest) -> Self
+    {
+        let mut request = Request::new_from (wire.id,
+                                             wire.execution_time,
+                                             wire.desired_completion_time,
+                                             wire.migratable_up_to,
+                                             wire.required_memory,
+                                             wire.desired_attributes,
+                                             wire.threshold,
+                                             wire.current_region);
+        request.set_age (std::time::Duration::from_millis (wire.age));
+        request
+    }
 }
 
 impl std::str::FromStr for Request
 {
-    type Err = std::string::ParseError;
+    type Err = ParseStateError;
 
     /// Expected string:
-    /// [index; execution_time; desired_completion_time; migratable_up_to; required_memory; (desired_coord); threshold; current_region]
-    /// '\[usize; u32; u32; usize; u32; (f32, f32); f32; f32\]'
+    /// [id; execution_time; desired_completion_time; migratable_up_to; required_memory; desired_attributes; threshold; current_region; age]
+    /// '\[RequestId; u32; u32; usize; u32; (f32, f32); f32; f32; u64\]'
+    /// (see RequestId and Attributes for the format of the
+    /// identifier and of the desired attributes). The age is the
+    /// time elapsed since the arrival of the request in ms, so that
+    /// its deadline does not depend on the clock of a node; without
+    /// it, the request has just arrived.
     fn from_str(s: &str) -> Result<Self, Self::Err>
     {
-        let mut index                   : usize = 0;
-        let mut execution_time          : u32   = 0;
-        let mut desired_completion_time : u32   = 0;
-        let mut migratable_up_to        : usize = 0;
-        let mut required_memory         : u32   = 0;
-        let mut desired_coord           : Coord = Coord { x: -1.0, y: -1.0 };
-        let mut threshold               : f32   = 0.0;
-        let mut current_region          : usize = 0;
-
-        let trimmed_s = s.replace ('[', "").replace (']', "");
-        let fields : Vec<&str> = trimmed_s.split_terminator (';').collect ();
-
-        match
-        (
-            fields.get (0),
-            fields.get (1),
-            fields.get (2),
-            fields.get (3),
-            fields.get (4),
-            fields.get (5),
-            fields.get (6),
-            fields.get (7)
-        )
-        {
-            (
-                Some (&req_index),
-                Some (&exec_time),
-                Some (&des_com_time),
-                Some (&mig_up_to),
-                Some (&req_memory),
-                Some (&des_coord),
-                Some (&thresh),
-                Some (&cur_region)
-            ) => {
-                index           = usize::from_str (req_index)
-                    .expect ("Failed to parse index. ");
-
-                execution_time  = u32::from_str (exec_time)
-                    .expect ("Unable to convert exec_time to u32");
-
-                desired_completion_time = u32::from_str (des_com_time)
-                    .expect ("Unable to convert des_com_time to u32");
-
-                migratable_up_to = usize::from_str (mig_up_to)
-                    .expect ("Unable to convert mig_up_to to usize");
-
-                required_memory = u32::from_str (req_memory)
-                    .expect ("Unable to convert req_memory to u32");
-
-                desired_coord   = Coord::from_str (des_coord)
-                        .expect ("Unable to convert des_coord to Coord");
-
-                threshold       = f32::from_str(thresh)
-                    .expect ("Unable to convert thresh to f32");
-
-                current_region  = usize::from_str (cur_region)
-                    .expect ("Unable to convert curr_region to usize");
-            }
-            _ => {}
-        }
+        let trimmed_s = s.trim ().trim_start_matches ('[').trim_end_matches (']');
+        let fields = match split_fields ("Request", trimmed_s, ';', 9)
+        {
+            Err (ParseStateError::FieldCount { found : 8, .. }) => split_fields ("Request", trimmed_s, ';', 8)?,
+            fields => fields?,
+        };
+        let age : u64 = fields.get (8).map (|age| parse_field ("Request", "age", age)).transpose ()?.unwrap_or (0);
 
-        Ok (Request
+        let mut request = Request
         {
-            index,
-            execution_time,
-            desired_completion_time,
-            migratable_up_to,
-            required_memory,
-            desired_coord,
-            threshold,
-            should_migrate : false,
-            current_region,
-            arrival_time   : std::time::Instant::now ()
-        })
+            id                      : fields[0].parse ()?,
+            execution_time          : parse_field ("Request", "execution_time", fields[1])?,
+            desired_completion_time : parse_field ("Request", "desired_completion_time", fields[2])?,
+            migratable_up_to        : parse_field ("Request", "migratable_up_to", fields[3])?,
+            required_memory         : parse_field ("Request", "required_memory", fields[4])?,
+            desired_attributes      : fields[5].parse ()?,
+            threshold               : parse_finite ("Request", "threshold", fields[6])?,
+            should_migrate          : false,
+            current_region          : parse_field ("Request", "current_region", fields[7])?,
+            arrival_time            : std::time::Instant::now (),
+            deadline_missed         : false,
+        };
+        request.set_age (std::time::Duration::from_millis (age));
+        Ok (request)
     }
 }
 
@@ -338,95 +743,229 @@ impl std::fmt::Display for Request
 {
     fn fmt (&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
     {
-        let str = format! ("[{};{};{};{};{};({},{});{};{}]",
-                          self.index,
+        let str = format! ("[{};{};{};{};{};{};{};{};{}]",
+                          self.id,
                           self.execution_time,
                           self.desired_completion_time,
                           self.migratable_up_to,
                           self.required_memory,
-                          self.desired_coord.x,
-                          self.desired_coord.y,
+                          self.desired_attributes,
                           self.threshold,
-                          self.current_region);
+                          self.current_region,
+                          self.get_age ().as_millis ());
         write! (f, "{}", str)
     }
 }
 
 
-pub struct MessageRequest
+/// Decide whether to trigger a migraiton, depending
+/// on the distance between a request desired state 
+/// and the node state
+pub fn should_migrate (request : &Request, node_state : &NodeState, metric : &dyn DistanceMetric) -> bool
 {
-    src     : usize,
-    request : Request,
+    let mut result = false;
+    let distance = metric.distance (&request.desired_attributes, &node_state.get_attributes ());
+    if request.threshold > distance
+    {
+        result = true
+    }
+    result
 }
 
-impl MessageRequest
+
+/// The stage of the life of a request on a node.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RequestState
 {
-    pub fn new (src: usize, request : Request) -> Self
+    /// Waiting to be served by the sporadic server.
+    Queued,
+
+    /// Being served by the sporadic server.
+    Running,
+
+    /// Being served, a checkpoint has been requested so that
+    /// the request can migrate.
+    Checkpointing,
+
+    /// Being transferred, either to another node (its folder is
+    /// ready to be sent) or from another node (its folder is
+    /// being received).
+    InTransit,
+
+    /// Completed on this node.
+    Completed,
+
+    /// Transferred to another node.
+    Migrated,
+
+    /// Terminated by an error.
+    Failed,
+}
+
+impl RequestState
+{
+    /// Whether the request has left the node.
+    pub fn is_final (self) -> bool
     {
-        Self { src, request }
+        matches! (self, RequestState::Completed | RequestState::Migrated | RequestState::Failed)
     }
-    pub fn get_src (&self) -> usize
+
+    /// Whether a request can go from this state to `next'.
+    pub fn can_become (self, next : RequestState) -> bool
     {
-        self.src
+        use RequestState::*;
+        matches! ((self, next),
+                  (Queued, Running) | (Queued, InTransit) | (Queued, Failed)
+                  | (Running, Checkpointing) | (Running, Completed) | (Running, Failed)
+                  | (Checkpointing, InTransit) | (Checkpointing, Completed) | (Checkpointing, Failed)
+                  | (InTransit, Queued) | (InTransit, Migrated) | (InTransit, Failed))
     }
+}
+
+impl std::str::FromStr for RequestState
+{
+    type Err = ParseStateError;
 
-    pub fn get_request (&self) -> &Request
+    /// The expected string: the name of the state (see Display).
+    fn from_str (s: &str) -> Result<Self, Self::Err>
     {
-        &self.request
+        Ok (match s.trim ()
+        {
+            "queued"        => RequestState::Queued,
+            "running"       => RequestState::Running,
+            "checkpointing" => RequestState::Checkpointing,
+            "in transit"    => RequestState::InTransit,
+            "completed"     => RequestState::Completed,
+            "migrated"      => RequestState::Migrated,
+            "failed"        => RequestState::Failed,
+            _ => return Err (ParseStateError::InvalidField { what  : "RequestState",
+                                                             field : "state",
+                                                             value : s.to_string () }),
+        })
     }
 }
 
-impl std::str::FromStr for MessageRequest
+impl Display for RequestState
 {
-    type Err = std::string::ParseError;
+    fn fmt (&self, f: &mut Formatter<'_>) -> std::fmt::Result
+    {
+        let name = match self
+        {
+            RequestState::Queued        => "queued",
+            RequestState::Running       => "running",
+            RequestState::Checkpointing => "checkpointing",
+            RequestState::InTransit     => "in transit",
+            RequestState::Completed     => "completed",
+            RequestState::Migrated      => "migrated",
+            RequestState::Failed        => "failed",
+        };
+        write! (f, "{}", name)
+    }
+}
 
-    fn from_str (s: &str) -> Result<Self, Self::Err>
+/// The states a request went through on this node, each with
+/// the time it was entered.
+#[derive(Clone)]
+pub struct RequestLifecycle
+{
+    history      : Vec<(RequestState, std::time::Instant)>,
+
+    /// Region of the request when it entered the node.
+    first_region : usize,
+
+    /// Wall-clock time the request entered the node, for its
+    /// lineage (Instant is not comparable across nodes).
+    arrival      : std::time::SystemTime,
+}
+
+impl RequestLifecycle
+{
+    fn new (state : RequestState, first_region : usize) -> Self
     {
-        let strs : Vec<&str> = s.split_terminator ('#').collect ();
-        match (strs.first (), strs.last ()) {
-            (Some (&str1), Some (&str2)) =>
-                {
-                    Ok (MessageRequest
-                    {
-                        src: usize::from_str(str1)
-                            .expect ("Unable to convert string to usize"),
-                        request: Request::from_str(str2)
-                            .expect ("Unable to convert string to Request")
-                    })
-                }
-            _ =>
-                {
-                    panic! ("Error while parsing a MessageRequest");
-                }
+        Self
+        {
+            history      : vec![(state, std::time::Instant::now ())],
+            first_region,
+            arrival      : std::time::SystemTime::now (),
         }
     }
+
+    pub fn get_state (&self) -> RequestState
+    {
+        self.history.last ().expect ("Empty lifecycle").0
+    }
+
+    #[allow(dead_code)]
+    pub fn get_history (&self) -> &[(RequestState, std::time::Instant)]
+    {
+        &self.history
+    }
 }
 
-impl std::fmt::Display for MessageRequest
+/// Wall-clock `time' in ms since the UNIX epoch.
+pub fn millis_since_epoch (time : std::time::SystemTime) -> u128
 {
-    fn fmt (&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    time.duration_since (std::time::UNIX_EPOCH).map_or (0, |duration| duration.as_millis ())
+}
+
+/// A stay of a request on a node, as recorded in the lineage
+/// of the request. Times are in ms since the UNIX epoch.
+pub struct LineageHop
+{
+    pub node         : usize,
+
+    /// The regions executed on the node: first_region..last_region.
+    pub first_region : usize,
+    pub last_region  : usize,
+
+    pub arrival      : u128,
+    pub departure    : u128,
+
+    /// How the request left the node.
+    pub outcome      : RequestState,
+}
+
+impl Display for LineageHop
+{
+    /// The string: [node;first_region;last_region;arrival;departure;outcome]
+    fn fmt (&self, f: &mut Formatter<'_>) -> std::fmt::Result
     {
-        write! (f, "{}", format! ("{}#{}", self.src, self.request.to_string ()))
+        write! (f, "[{};{};{};{};{};{}]",
+                self.node, self.first_region, self.last_region, self.arrival, self.departure, self.outcome)
     }
 }
 
+/// Error raised by a transition that the lifecycle of a
+/// request does not allow.
+#[derive(Debug, PartialEq)]
+pub enum TransitionError
+{
+    /// The request is not hosted by the node.
+    UnknownRequest { request_id : RequestId },
 
-/// Decide whether to trigger a migraiton, depending
-/// on the distance between a request desired state 
-/// and the node state
-pub fn should_migrate (request : &Request, node_state : &NodeState) -> bool
+    /// The request cannot go from its state to the next one.
+    Illegal { request_id : RequestId, from : RequestState, to : RequestState },
+}
+
+impl Display for TransitionError
 {
-    let mut result = false;
-    let distance = f32::sqrt (
-        (request.desired_coord.x - node_state.get_coord ().x).powi (2)
-        + (request.desired_coord.y - node_state.get_coord ().y).powi (2));
-    if request.threshold > distance
+    fn fmt (&self, f: &mut Formatter<'_>) -> std::fmt::Result
     {
-        result = true
+        match self
+        {
+            TransitionError::UnknownRequest { request_id } =>
+                write! (f, "request {} is not hosted by this node", request_id),
+            TransitionError::Illegal { request_id, from, to } =>
+                write! (f, "request {} cannot become {} while {}", request_id, to, from),
+        }
     }
-    result
 }
 
+impl std::error::Error for TransitionError {}
+
+/// Number of lifecycles kept after their request has left the
+/// node, for queries.
+const MAX_FINISHED_LIFECYCLES : usize = 64;
 
 /// The state of the application is composed of: 
 /// (1) node-related information,
@@ -435,6 +974,7 @@ pub fn should_migrate (request : &Request, node_state : &NodeState) -> bool
 pub struct ApplicationState
 {
     /// Node-related fields.
+    pub node_index : usize,
     pub node_state : NodeState,
 
     /// Period of the sporadic server associated to
@@ -445,12 +985,17 @@ pub struct ApplicationState
     /// to this application, in milliseconds.
     pub sporadic_server_c  : u32,
 
-    #[allow(dead_code)]
     /// Memory assigned to the application in kB.
     pub assigned_memory    : u32,
 
-    /// Available memory in kB.
-    pub available_memory   : u32,
+    /// Linear memory of the instantiated requests, as measured
+    /// by the sporadic server, in kB. A request that has not been
+    /// instantiated yet is accounted for its required_memory.
+    pub measured_memory    : std::collections::HashMap<RequestId, u32>,
+
+    /// Memory cgroup of the node, when the headroom left by its
+    /// limit is also taken into account.
+    pub memory_cgroup      : Option<std::path::PathBuf>,
 
     /// Sum of the computation time for all the requests in the
     /// backlog, in milliseconds.
@@ -460,34 +1005,61 @@ pub struct ApplicationState
     pub requests           : Vec<Request>,
     pub number_of_requests : u32,
 
-    /// A vector of request indexes sorted by
-    /// earlier desired completion time.
-    pub requests_by_dct    : Vec<usize>,
+    /// A vector of request identifiers sorted by
+    /// earlier absolute deadline (EDF order).
+    pub requests_by_dct    : Vec<RequestId>,
 
-    /// Checkpoint ready.
-    pub checkpoint_is_ready: bool,
+    /// Lifecycle of the hosted requests, and of the last ones
+    /// that have left the node.
+    lifecycles             : std::collections::HashMap<RequestId, RequestLifecycle>,
+
+    /// Requests that have left the node, oldest first.
+    finished_requests      : std::collections::VecDeque<RequestId>,
+
+    /// Write-ahead journal of the mutations of the requests, so
+    /// that they can be recovered after a crash (see journal).
+    pub journal            : Option<std::sync::Arc<Journal>>,
+
+    /// The node is shutting down: no request is accepted, and
+    /// the hosted ones are handed off to other nodes.
+    pub draining           : bool,
+
+    /// Sequence of the next request admitted by this node.
+    next_sequence          : usize,
+
+    /// Requests removed as Migrated whose transfer is not confirmed
+    /// yet: the journal is kept until it is.
+    sending                : std::collections::HashSet<RequestId>,
 }
 
 impl ApplicationState
 {
-    pub fn new (node_coords       : Coord,
+    pub fn new (node_index        : usize,
+                node_attributes   : Attributes,
                 sporadic_server_t : u32,
                 sporadic_server_c : u32,
                 speedup_factor    : f32,
-                assigned_memory: u32) -> Self
+                assigned_memory   : u32) -> Self
     {
         Self
         {
-            node_state         : NodeState { node_coords, speedup_factor },
+            node_index,
+            node_state         : NodeState { node_attributes, speedup_factor },
             sporadic_server_t,
             sporadic_server_c,
             assigned_memory,
-            available_memory   : assigned_memory,
+            measured_memory    : std::collections::HashMap::new (),
+            memory_cgroup      : None,
             backlog_sum_of_c   : 0,
             requests           : Vec::with_capacity (5),
             number_of_requests : 0,
             requests_by_dct    : Vec::with_capacity (5),
-            checkpoint_is_ready: false,
+            lifecycles         : std::collections::HashMap::new (),
+            finished_requests  : std::collections::VecDeque::new (),
+            journal            : None,
+            draining           : false,
+            next_sequence      : 0,
+            sending            : std::collections::HashSet::new (),
         }
     }
 
@@ -497,16 +1069,16 @@ impl ApplicationState
         self.node_state.clone ()
     }
 
-    pub fn set_node_state (&mut self, coord : Coord)
+    pub fn set_node_state (&mut self, attributes : Attributes)
     {
-        self.node_state.set_coords (coord);
+        self.node_state.set_attributes (attributes);
     }
 
-    pub fn get_request (&self, request_index : usize) -> Option<&Request>
+    pub fn get_request (&self, request_id : RequestId) -> Option<&Request>
     {
         for request in &self.requests
         {
-            if request.index == request_index
+            if request.id == request_id
             {
                 return Some (request);
             }
@@ -514,8 +1086,43 @@ impl ApplicationState
         None
     }
 
+    /// Add a request ready to be served.
     pub fn add_request (&mut self, request : Request)
     {
+        self.add_request_in_state (request, RequestState::Queued);
+    }
+
+    /// Add a request whose folder is being received, it is
+    /// served once set to Queued.
+    pub fn add_incoming_request (&mut self, request : Request)
+    {
+        self.add_request_in_state (request, RequestState::InTransit);
+    }
+
+    /// Add back, as Queued, a request which has been removed as
+    /// Migrated but whose transfer has failed: its lifecycle, if
+    /// still kept, goes on.
+    pub fn requeue_request (&mut self, request : Request)
+    {
+        self.sending.remove (&request.id);
+        let lifecycle = self.lifecycles.get (&request.id).cloned ();
+        self.add_request (request);
+        if let Some (mut lifecycle) = lifecycle
+        {
+            lifecycle.history.push ((RequestState::Queued, std::time::Instant::now ()));
+            self.lifecycles.insert (request.id, lifecycle);
+        }
+    }
+
+    fn add_request_in_state (&mut self, request : Request, state : RequestState)
+    {
+        self.write_ahead (JournalEntry::add (request, state));
+
+        // A previous lifecycle of the request (e.g. it has come
+        // back to this node) is over.
+        self.finished_requests.retain (|&id| id != request.id);
+        self.lifecycles.insert (request.id, RequestLifecycle::new (state, request.current_region));
+
         // Enqueue the incoming request.
         self.requests.push (request);
         self.number_of_requests += 1;
@@ -523,63 +1130,167 @@ impl ApplicationState
         // Then update backlog_sum_of_c.
         self.backlog_sum_of_c += request.execution_time;
 
-        // Update the resource consumption variables.
-        self.available_memory -= request.required_memory;
+        // Insert the request identifier into requests_by_dct, after
+        // the requests with an earlier or equal deadline.
+        let position = self.requests_by_dct.iter ()
+            .position (|&id| self.get_request (id)
+                .is_some_and (|request_i| request_i.get_deadline () > request.get_deadline ()))
+            .unwrap_or (self.requests_by_dct.len ());
+        self.requests_by_dct.insert (position, request.id);
+    }
+
+    /// The request to serve next: the one being served if any,
+    /// otherwise the queued one with the earliest deadline.
+    pub fn get_served_request (&self) -> Option<RequestId>
+    {
+        self.get_running_request ().or_else (|| self.requests_by_dct.iter ()
+            .find (|&&id| self.get_request_state (id) == Some (RequestState::Queued))
+            .copied ())
+    }
 
-        // Insert the request index into requests_by_dct.
-        if self.requests_by_dct.is_empty ()
+    /// The request being served by the sporadic server (once
+    /// started, a request is served until it completes or it is
+    /// checkpointed).
+    fn get_running_request (&self) -> Option<RequestId>
+    {
+        self.requests.iter ()
+            .map (|request| request.id)
+            .find (|&id| matches! (self.get_request_state (id),
+                                      Some (RequestState::Running | RequestState::Checkpointing)))
+    }
+
+    /// The current state of a request, hosted or recently left.
+    pub fn get_request_state (&self, request_id : RequestId) -> Option<RequestState>
+    {
+        self.lifecycles.get (&request_id).map (|lifecycle| lifecycle.get_state ())
+    }
+
+    /// The lifecycle of a request, hosted or recently left.
+    #[allow(dead_code)]
+    pub fn get_request_lifecycle (&self, request_id : RequestId) -> Option<&RequestLifecycle>
+    {
+        self.lifecycles.get (&request_id)
+    }
+
+    /// The stay of a hosted request on this node, from its arrival
+    /// up to now, as it would be recorded if it left with `outcome'.
+    pub fn get_lineage_hop (&self, request_id : RequestId, outcome : RequestState) -> Option<LineageHop>
+    {
+        let request   = self.get_request (request_id)?;
+        let lifecycle = self.lifecycles.get (&request_id)?;
+        Some (LineageHop
         {
-            self.requests_by_dct.push (request.index);
+            node         : self.node_index,
+            first_region : lifecycle.first_region,
+            last_region  : request.current_region,
+            arrival      : millis_since_epoch (lifecycle.arrival),
+            departure    : millis_since_epoch (std::time::SystemTime::now ()),
+            outcome,
+        })
+    }
+
+    /// Move a hosted request to the state `next'. A final state
+    /// is only entered through remove_request.
+    pub fn set_request_state (&mut self, request_id : RequestId, next : RequestState) -> Result<(), TransitionError>
+    {
+        if next.is_final () || self.get_request (request_id).is_none ()
+        {
+            return Err (TransitionError::UnknownRequest { request_id });
+        }
+        self.transition (request_id, next)
+    }
+
+    fn transition (&mut self, request_id : RequestId, next : RequestState) -> Result<(), TransitionError>
+    {
+        let from = self.get_request_state (request_id)
+            .ok_or (TransitionError::UnknownRequest { request_id })?;
+        if !from.can_become (next)
+        {
+            return Err (TransitionError::Illegal { request_id, from, to : next });
+        }
+        self.write_ahead (if next.is_final ()
+        {
+            JournalEntry::Remove { request_id, outcome : next }
         }
         else
         {
-            // Compute how much ime we have to complete
-            // `request' according to its desired specs.
-            let remaining_time =
-                request.desired_completion_time as i64
-                    - request.arrival_time.elapsed ().as_millis () as i64;
+            JournalEntry::State { request_id, state : next }
+        });
+        self.lifecycles.get_mut (&request_id).expect ("Missing lifecycle")
+            .history.push ((next, std::time::Instant::now ()));
 
-            for i in 0..self.requests_by_dct.len () {
-                let index = self.requests_by_dct[i];
-                let request_i = self.get_request (index)
-                    .expect ("Unable to get request from index");
-
-                // We do the same also for the request with index 'index'.
-                let remaining_time_i =
-                    request_i.desired_completion_time as i64
-                        - request_i.arrival_time.elapsed ().as_millis () as i64;
-                if remaining_time_i > remaining_time
-                {
-                    self.requests_by_dct.insert (i, request.index);
-                    break;
-                }
+        #[cfg(feature = "print_log")]
+        println! ("state - request {} {} -> {}", request_id, from, next);
+
+        Ok (())
+    }
+
+    /// The hosted requests whose deadline has passed since the last
+    /// call, each reported once.
+    pub fn take_deadline_misses (&mut self) -> Vec<Request>
+    {
+        let now = std::time::Instant::now ();
+        let mut misses = Vec::new ();
+        for request in self.requests.iter_mut ()
+        {
+            if !request.deadline_missed && request.get_deadline () < now
+            {
+                request.deadline_missed = true;
+                misses.push (*request);
             }
         }
+        misses
     }
 
-    pub fn remove_request (&mut self, request_index : usize)
+    /// Remove a request that has left the node with `outcome'
+    /// (Completed, Migrated or Failed). The request is removed even
+    /// if its state does not allow the outcome, it is then recorded
+    /// as Failed.
+    pub fn remove_request (&mut self, request_id : RequestId, outcome : RequestState) -> Result<(), TransitionError>
     {
-        let mut local_index : usize = 0;
-        for i in 0..self.requests.len ()
+        let local_index = self.requests.iter ()
+            .position (|request| request.id == request_id)
+            .ok_or (TransitionError::UnknownRequest { request_id })?;
+
+        let result = if outcome.is_final ()
+        {
+            self.transition (request_id, outcome)
+        }
+        else
         {
-            if self.requests[i].index == request_index
+            Err (TransitionError::Illegal { request_id,
+                                            from : self.get_request_state (request_id).unwrap_or (RequestState::Failed),
+                                            to   : outcome })
+        };
+        if result.is_err ()
+        {
+            self.write_ahead (JournalEntry::Remove { request_id, outcome : RequestState::Failed });
+            if let Some (lifecycle) = self.lifecycles.get_mut (&request_id)
             {
-                local_index = i;
+                lifecycle.history.push ((RequestState::Failed, std::time::Instant::now ()));
             }
         }
 
+        // Keep the lifecycle for a while, for queries.
+        self.finished_requests.push_back (request_id);
+        if self.finished_requests.len () > MAX_FINISHED_LIFECYCLES
+            && let Some (oldest) = self.finished_requests.pop_front ()
+        {
+            self.lifecycles.remove (&oldest);
+        }
+
         // Update backlog_sum_of_c before removing the request.
         let request    = self.requests[local_index];
         self.backlog_sum_of_c -= request.execution_time;
 
         // Update the resource consumption variables.
-        self.available_memory += request.required_memory;
+        self.measured_memory.remove (&request.id);
 
-        // Remove the request index from requests_by_dct.
+        // Remove the request identifier from requests_by_dct.
         if !self.requests_by_dct.is_empty ()
         {
             for i in 0..self.requests_by_dct.len () {
-                if request.index == self.requests_by_dct[i]
+                if request.id == self.requests_by_dct[i]
                 {
                     self.requests_by_dct.remove (i);
                     break;
@@ -590,6 +1301,63 @@ impl ApplicationState
         // Then, remove the request.
         self.requests.remove (local_index);
         self.number_of_requests -= 1;
+        if outcome == RequestState::Migrated && result.is_ok ()
+        {
+            self.sending.insert (request_id);
+        }
+
+        // Without requests, the journal has nothing left to recover.
+        if self.requests.is_empty () && self.sending.is_empty ()
+            && let Some (journal) = &self.journal
+            && let Err (error) = journal.clear (self.next_sequence)
+        {
+            eprintln! ("state - unable to clear the journal: {}", error);
+        }
+
+        result
+    }
+
+    /// Record that the request `request_id', removed as Migrated,
+    /// has reached its destination (before its folder is removed).
+    pub fn confirm_transfer (&mut self, request_id : RequestId)
+    {
+        self.write_ahead (JournalEntry::Sent { request_id });
+        self.sending.remove (&request_id);
+    }
+
+    /// Allocate the sequence of a request admitted by this node.
+    /// The next one is journaled first, so that a sequence is never
+    /// used twice, even across restarts.
+    pub fn allocate_sequence (&mut self) -> usize
+    {
+        let sequence = self.next_sequence;
+        self.reserve_sequence (sequence);
+        sequence
+    }
+
+    /// Record that `sequence' is used by a request admitted by this node.
+    pub fn reserve_sequence (&mut self, sequence : usize)
+    {
+        if sequence >= self.next_sequence
+        {
+            self.write_ahead (JournalEntry::Sequence { next : sequence + 1 });
+            self.next_sequence = sequence + 1;
+        }
+    }
+
+    pub fn get_next_sequence (&self) -> usize
+    {
+        self.next_sequence
+    }
+
+    /// Write `entry' to the journal (if any), before applying it.
+    fn write_ahead (&self, entry : JournalEntry)
+    {
+        if let Some (journal) = &self.journal
+            && let Err (error) = journal.append (&entry)
+        {
+            eprintln! ("state - unable to journal '{}': {}", entry, error);
+        }
     }
 
     #[allow(dead_code)]
@@ -598,24 +1366,25 @@ impl ApplicationState
         self.number_of_requests
     }
 
-    pub fn advance_cur_region_of_request (&mut self, request_index : usize)
+    pub fn advance_cur_region_of_request (&mut self, request_id : RequestId)
     {
         for i in 0..self.requests.len ()
         {
-            if self.requests[i].index == request_index
+            if self.requests[i].id == request_id
             {
+                self.write_ahead (JournalEntry::Region { request_id, region : self.requests[i].current_region + 1 });
                 self.requests[i].current_region += 1;
             }
         }
     }
 
     #[allow(dead_code)]
-    pub fn get_cur_region_of_request (&mut self, request_index : usize) -> usize
+    pub fn get_cur_region_of_request (&mut self, request_id : RequestId) -> usize
     {
         let mut cur_region : usize = 0;
         for i in 0..self.requests.len ()
         {
-            if self.requests[i].index == request_index
+            if self.requests[i].id == request_id
             {
                 cur_region = self.requests[i].current_region
             }
@@ -623,15 +1392,19 @@ impl ApplicationState
         cur_region
     }
 
-    pub fn is_request_migratable (&self, request_index : usize) -> bool
+    pub fn is_request_migratable (&self, request_id : RequestId) -> bool
     {
-        match self.get_request (request_index)
+        match self.get_request (request_id)
         {
             Some(request) =>
                 {
                     #[cfg(feature = "print_log")]
                     println! ("request.migratable_up_to = {}; request.current_region = {}", request.migratable_up_to, request.current_region);
+
+                    // A request already leaving cannot migrate again.
                     request.migratable_up_to > request.current_region
+                        && matches! (self.get_request_state (request_id),
+                                     Some (RequestState::Queued | RequestState::Running))
                 }
             None =>
                 {
@@ -639,33 +1412,310 @@ impl ApplicationState
                 }
         }
     }
-    pub fn get_should_migrate_of_request (&mut self, request_index : usize) -> bool
+    pub fn get_should_migrate_of_request (&mut self, request_id : RequestId) -> bool
     {
-        self.get_request (request_index).unwrap ().should_migrate
+        self.get_request (request_id).unwrap ().should_migrate
     }
 
-    pub fn set_should_migrate_of_request (&mut self, request_index : usize, should_migrate : bool)
+    pub fn set_should_migrate_of_request (&mut self, request_id : RequestId, should_migrate : bool)
     {
         for i in 0..self.requests.len ()
         {
-            if self.requests[i].index == request_index
+            if self.requests[i].id == request_id
             {
                 self.requests[i].should_migrate = should_migrate;
             }
         }
     }
 
-    pub fn get_expected_completion_time (&self, request_c: u32) -> u32
+    /// Expected time to complete `request' on this node, in ms: the
+    /// worst-case response time of the request and of the ones served
+    /// before it (by earliest deadline, so that an older request is
+    /// not delayed by the newer ones), as in the admission test.
+    pub fn get_expected_completion_time (&self, request : &Request) -> u32
+    {
+        let mut sum_of_c : u64 = 0;
+        for served in self.service_order (Some (request))
+        {
+            sum_of_c += served.execution_time as u64;
+            if served.id == request.id
+            {
+                break;
+            }
+        }
+        u32::try_from (self.worst_case_response_time (sum_of_c)).unwrap_or (u32::MAX)
+    }
+
+    /// Memory used by a request in kB: measured once the request
+    /// has been instantiated, as declared before.
+    pub fn get_memory_of_request (&self, request : &Request) -> u32
+    {
+        self.measured_memory.get (&request.id).copied ().unwrap_or (request.required_memory)
+    }
+
+    /// Memory a request needs in kB, to be hosted elsewhere: the
+    /// larger of its declared memory and of its measured memory.
+    pub fn get_needed_memory_of_request (&self, request : &Request) -> u32
+    {
+        self.measured_memory.get (&request.id).copied ().unwrap_or (0).max (request.required_memory)
+    }
+
+    /// Record the linear memory of an instantiated request, in kB.
+    pub fn set_measured_memory (&mut self, request_id : RequestId, memory : u32)
+    {
+        self.measured_memory.insert (request_id, memory);
+    }
+
+    /// Memory used by the hosted requests, except `excluded', in kB
+    /// (u32::MAX if the sum does not fit).
+    fn used_memory (&self, excluded : Option<RequestId>) -> u32
     {
-        (((self.backlog_sum_of_c + request_c ) / self.sporadic_server_c) as f32 * self.node_state.speedup_factor).ceil () as u32 * self.sporadic_server_t
+        self.requests.iter ()
+            .filter (|request| Some (request.id) != excluded)
+            .try_fold (0u32, |used, request| used.checked_add (self.get_memory_of_request (request)))
+            .unwrap_or (u32::MAX)
     }
-    pub fn could_host_computation (&self, request: &Request) -> bool
+
+    /// Memory left to the requests in kB: the assigned memory not
+    /// used by the hosted requests, bounded by the headroom of the
+    /// memory cgroup (if any).
+    pub fn available_memory (&self) -> u32
+    {
+        let available = self.assigned_memory.saturating_sub (self.used_memory (None));
+        match self.cgroup_headroom ()
+        {
+            Some (cgroup_headroom) => available.min (cgroup_headroom),
+            None                   => available,
+        }
+    }
+
+    /// Memory available to the request `request_id' in kB,
+    /// including the memory it already uses.
+    pub fn available_memory_for (&self, request_id : RequestId) -> u32
+    {
+        let available = self.assigned_memory.saturating_sub (self.used_memory (Some (request_id)));
+        match self.cgroup_headroom ()
+        {
+            // The usage of the cgroup already includes the measured
+            // memory of the request.
+            Some (cgroup_headroom) =>
+                {
+                    let measured = self.measured_memory.get (&request_id).copied ().unwrap_or (0);
+                    available.min (cgroup_headroom.saturating_add (measured))
+                }
+            None => available,
+        }
+    }
+
+    fn cgroup_headroom (&self) -> Option<u32>
+    {
+        self.memory_cgroup.as_deref ().and_then (crate::linux_utils::cgroup_memory_headroom)
+    }
+
+    /// Worst-case time to serve `sum_of_c' ms of computation (of the
+    /// reference node) on this node, in ms: the sporadic server
+    /// supplies its budget once per period.
+    fn worst_case_response_time (&self, sum_of_c : u64) -> u64
+    {
+        let demand = sum_of_c as f32 * self.node_state.speedup_factor;
+        (demand / self.sporadic_server_c as f32).ceil () as u64 * self.sporadic_server_t as u64
+    }
+
+    /// The requests in the order they are served by the sporadic
+    /// server, with `newcomer' if any: the request being served
+    /// first, then the others by earliest deadline.
+    fn service_order<'a> (&'a self, newcomer : Option<&'a Request>) -> Vec<&'a Request>
+    {
+        let running = self.get_running_request ();
+        let mut order : Vec<&Request> = self.requests.iter ()
+            .filter (|request| Some (request.id) != running)
+            .collect ();
+        if let Some (newcomer) = newcomer.filter (|newcomer| self.get_request (newcomer.id).is_none ())
+        {
+            order.push (newcomer);
+        }
+        order.sort_by_key (|request| request.get_deadline ());
+        if let Some (running) = running.and_then (|id| self.get_request (id))
+        {
+            order.insert (0, running);
+        }
+        order
+    }
+
+    /// Identifiers of the requests that complete within their desired
+    /// completion time when served in `order'. A request that is
+    /// being served is accounted for its whole execution time.
+    fn requests_meeting_deadline (&self, order : &[&Request]) -> Vec<RequestId>
+    {
+        let mut sum_of_c : u64 = 0;
+        let mut meeting  = Vec::with_capacity (order.len ());
+        for request in order
+        {
+            sum_of_c += request.execution_time as u64;
+            let remaining_time = request.desired_completion_time as i64
+                - request.arrival_time.elapsed ().as_millis () as i64;
+            if self.worst_case_response_time (sum_of_c) as i64 <= remaining_time
+            {
+                meeting.push (request.id);
+            }
+        }
+        meeting
+    }
+
+    /// Schedulability test: whether `request' would complete within
+    /// its desired completion time on this node, without making a
+    /// hosted request miss its own (the requests that are already
+    /// late do not prevent the admission).
+    pub fn is_schedulable (&self, request : &Request) -> bool
+    {
+        let before = self.requests_meeting_deadline (&self.service_order (None));
+        let after  = self.requests_meeting_deadline (&self.service_order (Some (request)));
+        after.contains (&request.id) && before.iter ().all (|id| after.contains (id))
+    }
+
+    /// Whether the node could host `request', which needs `memory' kB
+    /// (see get_needed_memory_of_request).
+    pub fn could_host_computation (&self, request: &Request, memory : u32) -> bool
     {
         let mut result = false;
-        if request.required_memory < self.available_memory
+        if !self.draining && memory < self.available_memory () && self.is_schedulable (request)
         {
             result = true;
         }
         result
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// A node whose sporadic server supplies 50 ms every 100 ms.
+    fn new_state () -> ApplicationState
+    {
+        ApplicationState::new (0, Attributes::new (), 100, 50, 1.0, 1024)
+    }
+
+    fn request (sequence : usize, execution_time : u32, desired_completion_time : u32) -> Request
+    {
+        Request::new_from (RequestId::new (0, 0, sequence), execution_time, desired_completion_time, 1, 64,
+                           Attributes::new (), 1.0, 0)
+    }
+
+    #[test]
+    fn schedulable_request_is_accepted ()
+    {
+        let mut state = new_state ();
+        state.add_request (request (0, 50, 250));
+
+        // Served first (100 ms), then the hosted one (200 ms).
+        assert! (state.is_schedulable (&request (1, 50, 150)));
+        assert! (state.could_host_computation (&request (1, 50, 150), 64));
+    }
+
+    #[test]
+    fn unschedulable_request_is_rejected ()
+    {
+        let mut state = new_state ();
+        state.add_request (request (0, 100, 250));
+
+        // It would miss its own deadline (200 ms for 150 ms).
+        assert! (!state.is_schedulable (&request (1, 100, 150)));
+
+        // It would make the hosted request miss its deadline (300 ms for 250 ms).
+        assert! (!state.is_schedulable (&request (1, 50, 120)));
+        assert! (!state.could_host_computation (&request (1, 50, 120), 64));
+    }
+
+    #[test]
+    fn late_request_does_not_prevent_the_admission ()
+    {
+        let mut state = new_state ();
+        let mut late = request (0, 50, 100);
+        late.set_age (std::time::Duration::from_millis (1_000));
+        state.add_request (late);
+
+        // Still served first, it misses its deadline anyway.
+        assert! (state.is_schedulable (&request (1, 50, 250)));
+    }
+
+    #[test]
+    fn earliest_deadline_is_served_first ()
+    {
+        let mut state = new_state ();
+        state.add_request (request (0, 50, 500));
+        state.add_request (request (1, 50, 200));
+        state.add_request (request (2, 50, 300));
+        assert_eq! (state.get_served_request (), Some (RequestId::new (0, 0, 1)));
+
+        // A request in transit is not served.
+        state.set_request_state (RequestId::new (0, 0, 1), RequestState::InTransit).unwrap ();
+        assert_eq! (state.get_served_request (), Some (RequestId::new (0, 0, 2)));
+    }
+
+    #[test]
+    fn equal_deadlines_are_served_in_admission_order ()
+    {
+        // The same arrival and completion time: the same deadline.
+        let first = request (1, 50, 300);
+        let mut second = first;
+        second.set_id (RequestId::new (0, 0, 0));
+
+        let mut state = new_state ();
+        state.add_request (first);
+        state.add_request (second);
+        assert_eq! (state.get_served_request (), Some (first.get_id ()));
+
+        // Once started, a request is served until it leaves, even when
+        // an earlier deadline arrives.
+        state.set_request_state (first.get_id (), RequestState::Running).unwrap ();
+        state.add_request (request (2, 50, 100));
+        assert_eq! (state.get_served_request (), Some (first.get_id ()));
+
+        state.remove_request (first.get_id (), RequestState::Completed).unwrap ();
+        assert_eq! (state.get_served_request (), Some (RequestId::new (0, 0, 2)));
+        state.remove_request (RequestId::new (0, 0, 2), RequestState::Failed).unwrap ();
+        assert_eq! (state.get_served_request (), Some (second.get_id ()));
+    }
+
+    #[test]
+    fn illegal_transitions_are_rejected ()
+    {
+        use RequestState::*;
+        let request_id = RequestId::new (0, 0, 0);
+        let mut state = new_state ();
+        state.add_request (request (0, 50, 250));
+
+        // Neither skipped nor reversed, and the state is left as is.
+        assert_eq! (state.set_request_state (request_id, Checkpointing),
+                    Err (TransitionError::Illegal { request_id, from : Queued, to : Checkpointing }));
+        state.set_request_state (request_id, Running).unwrap ();
+        assert_eq! (state.set_request_state (request_id, Queued),
+                    Err (TransitionError::Illegal { request_id, from : Running, to : Queued }));
+        assert_eq! (state.set_request_state (request_id, InTransit),
+                    Err (TransitionError::Illegal { request_id, from : Running, to : InTransit }));
+        assert_eq! (state.get_request_state (request_id), Some (Running));
+
+        // A final state is only entered when the request leaves.
+        assert_eq! (state.set_request_state (request_id, Completed),
+                    Err (TransitionError::UnknownRequest { request_id }));
+        assert_eq! (state.set_request_state (RequestId::new (0, 0, 1), Queued),
+                    Err (TransitionError::UnknownRequest { request_id : RequestId::new (0, 0, 1) }));
+    }
+
+    #[test]
+    fn request_leaving_with_an_illegal_outcome_has_failed ()
+    {
+        use RequestState::*;
+        let request_id = RequestId::new (0, 0, 0);
+        let mut state = new_state ();
+        state.add_request (request (0, 50, 250));
+
+        // Only a request in transit migrates.
+        assert_eq! (state.remove_request (request_id, Migrated),
+                    Err (TransitionError::Illegal { request_id, from : Queued, to : Migrated }));
+        assert! (state.get_request (request_id).is_none ());
+        assert_eq! (state.get_request_state (request_id), Some (Failed));
+        assert_eq! (state.remove_request (request_id, Failed), Err (TransitionError::UnknownRequest { request_id }));
+    }
+}
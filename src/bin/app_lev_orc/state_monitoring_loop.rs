@@ -2,17 +2,19 @@
 /*        STATE MONITORING LOOP        */
 /***************************************/
 
-use paho_mqtt::{self as mqtt, MQTT_VERSION_5};
 use futures::{executor::block_on, stream::StreamExt};
 
 use crate::state::{ApplicationState, NodeState};
 use crate::linux_utils;
+use crate::mqtt_utils::{self, MqttLink};
+use crate::message_bus::BusError;
+use crate::protocol::{self, ProtocolError};
 
 /// Data and functions associated with the
 /// state_monitoring_loop.
 pub struct ControlSystem
 {
-    client   : mqtt::AsyncClient,
+    link     : MqttLink,
     topic    : String,
     priority : i32,
     affinity : usize,
@@ -20,55 +22,23 @@ pub struct ControlSystem
 
 impl ControlSystem
 {
-    pub fn new (application_index: usize,
-                node_index       : usize,
+    pub fn new (node_index       : usize,
                 priority         : i32,
                 affinity         : usize,
-                broker_address   : String) -> Self
+                link             : MqttLink) -> Self
     {
-
-        #[cfg(feature = "print_log")]
-        println! ("state_monitoring_loop - new START");
-
-        // Initialization. 
-        let host = format! ("mqtt://{}:1883", broker_address).to_string ();
-
-        let client_id = format! ("node_{}_app_{}_sml", node_index, application_index);
-
-        // Create the client. Use an ID for a persistent session. 
-        let create_opts = mqtt::CreateOptionsBuilder::new ()
-            .server_uri (host)
-            .client_id( client_id)
-            .finalize ();
-
-        // Create the subscriber connection. 
-        let client = mqtt::AsyncClient::new (create_opts).unwrap_or_else (|e|
-            {
-                panic! ("state_monitoring_loop - error creating the client: {:?}", e);
-            }
-        );
-
-        client.set_disconnected_callback(|_, _props, reason|
-            {
-                panic! ("state_monitoring_loop - server disconnected with reason: {}", reason);
-            }
-        );
-
-        #[cfg(feature = "print_log")]
-        println! ("state_monitoring_loop - new END");
-
         Self
         {
-            client,
+            link,
             topic : format! ("node_state_{}", node_index).to_string (),
             priority,
             affinity,
         }
-    
     }
 
-    /// Start the state monitoring loop.
-    pub fn start (&mut self, application_state: std::sync::Arc<std::sync::Mutex<ApplicationState>>)
+    /// Start the state monitoring loop. The state of the node
+    /// is shared by all the applications it hosts.
+    pub fn start (&mut self, application_states: Vec<std::sync::Arc<std::sync::Mutex<ApplicationState>>>)
     {
 
         #[cfg(feature = "print_log")]
@@ -79,32 +49,8 @@ impl ControlSystem
 
         if let Err (err) = block_on (async
             {
-                // Get message stream before connecting.
-                let mut strm = self.client.get_stream (15);
-
-                // Define the set of options for the connection.
-                let lwt = mqtt::Message::new (
-                    self.topic.clone (),
-                    "[LWT] Async subscriber 'state_monitoring_loop' lost connection",
-                    mqtt::QOS_1,
-                );
-
-                // Connect with MQTT v5 and a persistent server session (no clean start).
-                let conn_opts = mqtt::ConnectOptionsBuilder::with_mqtt_version (MQTT_VERSION_5)
-                    .clean_start (true)
-                    .properties (mqtt::properties![mqtt::PropertyCode::SessionExpiryInterval => 3600])
-                    .will_message (lwt)
-                    .finalize ();
-
-                // Make the connection to the broker.
-                self.client.connect (conn_opts).await?;
-
-                let sub_opts = vec![mqtt::SubscribeOptions::with_retain_as_published (); 2];
-                self.client.subscribe_many_with_options (
-                    &[self.topic.clone ()],
-                    &[mqtt::QOS_1],
-                    &sub_opts,
-                    None).await?;
+                // Subscribe through the connection of the node.
+                let mut strm = self.link.subscribe (&[self.topic.clone ()]).await?;
 
                 #[cfg(feature = "print_log")]
                 println! ("state_monitoring_loop - LOOP");
@@ -121,13 +67,30 @@ impl ControlSystem
                             #[cfg(feature = "print_log")]
                             println! ("state_monitoring_loop - message ARRIVED");
 
-                            // Parse the received message.
-                            let node_state = msg.payload_str ().parse::<NodeState> ()
-                                .expect ("msg");
-
-                            // The update the NodeState object within ApplicationState.
+                            // Parse the received message. The text form, e.g.
+                            // [(f32,f32);f32], is still accepted without a
+                            // version, as published by hand with mosquitto_pub.
+                            let node_state = match protocol::decode::<NodeState> (msg.payload ())
+                            {
+                                Ok (node_state) => Ok (node_state),
+                                Err (ProtocolError::Unversioned) => msg.payload_str ().parse::<NodeState> ()
+                                    .map_err (ProtocolError::Invalid),
+                                Err (error) => Err (error),
+                            };
+                            let node_state = match node_state
+                            {
+                                Ok (node_state) => node_state,
+                                Err (error) =>
+                                    {
+                                        mqtt_utils::reject_payload (&msg, &error);
+                                        continue;
+                                    }
+                            };
+
+                            // The update the NodeState object within each ApplicationState.
+                            for application_state in &application_states
                             {
-                                application_state.lock ().unwrap ().set_node_state (node_state.get_coord ());
+                                application_state.lock ().unwrap ().set_node_state (node_state.get_attributes ());
                             }
 
                             #[cfg(feature = "print_log")]
@@ -138,7 +101,7 @@ impl ControlSystem
                 }
 
                 // Explicit return type for the async block.
-                Ok::<(), mqtt::Error> (())
+                Ok::<(), BusError> (())
         }) 
         {
            println! ("state_monitoring_loop - error creating the client: {:?}", err);
@@ -0,0 +1,214 @@
+/***************************************/
+/*                 TLS                 */
+/***************************************/
+
+// TLS with client certificates, for the connection to the broker and
+// for the data plane. The nodes of a federation share a CA, and the
+// certificate of each node carries its name (see node_name) as its
+// common name: a peer is identified by its certificate, not by the
+// node ID it claims, and both ends of a connection check each other.
+// The embedded broker also binds the node IDs claimed by the
+// messages it relays to the certificate of their publisher.
+
+use openssl::ssl::{SslAcceptor, SslConnector, SslFiletype, SslMethod, SslRef, SslStream, SslVerifyMode};
+
+/// The PEM files of the TLS configuration of a node.
+#[derive(Clone, Debug)]
+pub struct TlsFiles
+{
+    /// Certificate of the CA of the federation.
+    pub ca_file   : String,
+
+    /// Certificate of the node, signed by the CA.
+    pub cert_file : String,
+
+    /// Private key of the node.
+    pub key_file  : String,
+}
+
+/// The TLS contexts of a node, as a server and as a client.
+#[derive(Clone)]
+pub struct Tls
+{
+    acceptor  : SslAcceptor,
+    connector : SslConnector,
+}
+
+impl Tls
+{
+    /// Load the CA, the certificate and the key in `files'.
+    pub fn new (files : &TlsFiles) -> Result<Self, openssl::error::ErrorStack>
+    {
+        // Clients must present a certificate signed by the CA.
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5 (SslMethod::tls_server ())?;
+        acceptor.set_ca_file (&files.ca_file)?;
+        acceptor.set_certificate_chain_file (&files.cert_file)?;
+        acceptor.set_private_key_file (&files.key_file, SslFiletype::PEM)?;
+        acceptor.check_private_key ()?;
+        acceptor.set_verify (SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+
+        let mut connector = SslConnector::builder (SslMethod::tls_client ())?;
+        connector.set_ca_file (&files.ca_file)?;
+        connector.set_certificate_chain_file (&files.cert_file)?;
+        connector.set_private_key_file (&files.key_file, SslFiletype::PEM)?;
+        connector.check_private_key ()?;
+
+        Ok (Self { acceptor : acceptor.build (), connector : connector.build () })
+    }
+
+    /// Accept a TLS connection on `stream', and return it with the
+    /// name of the peer.
+    pub fn accept (&self, stream : std::net::TcpStream) -> std::io::Result<(SslStream<std::net::TcpStream>, String)>
+    {
+        let stream = self.acceptor.accept (stream)
+            .map_err (|e| std::io::Error::new (std::io::ErrorKind::PermissionDenied, e.to_string ()))?;
+        let peer = peer_name (stream.ssl ())?;
+        Ok ((stream, peer))
+    }
+
+    /// Open a TLS connection on `stream', and return it with the name
+    /// of the peer. Peers are identified by their name rather than by
+    /// their address, which is not checked.
+    pub fn connect (&self, stream : std::net::TcpStream) -> std::io::Result<(SslStream<std::net::TcpStream>, String)>
+    {
+        let stream = self.connector.configure ()
+            .map (|config| config.use_server_name_indication (false).verify_hostname (false))
+            .map_err (std::io::Error::other)?
+            .connect ("", stream)
+            .map_err (|e| std::io::Error::new (std::io::ErrorKind::PermissionDenied, e.to_string ()))?;
+        let peer = peer_name (stream.ssl ())?;
+        Ok ((stream, peer))
+    }
+}
+
+/// The name of node `node_index' in the certificates, e.g. node_0.
+pub fn node_name (node_index : usize) -> String
+{
+    format! ("node_{}", node_index)
+}
+
+/// Check that `peer' is the name of node `node_index', e.g. that the
+/// peer of a data connection, or the publisher of a message claiming
+/// to come from `node_index' (see embedded_broker), is that node.
+pub fn check_peer (peer : &str, node_index : usize) -> std::io::Result<()>
+{
+    if peer == node_name (node_index)
+    {
+        Ok (())
+    }
+    else
+    {
+        Err (std::io::Error::new (std::io::ErrorKind::PermissionDenied,
+                                  format! ("peer {} is not {}", peer, node_name (node_index))))
+    }
+}
+
+/// The common name of the certificate of the peer.
+fn peer_name (ssl : &SslRef) -> std::io::Result<String>
+{
+    ssl.peer_certificate ()
+        .and_then (|certificate| certificate.subject_name ()
+            .entries_by_nid (openssl::nid::Nid::COMMONNAME)
+            .next ()
+            .and_then (|entry| entry.data ().to_string ().ok ()))
+        .ok_or_else (|| std::io::Error::new (std::io::ErrorKind::PermissionDenied, "peer without a name"))
+}
+
+#[cfg(test)]
+pub mod tests
+{
+    use super::*;
+    use openssl::{asn1::Asn1Time, hash::MessageDigest, pkey::{PKey, Private}, rsa::Rsa};
+    use openssl::x509::{X509, X509NameBuilder, extension::{BasicConstraints, SubjectAlternativeName}};
+
+    fn certificate (name : &str, issuer : Option<(&X509, &PKey<Private>)>) -> (X509, PKey<Private>)
+    {
+        let key = PKey::from_rsa (Rsa::generate (2048).unwrap ()).unwrap ();
+        let mut subject = X509NameBuilder::new ().unwrap ();
+        subject.append_entry_by_nid (openssl::nid::Nid::COMMONNAME, name).unwrap ();
+        let subject = subject.build ();
+
+        let mut builder = X509::builder ().unwrap ();
+        builder.set_version (2).unwrap ();
+        builder.set_subject_name (&subject).unwrap ();
+        builder.set_pubkey (&key).unwrap ();
+        builder.set_not_before (&Asn1Time::days_from_now (0).unwrap ()).unwrap ();
+        builder.set_not_after (&Asn1Time::days_from_now (1).unwrap ()).unwrap ();
+        match issuer
+        {
+            Some ((ca, ca_key)) =>
+                {
+                    let san = SubjectAlternativeName::new ().ip ("127.0.0.1").build (&builder.x509v3_context (Some (ca), None)).unwrap ();
+                    builder.append_extension (san).unwrap ();
+                    builder.set_issuer_name (ca.subject_name ()).unwrap ();
+                    builder.sign (ca_key, MessageDigest::sha256 ()).unwrap ();
+                }
+            None =>
+                {
+                    builder.append_extension (BasicConstraints::new ().critical ().ca ().build ().unwrap ()).unwrap ();
+                    builder.set_issuer_name (&subject).unwrap ();
+                    builder.sign (&key, MessageDigest::sha256 ()).unwrap ();
+                }
+        }
+        (builder.build (), key)
+    }
+
+    /// Generate in `dir' a CA named `ca_name', and the certificates
+    /// of `nodes' signed by it, then return their TLS files.
+    pub fn generate (dir : &std::path::Path, ca_name : &str, nodes : &[&str]) -> Vec<TlsFiles>
+    {
+        std::fs::create_dir_all (dir).unwrap ();
+        let (ca, ca_key) = certificate (ca_name, None);
+        let ca_file = dir.join (format! ("{}.crt", ca_name));
+        std::fs::write (&ca_file, ca.to_pem ().unwrap ()).unwrap ();
+        nodes.iter ().map (|node|
+            {
+                let (cert, key) = certificate (node, Some ((&ca, &ca_key)));
+                let cert_file = dir.join (format! ("{}.crt", node));
+                let key_file  = dir.join (format! ("{}.key", node));
+                std::fs::write (&cert_file, cert.to_pem ().unwrap ()).unwrap ();
+                std::fs::write (&key_file, key.private_key_to_pem_pkcs8 ().unwrap ()).unwrap ();
+                TlsFiles
+                {
+                    ca_file   : ca_file.to_string_lossy ().to_string (),
+                    cert_file : cert_file.to_string_lossy ().to_string (),
+                    key_file  : key_file.to_string_lossy ().to_string (),
+                }
+            })
+            .collect ()
+    }
+
+    /// Connect `client' to `server', and return the names each sees.
+    fn handshake (server : &Tls, client : &Tls) -> (std::io::Result<String>, std::io::Result<String>)
+    {
+        let listener = std::net::TcpListener::bind ("127.0.0.1:0").unwrap ();
+        let address  = listener.local_addr ().unwrap ();
+        let server   = server.clone ();
+        let accepted = std::thread::spawn (move ||
+            server.accept (listener.accept ().unwrap ().0).map (|(_, peer)| peer));
+        let connected = client.connect (std::net::TcpStream::connect (address).unwrap ()).map (|(_, peer)| peer);
+        (accepted.join ().unwrap (), connected)
+    }
+
+    #[test]
+    fn peers_are_identified_by_their_certificate ()
+    {
+        let dir = std::env::temp_dir ().join (format! ("tls_test_{}", std::process::id ()));
+        let files  = generate (&dir, "ca", &["node_0", "node_1"]);
+        let rogue  = generate (&dir, "rogue_ca", &["node_2"]);
+        let node_0 = Tls::new (&files[0]).unwrap ();
+        let node_1 = Tls::new (&files[1]).unwrap ();
+        let node_2 = Tls::new (&rogue[0]).unwrap ();
+
+        let (accepted, connected) = handshake (&node_0, &node_1);
+        assert_eq! (accepted.unwrap (), "node_1");
+        assert_eq! (connected.unwrap (), "node_0");
+        assert! (check_peer ("node_1", 1).is_ok ());
+        assert! (check_peer ("node_1", 2).is_err ());
+
+        // A certificate of another CA is rejected.
+        let (accepted, _) = handshake (&node_0, &node_2);
+        assert! (accepted.is_err ());
+        std::fs::remove_dir_all (dir).unwrap ();
+    }
+}